@@ -0,0 +1,346 @@
+uniffi::setup_scaffolding!();
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::Arc,
+};
+
+use filen_swift::client::{FilenClient, FilenClientError};
+use indexed_blobs::{
+    backup::{
+        BackupFile, backup_digests_file, backup_midx_file, plan_restore, read_backup_digests,
+    },
+    blob_provider::BlobProvider,
+    err_type::BlobProviderError,
+};
+
+#[derive(Debug, uniffi::Error, thiserror::Error)]
+pub enum BlobBackupError {
+    #[error("Filen client error: {msg}")]
+    FilenClientError { msg: String },
+
+    /// Kept whole, so e.g. an I/O error still tells its kind, path and offset
+    #[error("Blob provider error: {0}")]
+    BlobProviderError(#[from] BlobProviderError),
+
+    #[error("Incomplete backup: {msg}")]
+    IncompleteBackup { msg: String },
+}
+
+impl From<FilenClientError> for BlobBackupError {
+    fn from(err: FilenClientError) -> Self {
+        BlobBackupError::FilenClientError {
+            msg: format!("{}", err),
+        }
+    }
+}
+
+#[derive(Debug, uniffi::Record)]
+pub struct BlobBackupReport {
+    /// Files uploaded or downloaded
+    pub transferred_files: u64,
+    /// Chunks that were already backed up or restored, and weren't transferred again
+    pub skipped_chunks: u64,
+}
+
+/// Folder a backup is kept in, which holds nothing but the backup
+#[allow(async_fn_in_trait)]
+pub trait BackupRemote {
+    /// Name and uuid of every file in the folder
+    async fn list(&self) -> Result<Vec<(String, String)>, BlobBackupError>;
+
+    /// Uploads the local file at `path` as `name`, returning the uuid of the new file
+    async fn upload(&self, path: &str, name: &str) -> Result<String, BlobBackupError>;
+
+    /// Downloads the file `uuid` to the local `path`
+    async fn download(&self, uuid: &str, path: &str) -> Result<(), BlobBackupError>;
+
+    async fn delete(&self, uuid: &str) -> Result<(), BlobBackupError>;
+}
+
+/// Filen folder `dir_uuid` as a `BackupRemote`
+pub struct FilenFolder {
+    pub client: Arc<FilenClient>,
+    pub dir_uuid: String,
+}
+
+impl BackupRemote for FilenFolder {
+    async fn list(&self) -> Result<Vec<(String, String)>, BlobBackupError> {
+        Ok(self
+            .client
+            .list_dir(self.dir_uuid.clone())
+            .await?
+            .files
+            .into_iter()
+            .map(|file| (file.name, file.uuid))
+            .collect())
+    }
+
+    async fn upload(&self, path: &str, name: &str) -> Result<String, BlobBackupError> {
+        let file = self
+            .client
+            .upload_file_from_path(self.dir_uuid.clone(), path.to_owned(), name.to_owned())
+            .await?;
+
+        Ok(file.uuid)
+    }
+
+    async fn download(&self, uuid: &str, path: &str) -> Result<(), BlobBackupError> {
+        self.client
+            .download_file_to_path(uuid.to_owned(), path.to_owned())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, uuid: &str) -> Result<(), BlobBackupError> {
+        self.client.delete_file(uuid.to_owned()).await?;
+
+        Ok(())
+    }
+}
+
+/// Name a file is uploaded as in the backup numbered `version`. Files replaced by a
+/// backup are kept until it is complete, so names never clash and an interrupted
+/// backup still leaves every file of the one before.
+fn versioned_name(name: &str, version: u64) -> String {
+    match version {
+        0 => name.to_owned(),
+        _ => format!("{name}.v{version}"),
+    }
+}
+
+/// Name and version of the file uploaded as `remote_name`, see `versioned_name`
+fn parse_versioned_name(remote_name: &str) -> (&str, u64) {
+    remote_name
+        .rsplit_once(".v")
+        .and_then(|(name, version)| Some((name, version.parse().ok()?)))
+        .unwrap_or((remote_name, 0))
+}
+
+/// Files in the backup folder
+struct RemoteFiles {
+    /// Uuid of every version of every file, by name and version
+    versions: HashMap<String, BTreeMap<u64, String>>,
+}
+
+impl RemoteFiles {
+    async fn list(remote: &impl BackupRemote) -> Result<Self, BlobBackupError> {
+        let mut versions = HashMap::<String, BTreeMap<u64, String>>::new();
+        for (remote_name, uuid) in remote.list().await? {
+            let (name, version) = parse_versioned_name(&remote_name);
+            versions
+                .entry(name.to_owned())
+                .or_default()
+                .insert(version, uuid);
+        }
+
+        Ok(RemoteFiles { versions })
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.versions.contains_key(name)
+    }
+
+    fn latest_version(&self, name: &str) -> Option<u64> {
+        let (version, _) = self.versions.get(name)?.last_key_value()?;
+
+        Some(*version)
+    }
+
+    /// Version of the backup after every one the folder holds files of, even
+    /// interrupted ones
+    fn next_version(&self) -> u64 {
+        self.versions
+            .keys()
+            .filter_map(|name| self.latest_version(name))
+            .max()
+            .map_or(0, |latest| latest + 1)
+    }
+
+    /// Uuid of the file `name` as of the backup numbered `version`, which is its
+    /// latest version up to that one
+    fn uuid(&self, name: &str, version: u64) -> Result<&String, BlobBackupError> {
+        self.versions
+            .get(name)
+            .and_then(|versions| versions.range(..=version).next_back())
+            .map(|(_, uuid)| uuid)
+            .ok_or_else(|| BlobBackupError::IncompleteBackup {
+                msg: format!("{name} is missing"),
+            })
+    }
+
+    fn uuids(&self) -> impl Iterator<Item = &String> {
+        self.versions.values().flat_map(BTreeMap::values)
+    }
+
+    async fn download(
+        &self,
+        remote: &impl BackupRemote,
+        file: &BackupFile,
+        version: u64,
+    ) -> Result<(), BlobBackupError> {
+        let uuid = self.uuid(&file.name, version)?;
+
+        remote.download(uuid, &file.path).await
+    }
+}
+
+/// Backs up `provider` to `remote`, see `back_up_blob_chunks`
+pub async fn back_up(
+    remote: &impl BackupRemote,
+    provider: &BlobProvider,
+    staging_dir: &str,
+) -> Result<BlobBackupReport, BlobBackupError> {
+    let manifest = provider.prepare_backup(staging_dir.to_owned())?;
+    let remote_files = RemoteFiles::list(remote).await?;
+    // Picks the latest version of a file
+    let latest = u64::MAX;
+
+    // The latest digests list every chunk uploaded, even by an interrupted backup
+    let backed_up = match remote_files.contains(&manifest.digests.name) {
+        true => {
+            let path = Path::new(staging_dir).join(format!("remote_{}", manifest.digests.name));
+            let file = BackupFile {
+                name: manifest.digests.name.clone(),
+                path: path.to_string_lossy().into_owned(),
+            };
+            remote_files.download(remote, &file, latest).await?;
+            let backed_up = read_backup_digests(file.path);
+            // Only a leftover in the staging directory if it stays
+            let _ = fs::remove_file(path);
+            backed_up?
+        }
+        false => Vec::new(),
+    };
+
+    // Every file is uploaded as this version, which a restore picks files by
+    let version = remote_files.next_version();
+    let mut report = BlobBackupReport {
+        transferred_files: 0,
+        skipped_chunks: 0,
+    };
+    // Uuid of every file the backup consists of once it's complete
+    let mut kept = HashSet::new();
+    for chunk in &manifest.chunks {
+        let is_backed_up = backed_up.contains(&chunk.digest)
+            && chunk
+                .files
+                .iter()
+                .all(|file| remote_files.contains(&file.name));
+        if is_backed_up {
+            report.skipped_chunks += 1;
+            for file in &chunk.files {
+                kept.insert(remote_files.uuid(&file.name, latest)?.clone());
+            }
+            continue;
+        }
+
+        for file in &chunk.files {
+            let name = versioned_name(&file.name, version);
+            kept.insert(remote.upload(&file.path, &name).await?);
+            report.transferred_files += 1;
+        }
+    }
+
+    // The midx goes last, so it never points at chunks that aren't uploaded yet
+    for file in manifest
+        .sidecars
+        .iter()
+        .chain([&manifest.digests, &manifest.midx])
+    {
+        let name = versioned_name(&file.name, version);
+        kept.insert(remote.upload(&file.path, &name).await?);
+        report.transferred_files += 1;
+    }
+
+    // Replaced versions and files of chunks compacted away since
+    for uuid in remote_files.uuids() {
+        if !kept.contains(uuid) {
+            remote.delete(uuid).await?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Restores the backup in `remote`, see `restore_blob_chunks`
+pub async fn restore(
+    remote: &impl BackupRemote,
+    path: &str,
+    prefix: &str,
+) -> Result<BlobBackupReport, BlobBackupError> {
+    let remote_files = RemoteFiles::list(remote).await?;
+    let digests = backup_digests_file(path.to_owned(), prefix.to_owned())?;
+    let midx = backup_midx_file(path.to_owned(), prefix.to_owned())?;
+
+    // A backup interrupted before its midx was uploaded leaves newer versions of
+    // other files, which belong to no complete backup
+    let version = remote_files.latest_version(&midx.name).ok_or_else(|| {
+        BlobBackupError::IncompleteBackup {
+            msg: format!("{} is missing", midx.name),
+        }
+    })?;
+    remote_files.download(remote, &digests, version).await?;
+
+    let plan = plan_restore(path.to_owned(), prefix.to_owned(), digests.path.clone())?;
+    let backed_up = read_backup_digests(digests.path.clone())?;
+
+    let mut report = BlobBackupReport {
+        transferred_files: 1,
+        skipped_chunks: (backed_up.len() - plan.chunks.len()) as u64,
+    };
+    for file in plan.chunks.iter().flat_map(|chunk| &chunk.files) {
+        remote_files.download(remote, file, version).await?;
+        report.transferred_files += 1;
+    }
+
+    if let Some(chunk) = plan_restore(path.to_owned(), prefix.to_owned(), digests.path)?
+        .chunks
+        .first()
+    {
+        return Err(BlobBackupError::IncompleteBackup {
+            msg: format!("chunk {} doesn't match its digest", chunk.digest.chunk),
+        });
+    }
+
+    for file in plan.sidecars.iter().chain([&midx]) {
+        remote_files.download(remote, file, version).await?;
+        report.transferred_files += 1;
+    }
+
+    Ok(report)
+}
+
+/// Seals the active chunk of `provider` and backs up every sealed chunk, along with
+/// the key metadata, to the Filen folder `dir_uuid`, which holds nothing but the
+/// backup. Chunks the folder already holds with the same
+/// digest are skipped. Files replaced by the backup, and files of chunks compacted
+/// away since, are deleted once the new midx is uploaded. `staging_dir` holds the
+/// midx, digests and sidecars while they upload.
+#[uniffi::export]
+pub async fn back_up_blob_chunks(
+    client: Arc<FilenClient>,
+    provider: Arc<BlobProvider>,
+    dir_uuid: String,
+    staging_dir: String,
+) -> Result<BlobBackupReport, BlobBackupError> {
+    back_up(&FilenFolder { client, dir_uuid }, &provider, &staging_dir).await
+}
+
+/// Restores the backup in the Filen folder `dir_uuid` into the blob directory at
+/// `path`, before a provider with `prefix` is opened on it. Every file is restored as
+/// of the latest backup whose midx was uploaded. Chunks an interrupted restore
+/// already downloaded are skipped, and every chunk is checked against its digest
+/// before the midx that makes them visible is restored.
+#[uniffi::export]
+pub async fn restore_blob_chunks(
+    client: Arc<FilenClient>,
+    dir_uuid: String,
+    path: String,
+    prefix: String,
+) -> Result<BlobBackupReport, BlobBackupError> {
+    restore(&FilenFolder { client, dir_uuid }, &path, &prefix).await
+}
@@ -0,0 +1,322 @@
+use std::{
+    error::Error,
+    fs,
+    future::Future,
+    path::PathBuf,
+    pin::pin,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+};
+
+use blob_backup::{BackupRemote, BlobBackupError, back_up, restore};
+use indexed_blobs::{
+    blob_provider::{BlobProvider, new_blob_provider},
+    err_type::{BlobProviderError, IoErrorKind},
+};
+
+fn key(id: u8) -> String {
+    format!("00000000-0000-0000-0000-{:012X}", id)
+}
+
+/// Runs a future that never pends, as `MemRemote` never does
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut context = Context::from_waker(Waker::noop());
+    match pin!(future).poll(&mut context) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("future pended"),
+    }
+}
+
+/// Empty directory unique to the test
+fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("blob-backup-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().into_owned()
+}
+
+fn open(path: &str) -> BlobProvider {
+    new_blob_provider(path.to_owned(), "thumbs".to_owned()).unwrap()
+}
+
+#[derive(Default)]
+struct MemRemoteState {
+    /// Uuid, name and contents of every file
+    files: Vec<(String, String, Vec<u8>)>,
+    next_uuid: u64,
+    /// Name of every file uploaded, in order
+    uploads: Vec<String>,
+    /// Fails uploading files whose name starts with this, as if the connection dropped
+    fail_upload: Option<&'static str>,
+}
+
+/// Backup folder in memory
+#[derive(Default)]
+struct MemRemote {
+    state: Mutex<MemRemoteState>,
+}
+
+impl MemRemote {
+    fn names(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut names = state
+            .files
+            .iter()
+            .map(|(_, name, _)| name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn take_uploads(&self) -> Vec<String> {
+        std::mem::take(&mut self.state.lock().unwrap().uploads)
+    }
+
+    fn remove(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.files.retain(|(_, file_name, _)| file_name != name);
+    }
+}
+
+impl BackupRemote for MemRemote {
+    async fn list(&self) -> Result<Vec<(String, String)>, BlobBackupError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .files
+            .iter()
+            .map(|(uuid, name, _)| (name.clone(), uuid.clone()))
+            .collect())
+    }
+
+    async fn upload(&self, path: &str, name: &str) -> Result<String, BlobBackupError> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .fail_upload
+            .is_some_and(|prefix| name.starts_with(prefix))
+        {
+            return Err(BlobBackupError::FilenClientError {
+                msg: "connection lost".to_owned(),
+            });
+        }
+        assert!(
+            state
+                .files
+                .iter()
+                .all(|(_, file_name, _)| file_name != name),
+            "{name} uploaded twice"
+        );
+
+        let uuid = format!("uuid-{}", state.next_uuid);
+        state.next_uuid += 1;
+        state
+            .files
+            .push((uuid.clone(), name.to_owned(), fs::read(path).unwrap()));
+        state.uploads.push(name.to_owned());
+        Ok(uuid)
+    }
+
+    async fn download(&self, uuid: &str, path: &str) -> Result<(), BlobBackupError> {
+        let state = self.state.lock().unwrap();
+        let (_, _, data) = state
+            .files
+            .iter()
+            .find(|(file_uuid, _, _)| file_uuid == uuid)
+            .unwrap();
+        fs::write(path, data).unwrap();
+        Ok(())
+    }
+
+    async fn delete(&self, uuid: &str) -> Result<(), BlobBackupError> {
+        let mut state = self.state.lock().unwrap();
+        let len = state.files.len();
+        state.files.retain(|(file_uuid, _, _)| file_uuid != uuid);
+        assert_eq!(state.files.len(), len - 1, "{uuid} deleted twice");
+        Ok(())
+    }
+}
+
+/// Provider with a sealed chunk of keys 1 to 4, and key 5 in the active chunk with
+/// secondary key 50
+fn provider_with_sealed_chunk(name: &str) -> (String, BlobProvider) {
+    let path = temp_dir(name);
+    let provider = open(&path);
+    for id in 1..=4 {
+        provider.put(key(id), vec![id; 100 * id as usize]).unwrap();
+    }
+    provider.compact().unwrap();
+    provider
+        .put_with_secondary(key(5), vec![5; 500], 50)
+        .unwrap();
+
+    (path, provider)
+}
+
+fn assert_restores(remote: &MemRemote, name: &str, ids: impl IntoIterator<Item = u8>) {
+    let path = temp_dir(name);
+    block_on(restore(remote, &path, "thumbs")).unwrap();
+
+    let restored = open(&path);
+    for id in ids {
+        assert_eq!(
+            restored.get(key(id)).unwrap(),
+            Some(vec![id; 100 * id as usize])
+        );
+    }
+    assert_eq!(restored.range_by_secondary(0, 100, 10).unwrap(), [key(5)]);
+}
+
+#[test]
+fn backed_up_chunks_are_skipped() {
+    let (_, provider) = provider_with_sealed_chunk("skip");
+    let staging = temp_dir("skip-staging");
+    let remote = MemRemote::default();
+
+    // Two chunks, as key 5 is sealed, then 4 sidecars, the digests and the midx
+    let report = block_on(back_up(&remote, &provider, &staging)).unwrap();
+    assert_eq!(report.transferred_files, 10);
+    assert_eq!(report.skipped_chunks, 0);
+
+    let report = block_on(back_up(&remote, &provider, &staging)).unwrap();
+    assert_eq!(report.transferred_files, 6);
+    assert_eq!(report.skipped_chunks, 2);
+
+    // A chunk missing a file is uploaded again
+    let chunk_file = remote
+        .names()
+        .into_iter()
+        .find(|name| name.ends_with(".dat"))
+        .unwrap();
+    remote.remove(&chunk_file);
+    let report = block_on(back_up(&remote, &provider, &staging)).unwrap();
+    assert_eq!(report.transferred_files, 8);
+    assert_eq!(report.skipped_chunks, 1);
+
+    // Replaced files are swept, one version of every file is left
+    let names = remote.names();
+    assert_eq!(names.len(), 10, "{names:?}");
+    assert_restores(&remote, "skip-restore", 1..=5);
+
+    // The remote digests were only downloaded to compare against
+    let staged = fs::read_dir(&staging)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    assert!(
+        staged.iter().all(|name| !name.starts_with("remote_")),
+        "{staged:?}"
+    );
+}
+
+#[test]
+fn midx_is_uploaded_last() {
+    let (_, provider) = provider_with_sealed_chunk("order");
+    let staging = temp_dir("order-staging");
+    let remote = MemRemote::default();
+
+    block_on(back_up(&remote, &provider, &staging)).unwrap();
+    let uploads = remote.take_uploads();
+    assert!(
+        uploads.last().unwrap().starts_with("thumbs.midx"),
+        "{uploads:?}"
+    );
+
+    // Compact both chunks into a new one, and drop the connection uploading the midx
+    provider.delete(key(1)).unwrap();
+    provider.compact().unwrap();
+    remote.state.lock().unwrap().fail_upload = Some("thumbs.midx");
+    let err = block_on(back_up(&remote, &provider, &staging)).unwrap_err();
+    assert!(matches!(err, BlobBackupError::FilenClientError { .. }));
+    let uploads = remote.take_uploads();
+    assert!(uploads.iter().any(|name| name.starts_with("thumbs.sum")));
+
+    // The new digests went ahead, but nothing of the previous backup was deleted
+    // or replaced yet
+    assert_restores(&remote, "order-interrupted", 1..=5);
+
+    remote.state.lock().unwrap().fail_upload = None;
+    block_on(back_up(&remote, &provider, &staging)).unwrap();
+    let uploads = remote.take_uploads();
+    assert!(
+        uploads.last().unwrap().starts_with("thumbs.midx"),
+        "{uploads:?}"
+    );
+    assert_restores(&remote, "order-restore", 2..=5);
+}
+
+#[test]
+fn interrupted_backups_restore_the_previous_one() {
+    let (_, provider) = provider_with_sealed_chunk("interrupted");
+    let staging = temp_dir("interrupted-staging");
+    let remote = MemRemote::default();
+    block_on(back_up(&remote, &provider, &staging)).unwrap();
+
+    // A reinstalled app numbers its chunks from the start again, so the chunk files
+    // it uploads are new versions of the backed up ones
+    let reinstalled = open(&temp_dir("interrupted-reinstalled"));
+    for id in 6..=9 {
+        reinstalled.put(key(id), vec![id; 100]).unwrap();
+    }
+    reinstalled.compact().unwrap();
+    remote.state.lock().unwrap().fail_upload = Some("thumbs.midx");
+    block_on(back_up(&remote, &reinstalled, &staging)).unwrap_err();
+    let uploads = remote.take_uploads();
+    assert!(
+        uploads
+            .iter()
+            .any(|name| name.starts_with("thumbs_1.dat.v")),
+        "{uploads:?}"
+    );
+
+    // Every file is restored as of the last backup whose midx made it
+    assert_restores(&remote, "interrupted-restore", 1..=5);
+}
+
+#[test]
+fn missing_chunk_files_fail_the_restore() {
+    let (_, provider) = provider_with_sealed_chunk("incomplete");
+    let staging = temp_dir("incomplete-staging");
+    let remote = MemRemote::default();
+    block_on(back_up(&remote, &provider, &staging)).unwrap();
+
+    let chunk_file = remote
+        .names()
+        .into_iter()
+        .find(|name| name.ends_with(".idx"))
+        .unwrap();
+    remote.remove(&chunk_file);
+
+    let path = temp_dir("incomplete-restore");
+    let err = block_on(restore(&remote, &path, "thumbs")).unwrap_err();
+    assert!(
+        matches!(&err, BlobBackupError::IncompleteBackup { msg } if msg.contains(&chunk_file)),
+        "{err}"
+    );
+    // The midx wasn't restored, so nothing points at the missing chunk
+    assert!(!PathBuf::from(&path).join("thumbs.midx").exists());
+}
+
+#[test]
+fn storage_errors_keep_their_kind_and_path() {
+    let (_, provider) = provider_with_sealed_chunk("errors");
+    let staging = format!("{}/missing", temp_dir("errors-staging"));
+
+    let err = block_on(back_up(&MemRemote::default(), &provider, &staging)).unwrap_err();
+    match &err {
+        BlobBackupError::BlobProviderError(BlobProviderError::IoError {
+            kind,
+            path,
+            offset,
+            ..
+        }) => {
+            assert_eq!(*kind, IoErrorKind::NotFound);
+            assert_eq!(
+                path.as_deref(),
+                Some(format!("{staging}/thumbs.midx").as_str())
+            );
+            assert_eq!(*offset, None);
+        }
+        err => panic!("expected a not found error, got {err:?}"),
+    }
+    let source = err.source().and_then(Error::source).unwrap();
+    assert!(source.to_string().contains("thumbs.midx"), "{source}");
+}
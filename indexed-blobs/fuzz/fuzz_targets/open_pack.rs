@@ -0,0 +1,100 @@
+#![no_main]
+
+//! Opens a provider on arbitrary `.midx`, `.idx`, `.dat` and `.cidx` files and reads
+//! through it. Malformed files have to surface as errors, never as panics.
+
+use std::{path::Path, sync::Arc};
+
+use arbitrary::Arbitrary;
+use indexed_blobs::{
+    blob_provider::{BlobProviderOptions, new_blob_provider_with_storage},
+    storage::{OpenMode, Storage, mem_storage::MemStorage},
+};
+use libfuzzer_sys::fuzz_target;
+
+const ROOT: &str = "/blobs";
+const PREFIX: &str = "pack";
+const MAX_CHUNKS: usize = 8;
+
+#[derive(Debug, Arbitrary)]
+struct PackFiles {
+    midx: Vec<u8>,
+    chunks: Vec<(Vec<u8>, Vec<u8>)>,
+    content_idx: Vec<u8>,
+    deduplicate: bool,
+    compact: bool,
+}
+
+fn write_file(storage: &MemStorage, file_name: &str, contents: &[u8]) {
+    let file = storage
+        .open(&Path::new(ROOT).join(file_name), OpenMode::Truncate)
+        .unwrap();
+    file.write_at(contents, 0).unwrap();
+    file.fsync().unwrap();
+}
+
+fn format_key(key: &[u8]) -> String {
+    let hex = key.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fuzz_target!(|files: PackFiles| {
+    let storage = MemStorage::new();
+    let chunks = &files.chunks[..files.chunks.len().min(MAX_CHUNKS)];
+
+    write_file(&storage, &format!("{PREFIX}.midx"), &files.midx);
+    write_file(&storage, &format!("{PREFIX}.cidx"), &files.content_idx);
+    for (chunk, (idx, dat)) in chunks.iter().enumerate() {
+        write_file(&storage, &format!("{PREFIX}_{chunk}.idx"), idx);
+        write_file(&storage, &format!("{PREFIX}_{chunk}.dat"), dat);
+    }
+
+    let options = BlobProviderOptions {
+        deduplicate: files.deduplicate,
+        cache_budget_bytes: 1 << 16,
+        ..Default::default()
+    };
+    let Ok(provider) = new_blob_provider_with_storage(
+        Arc::new(storage),
+        Path::new(ROOT),
+        PREFIX.to_owned(),
+        options,
+    ) else {
+        return;
+    };
+
+    // Keys that actually appear in the index records
+    let keys = chunks
+        .iter()
+        .flat_map(|(idx, _)| idx.chunks_exact(32))
+        .map(|record| format_key(&record[..16]))
+        .collect::<Vec<_>>();
+
+    for key in &keys {
+        let _ = provider.contains(key.clone());
+        let _ = provider.get(key.clone());
+        let _ = provider.get_range(key.clone(), 1, 8);
+    }
+    let _ = provider.stats();
+    let _ = provider.prefetch(keys.clone());
+
+    if files.compact {
+        let _ = provider.compact();
+        for key in &keys {
+            let _ = provider.get(key.clone());
+        }
+    }
+
+    if let Some(key) = keys.first() {
+        let _ = provider.put(key.clone(), vec![0; 32]);
+        let _ = provider.delete(key.clone());
+    }
+});
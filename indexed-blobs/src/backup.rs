@@ -0,0 +1,349 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    path::{Path, PathBuf},
+};
+
+use zerocopy::IntoBytes;
+
+use crate::{
+    blob_provider::BlobProvider,
+    consts::{BLOB_EXTENSION, IDX_EXTENSION, MAX_BLOB_SIZE},
+    data_structures::{
+        chunk_digest_idx::{ChunkDigestEntry, parse_digests},
+        chunk_idx::SealedIdx,
+        mmap_midx::{MIDX_FLAG_RETIRED, MIdxEntry},
+    },
+    err_type::BlobProviderError,
+    fs::{
+        chunk_digest_file_path, chunk_file_path, generation_floor_file_path, midx_file_path,
+        perceptual_hash_file_path, placeholder_idx_file_path, secondary_idx_file_path,
+    },
+    merkle::{ChunkDigest, digest_records},
+    storage::{OpenMode, Storage, StorageFile, fs_storage::FsStorage, read_all},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct BackupFile {
+    /// Name of the file inside the blob directory, and inside the backup
+    pub name: String,
+    /// Where the file is uploaded from, or downloaded to when restoring
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct BackupChunk {
+    pub digest: ChunkDigest,
+    /// The chunk's `.idx` and `.dat` files
+    pub files: Vec<BackupFile>,
+}
+
+/// Files backing up the sealed chunks of a provider
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct BackupManifest {
+    /// Oldest first
+    pub chunks: Vec<BackupChunk>,
+    /// midx that only knows the sealed chunks, every other chunk is flagged as retired
+    pub midx: BackupFile,
+    /// Digests of the sealed chunks, in the format of the `.sum` sidecar
+    pub digests: BackupFile,
+    /// Generation floor, secondary keys, placeholders and perceptual hashes of the
+    /// keys in the chunks, restored along with the midx
+    pub sidecars: Vec<BackupFile>,
+}
+
+fn backup_file(path: PathBuf) -> BackupFile {
+    BackupFile {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        path: path.to_string_lossy().into_owned(),
+    }
+}
+
+fn backup_chunk(
+    root_blob_dir: &Path,
+    blob_file_prefix: &str,
+    digest: &ChunkDigestEntry,
+) -> BackupChunk {
+    BackupChunk {
+        digest: ChunkDigest::from(digest),
+        files: [IDX_EXTENSION, BLOB_EXTENSION]
+            .into_iter()
+            .map(|extension| {
+                backup_file(chunk_file_path(
+                    root_blob_dir,
+                    blob_file_prefix,
+                    digest.chunk as usize,
+                    extension,
+                ))
+            })
+            .collect(),
+    }
+}
+
+/// Sidecars a backup holds besides its chunks, in the blob directory at `root_blob_dir`
+fn sidecar_files(root_blob_dir: &Path, blob_file_prefix: &str) -> Vec<BackupFile> {
+    [
+        generation_floor_file_path(root_blob_dir, blob_file_prefix),
+        secondary_idx_file_path(root_blob_dir, blob_file_prefix),
+        placeholder_idx_file_path(root_blob_dir, blob_file_prefix),
+        perceptual_hash_file_path(root_blob_dir, blob_file_prefix),
+    ]
+    .into_iter()
+    .map(backup_file)
+    .collect()
+}
+
+fn write_file(storage: &dyn Storage, path: &Path, data: &[u8]) -> Result<(), BlobProviderError> {
+    let file = storage.open(path, OpenMode::Truncate)?;
+    file.write_at(data, 0)?;
+    file.fsync()?;
+
+    Ok(())
+}
+
+fn read_digests(
+    storage: &dyn Storage,
+    path: &Path,
+) -> Result<Vec<ChunkDigestEntry>, BlobProviderError> {
+    Ok(parse_digests(&read_all(
+        storage.open(path, OpenMode::Read)?.as_ref(),
+    )?))
+}
+
+/// Digest of a chunk restored into the blob directory, with the number of records
+/// `expected` has. Blobs deduplicated into older chunks are read from those chunks'
+/// files, which are restored first.
+fn digest_chunk_files(
+    storage: &dyn Storage,
+    root_blob_dir: &Path,
+    blob_file_prefix: &str,
+    expected: &ChunkDigestEntry,
+) -> Result<ChunkDigestEntry, BlobProviderError> {
+    let chunk = expected.chunk as usize;
+    let sealed = SealedIdx::open(
+        storage,
+        &chunk_file_path(root_blob_dir, blob_file_prefix, chunk, IDX_EXTENSION),
+        expected.num_records as usize,
+        chunk,
+    )?;
+
+    let mut dat_files = HashMap::<u32, Box<dyn StorageFile>>::new();
+    digest_records(chunk, sealed.entries(), |record| {
+        if record.is_inline() {
+            return sealed
+                .inline_bytes(record.offset, record.len)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| BlobProviderError::InvalidIdx {
+                    chunk: chunk as u64,
+                    offset: Some(record.offset as u64),
+                    reason: "inline blob is out of bounds".to_owned(),
+                });
+        }
+
+        // Records come from disk, don't let a corrupt one allocate unbounded memory
+        if record.len as usize > MAX_BLOB_SIZE {
+            return Err(BlobProviderError::InvalidIdx {
+                chunk: record.chunk as u64,
+                offset: Some(record.offset as u64),
+                reason: format!("blob of {} bytes exceeds the maximum blob size", record.len),
+            });
+        }
+
+        let file = match dat_files.entry(record.chunk) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(storage.open(
+                &chunk_file_path(
+                    root_blob_dir,
+                    blob_file_prefix,
+                    record.chunk as usize,
+                    BLOB_EXTENSION,
+                ),
+                OpenMode::Read,
+            )?),
+        };
+
+        let mut data = vec![0; record.len as usize];
+        file.read_at(&mut data, record.offset as u64)?;
+        Ok(data)
+    })
+}
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Seals the active chunk, lists the files backing up every sealed chunk, and
+    /// writes the backup's midx, chunk digests and sidecars to `staging_dir`, which
+    /// can't be the blob directory. Sealed chunk files never change, so they are
+    /// uploaded straight from the blob directory while the provider stays in use. A
+    /// compaction meanwhile deletes them though, the backup then has to be prepared
+    /// again.
+    pub fn prepare_backup(&self, staging_dir: String) -> Result<BackupManifest, BlobProviderError> {
+        let staging_dir = Path::new(&staging_dir);
+
+        // The staged midx and sidecars would replace the provider's own
+        let is_blob_dir = match (
+            staging_dir.canonicalize(),
+            self.root_blob_dir.canonicalize(),
+        ) {
+            (Ok(staging_dir), Ok(root_blob_dir)) => staging_dir == root_blob_dir,
+            _ => staging_dir == self.root_blob_dir,
+        };
+        if is_blob_dir {
+            return Err(BlobProviderError::InvalidPath);
+        }
+
+        // Chunks are only sealed and retired with the index lock held, along with
+        // publishing the snapshot that tells. Sidecar values are applied under it too,
+        // once the commit they came with landed.
+        let (mut midx_entries, snapshot, sidecars) = {
+            let mut index = self.index.lock()?;
+            if index
+                .active
+                .as_ref()
+                .is_some_and(|active| active.num_records > 0)
+            {
+                self.seal_active_chunk(&mut index)?;
+            }
+
+            let midx_entries = (0..index.midx.entry_count())
+                .map(|chunk| index.midx[chunk])
+                .collect::<Vec<MIdxEntry>>();
+            // In the order of `sidecar_files`
+            let sidecars = [
+                index.generation_floor.to_le_bytes().to_vec(),
+                index.secondary.encode()?,
+                index.placeholders.encode()?,
+                index.perceptual_hashes.encode()?,
+            ];
+
+            (midx_entries, index.snapshot(), sidecars)
+        };
+        for entry in midx_entries.iter_mut().filter(|entry| !entry.is_sealed()) {
+            entry.flags |= MIDX_FLAG_RETIRED;
+        }
+
+        let mut digests = Vec::new();
+        for (chunk, sealed) in snapshot.sealed_chunks() {
+            digests.push(self.sealed_digest(&snapshot, *chunk, sealed)?);
+        }
+
+        let midx_path = midx_file_path(staging_dir, &self.blob_file_prefix);
+        write_file(self.storage.as_ref(), &midx_path, midx_entries.as_bytes())?;
+        let digests_path = chunk_digest_file_path(staging_dir, &self.blob_file_prefix);
+        write_file(self.storage.as_ref(), &digests_path, digests.as_bytes())?;
+        let sidecar_files = sidecar_files(staging_dir, &self.blob_file_prefix);
+        for (file, data) in sidecar_files.iter().zip(&sidecars) {
+            write_file(self.storage.as_ref(), Path::new(&file.path), data)?;
+        }
+
+        Ok(BackupManifest {
+            chunks: digests
+                .iter()
+                .map(|digest| backup_chunk(&self.root_blob_dir, &self.blob_file_prefix, digest))
+                .collect(),
+            midx: backup_file(midx_path),
+            digests: backup_file(digests_path),
+            sidecars: sidecar_files,
+        })
+    }
+}
+
+/// Chunk digests of a backup's digests file, e.g. to skip uploading chunks the
+/// backup already holds
+#[uniffi::export]
+pub fn read_backup_digests(path: String) -> Result<Vec<ChunkDigest>, BlobProviderError> {
+    Ok(read_digests(&FsStorage, Path::new(&path))?
+        .iter()
+        .map(ChunkDigest::from)
+        .collect())
+}
+
+/// Where the digests file of a backup goes in the blob directory at `path`. It is
+/// restored first, to plan restoring the chunks with.
+#[uniffi::export]
+pub fn backup_digests_file(path: String, prefix: String) -> Result<BackupFile, BlobProviderError> {
+    if prefix.is_empty() {
+        return Err(BlobProviderError::InvalidPrefix);
+    }
+
+    Ok(backup_file(chunk_digest_file_path(
+        Path::new(&path),
+        &prefix,
+    )))
+}
+
+/// Where the midx of a backup goes in the blob directory at `path`. It is restored
+/// last, once every chunk it points at is.
+#[uniffi::export]
+pub fn backup_midx_file(path: String, prefix: String) -> Result<BackupFile, BlobProviderError> {
+    if prefix.is_empty() {
+        return Err(BlobProviderError::InvalidPrefix);
+    }
+
+    Ok(backup_file(midx_file_path(Path::new(&path), &prefix)))
+}
+
+/// Plans restoring the backup whose digests file was downloaded to `digests_path`
+/// into the blob directory at `path`, see `plan_restore_in`
+#[uniffi::export]
+pub fn plan_restore(
+    path: String,
+    prefix: String,
+    digests_path: String,
+) -> Result<BackupManifest, BlobProviderError> {
+    let root_blob_dir = Path::new(&path);
+
+    if path.is_empty() || !root_blob_dir.exists() || !root_blob_dir.is_dir() {
+        return Err(BlobProviderError::InvalidPath);
+    }
+
+    plan_restore_in(&FsStorage, root_blob_dir, &prefix, Path::new(&digests_path))
+}
+
+/// Plans a restore on top of any storage backend, e.g. an in-memory one in tests
+#[cfg(feature = "test-support")]
+pub fn plan_restore_with_storage(
+    storage: &dyn Storage,
+    root_blob_dir: &Path,
+    prefix: &str,
+    digests_path: &Path,
+) -> Result<BackupManifest, BlobProviderError> {
+    plan_restore_in(storage, root_blob_dir, prefix, digests_path)
+}
+
+/// Lists the chunks of a backup that still have to be downloaded, to where in the
+/// blob directory, and where the backup's midx, digests and sidecars go once they are.
+///
+/// Chunks whose files are already there and digest as expected, e.g. after an
+/// interrupted restore, are skipped, so planning again once every chunk was
+/// downloaded lists none. No provider may be open on the directory until the midx
+/// is restored.
+fn plan_restore_in(
+    storage: &dyn Storage,
+    root_blob_dir: &Path,
+    prefix: &str,
+    digests_path: &Path,
+) -> Result<BackupManifest, BlobProviderError> {
+    if prefix.is_empty() {
+        return Err(BlobProviderError::InvalidPrefix);
+    }
+
+    let mut chunks = Vec::new();
+    for digest in read_digests(storage, digests_path)? {
+        // Whatever keeps the files from digesting as expected, e.g. a torn download,
+        // is fixed by downloading them again
+        let is_restored = digest_chunk_files(storage, root_blob_dir, prefix, &digest)
+            .is_ok_and(|restored| restored == digest);
+        if !is_restored {
+            chunks.push(backup_chunk(root_blob_dir, prefix, &digest));
+        }
+    }
+
+    Ok(BackupManifest {
+        chunks,
+        midx: backup_file(midx_file_path(root_blob_dir, prefix)),
+        digests: backup_file(chunk_digest_file_path(root_blob_dir, prefix)),
+        sidecars: sidecar_files(root_blob_dir, prefix),
+    })
+}
@@ -0,0 +1,144 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    blob_provider::BlobProvider,
+    consts::MAX_BLOB_SIZE,
+    data_structures::{
+        blob_index::BlobMetadata,
+        chunk_idx::{BlobKey, BlobLocation, IdxEntry},
+        content_idx::{ContentHash, hash_content},
+    },
+    err_type::BlobProviderError,
+    key::parse_key,
+};
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct BlobBatchEntry {
+    pub key: String,
+    pub data: Vec<u8>,
+    /// Secondary key to give `key`, see `put_with_secondary`
+    #[uniffi(default = None)]
+    pub secondary: Option<i64>,
+}
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Stores every entry, replacing the blobs of their keys' latest generations. The
+    /// whole batch is published with a single index commit, so after a crash either
+    /// all entries are visible or none. A key appearing twice ends up with its last
+    /// entry.
+    pub fn write_batch(&self, entries: Vec<BlobBatchEntry>) -> Result<(), BlobProviderError> {
+        let mut blobs = Vec::with_capacity(entries.len());
+        let mut metadata_by_key: HashMap<BlobKey, BlobMetadata> = HashMap::new();
+        for entry in entries {
+            if entry.data.len() > MAX_BLOB_SIZE {
+                return Err(BlobProviderError::BlobTooLarge(entry.data.len() as u64));
+            }
+
+            let inline = self.stores_inline(entry.data.len() as u64);
+            let hash = (self.options.deduplicate && !inline).then(|| hash_content(&entry.data));
+            let key = parse_key(&entry.key)?;
+            let metadata = metadata_by_key.entry(key).or_default();
+            metadata.secondary = entry.secondary.or(metadata.secondary);
+            blobs.push((key, entry.data, hash));
+        }
+
+        if blobs.is_empty() {
+            return Ok(());
+        }
+
+        let mut index = self.index.lock()?;
+
+        // Identical blobs within the batch are deduplicated against each other too
+        let mut appended: HashMap<ContentHash, BlobLocation> = HashMap::new();
+        let mut locations = Vec::with_capacity(blobs.len());
+
+        for (_, data, hash) in &blobs {
+            if self.stores_inline(data.len() as u64) {
+                locations.push(None);
+                continue;
+            }
+
+            let existing = hash.as_ref().and_then(|hash| {
+                appended.get(hash).copied().or_else(|| {
+                    index
+                        .content
+                        .as_ref()
+                        .and_then(|content| content.find(hash))
+                })
+            });
+
+            let location = match existing {
+                Some(location) => location,
+                None => {
+                    let location = self
+                        .ensure_active_chunk(&mut index, data.len() as u64)?
+                        .append_data(data)?;
+                    if let Some(hash) = hash {
+                        appended.insert(*hash, location);
+                    }
+                    location
+                }
+            };
+
+            locations.push(Some(location));
+        }
+
+        // Inline bytes must land in the chunk their records are committed to
+        self.ensure_active_chunk(&mut index, 0)?;
+
+        let mut records = Vec::with_capacity(blobs.len());
+        for ((key, data, _), location) in blobs.iter().zip(locations) {
+            let location = match location {
+                Some(location) => location,
+                None => self
+                    .ensure_active_chunk(&mut index, 0)?
+                    .append_inline(data)?,
+            };
+
+            let generation = index.snapshot().put_generation(key);
+            records.push(IdxEntry::new(*key, generation, location));
+        }
+
+        index.commit_with_metadata(&records, &metadata_by_key)?;
+
+        let written = blobs.iter().map(|(key, _, _)| *key).collect::<Vec<_>>();
+        self.finish_write(&mut index, &written, appended);
+
+        Ok(())
+    }
+
+    /// Removes every generation of every key with a single index commit and returns
+    /// how many keys were removed
+    pub fn delete_batch(&self, keys: Vec<String>) -> Result<u64, BlobProviderError> {
+        let keys = keys
+            .iter()
+            .map(|key| parse_key(key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut index = self.index.lock()?;
+
+        let snapshot = index.snapshot();
+        let mut seen = HashSet::new();
+        let live_keys = keys
+            .into_iter()
+            .filter(|key| seen.insert(*key) && snapshot.find_latest(key).is_some())
+            .collect::<Vec<_>>();
+
+        if live_keys.is_empty() {
+            return Ok(0);
+        }
+
+        let tombstones = live_keys
+            .iter()
+            .flat_map(|key| snapshot.live_generations(key))
+            .map(|entry| IdxEntry::tombstone(entry.key, entry.generation))
+            .collect::<Vec<_>>();
+
+        self.ensure_active_chunk(&mut index, 0)?;
+        index.commit(&tombstones)?;
+        self.finish_delete(&live_keys);
+
+        Ok(live_keys.len() as u64)
+    }
+}
@@ -1,47 +1,342 @@
-use core::num;
 use std::{
-    iter::Map,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::AtomicU64},
+    time::Duration,
 };
 
-use crate::err_type::BlobProviderError;
+use arc_swap::ArcSwap;
+
+use crate::{
+    blob_writer::{BlobWriter, StagingFile},
+    consts::MAX_BLOB_SIZE,
+    data_structures::{
+        blob_cache::BlobCache,
+        blob_index::{BlobIndex, IndexSnapshot},
+        chunk_digest_idx::ChunkDigestIdx,
+        chunk_idx::IdxEntry,
+        fd_pool::FdPool,
+        perceptual_hash_idx::PerceptualHashIdx,
+        placeholder_idx::PlaceholderIdx,
+        quota_tracker::QuotaTracker,
+        secondary_idx::SecondaryIdx,
+    },
+    err_type::BlobProviderError,
+    fs::{eviction_log_file_path, pins_file_path, staging_file_path},
+    group_commit::spawn_group_committer,
+    key::parse_key,
+    stats::dat_bytes,
+    storage::{Storage, fs_storage::FsStorage},
+};
 
 #[derive(uniffi::Object)]
 pub struct BlobProvider {
+    pub(crate) storage: Arc<dyn Storage>,
     pub(crate) root_blob_dir: PathBuf,
     pub(crate) blob_file_prefix: String,
-    pub(crate) num_chunks: usize,
-    pub(crate) midx: crate::data_structures::mmap_midx::MIdx,
-    pub(crate) idx_fd_pool: crate::data_structures::fd_pool::FdPool,
-    pub(crate) dat_fd_pool: crate::data_structures::fd_pool::FdPool,
+    pub(crate) options: BlobProviderOptions,
+    /// Serializes writers, readers only ever load `snapshot`
+    pub(crate) index: Arc<Mutex<BlobIndex>>,
+    /// Latest snapshot the writer published, swapped without blocking readers
+    pub(crate) snapshot: Arc<ArcSwap<IndexSnapshot>>,
+    pub(crate) secondary: Arc<SecondaryIdx>,
+    pub(crate) placeholders: Arc<PlaceholderIdx>,
+    pub(crate) perceptual_hashes: Arc<PerceptualHashIdx>,
+    pub(crate) digests: Arc<ChunkDigestIdx>,
+    /// Tells this provider's files and cached blobs apart from those of other
+    /// namespaces sharing the fd pool and cache
+    pub(crate) namespace: u32,
+    pub(crate) dat_fd_pool: Arc<FdPool>,
+    pub(crate) cache: Option<Arc<BlobCache>>,
+    pub(crate) quota: Option<QuotaTracker>,
+    pub(crate) next_staging_id: AtomicU64,
+    pub(crate) prefetch_generation: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct BlobProviderOptions {
+    /// Store byte-identical blobs once and let every key reference the same bytes
+    #[uniffi(default = false)]
+    pub deduplicate: bool,
+
+    /// Keep recently read blobs in memory up to this many bytes, 0 disables the cache.
+    /// A `BlobStore` uses it as the budget of the cache all its namespaces share.
+    #[uniffi(default = 0)]
+    pub cache_budget_bytes: u64,
+
+    /// Evict the least recently used unpinned blobs once live blobs take more than
+    /// this many bytes, 0 disables eviction. Writes compact the provider once
+    /// replaced and evicted bytes take up as much again, so its `.dat` files stay
+    /// within about twice the quota.
+    #[uniffi(default = 0)]
+    pub quota_bytes: u64,
+
+    /// Store blobs of at most this many bytes, e.g. placeholders, inside the index so
+    /// reads never touch a `.dat` file. Capped at 4 KiB, 0 disables inline storage.
+    /// Inline blobs are never deduplicated.
+    #[uniffi(default = 0)]
+    pub inline_max_bytes: u64,
+
+    /// Stage writes without syncing and commit them together once this many are
+    /// staged, e.g. while generating thumbnails during the initial sync. 0 or 1 commits
+    /// every write on its own. Staged writes are readable right away but a crash
+    /// loses them, call `flush` to make them durable.
+    #[uniffi(default = 0)]
+    pub group_commit_max_writes: u64,
+
+    /// Also commit staged writes once the oldest one waited this many milliseconds,
+    /// 0 disables the time limit
+    #[uniffi(default = 0)]
+    pub group_commit_max_delay_ms: u64,
+
+    /// Let `compact` lay out blobs in secondary key order, so reading a range of the
+    /// timeline becomes a sequential read. Blobs without a secondary key go last.
+    #[uniffi(default = false)]
+    pub compact_by_secondary: bool,
 }
 
 #[uniffi::export]
 pub fn new_blob_provider(path: String, prefix: String) -> Result<BlobProvider, BlobProviderError> {
+    new_blob_provider_with_options(path, prefix, BlobProviderOptions::default())
+}
+
+#[uniffi::export]
+pub fn new_blob_provider_with_options(
+    path: String,
+    prefix: String,
+    options: BlobProviderOptions,
+) -> Result<BlobProvider, BlobProviderError> {
     let root_blob_dir = Path::new(&path);
 
     if path.is_empty() || !root_blob_dir.exists() || !root_blob_dir.is_dir() {
         return Err(BlobProviderError::InvalidPath);
     }
 
+    open_standalone_blob_provider(Arc::new(FsStorage), root_blob_dir, prefix, options)
+}
+
+/// Opens a provider on top of any storage backend, e.g. an in-memory one in tests
+#[cfg(feature = "test-support")]
+pub fn new_blob_provider_with_storage(
+    storage: Arc<dyn Storage>,
+    root_blob_dir: &Path,
+    prefix: String,
+    options: BlobProviderOptions,
+) -> Result<BlobProvider, BlobProviderError> {
+    open_standalone_blob_provider(storage, root_blob_dir, prefix, options)
+}
+
+/// Opens a provider with its own fd pool and cache, outside of any `BlobStore`
+fn open_standalone_blob_provider(
+    storage: Arc<dyn Storage>,
+    root_blob_dir: &Path,
+    prefix: String,
+    options: BlobProviderOptions,
+) -> Result<BlobProvider, BlobProviderError> {
     if prefix.is_empty() {
         return Err(BlobProviderError::InvalidPrefix);
     }
 
-    let num_chunks = BlobProvider::get_num_chunks(root_blob_dir.to_path_buf(), &prefix)?;
-    let midx_name = format!("{}.midx", prefix);
+    let dat_fd_pool = Arc::new(FdPool::new(storage.clone()));
+    let cache = BlobCache::for_budget(options.cache_budget_bytes);
+
+    open_blob_provider(
+        storage,
+        root_blob_dir,
+        prefix,
+        options,
+        0,
+        dat_fd_pool,
+        cache,
+    )
+}
+
+/// Opens the provider of `prefix`, reading through the given fd pool and cache
+pub(crate) fn open_blob_provider(
+    storage: Arc<dyn Storage>,
+    root_blob_dir: &Path,
+    prefix: String,
+    options: BlobProviderOptions,
+    namespace: u32,
+    dat_fd_pool: Arc<FdPool>,
+    cache: Option<Arc<BlobCache>>,
+) -> Result<BlobProvider, BlobProviderError> {
+    let index = BlobProvider::load_blob_index(&storage, root_blob_dir, &prefix, &options)?;
+    let quota = match options.quota_bytes {
+        0 => None,
+        quota_bytes => Some(QuotaTracker::open(
+            storage.clone(),
+            quota_bytes,
+            &pins_file_path(root_blob_dir, &prefix),
+            &eviction_log_file_path(root_blob_dir, &prefix),
+            &index.snapshot().live_entries(),
+            dat_bytes(storage.as_ref(), root_blob_dir, &prefix, &index)?,
+        )?),
+    };
+
+    let snapshot = index.published.clone();
+    let secondary = index.secondary.clone();
+    let placeholders = index.placeholders.clone();
+    let perceptual_hashes = index.perceptual_hashes.clone();
+    let digests = index.digests.clone();
+    let index = Arc::new(Mutex::new(index));
+    if options.group_commit_max_delay_ms > 0 {
+        spawn_group_committer(
+            Arc::downgrade(&index),
+            Duration::from_millis(options.group_commit_max_delay_ms),
+        )?;
+    }
 
     Ok(BlobProvider {
+        storage,
         root_blob_dir: root_blob_dir.to_path_buf(),
         blob_file_prefix: prefix,
-        num_chunks,
-        midx: crate::data_structures::mmap_midx::open_or_create_midx(
-            &root_blob_dir.join(midx_name),
-        )?,
-        idx_fd_pool: crate::data_structures::fd_pool::FdPool::new(),
-        dat_fd_pool: crate::data_structures::fd_pool::FdPool::new(),
+        options,
+        index,
+        snapshot,
+        secondary,
+        placeholders,
+        perceptual_hashes,
+        digests,
+        namespace,
+        dat_fd_pool,
+        cache,
+        quota,
+        next_staging_id: AtomicU64::new(0),
+        prefetch_generation: AtomicU64::new(0),
     })
 }
 
 #[uniffi::export]
-impl BlobProvider {}
+impl BlobProvider {
+    /// Stores `data` under `key`, replacing the blob of its latest generation
+    pub fn put(&self, key: String, data: Vec<u8>) -> Result<(), BlobProviderError> {
+        self.put_latest(parse_key(&key)?, &data, None, None)
+    }
+
+    /// Starts streaming a blob for `key`. If `expected_len` is given, the commit fails
+    /// unless exactly that many bytes were written.
+    pub fn begin_write(
+        self: Arc<Self>,
+        key: String,
+        expected_len: Option<u64>,
+    ) -> Result<BlobWriter, BlobProviderError> {
+        let key = parse_key(&key)?;
+
+        if let Some(expected_len) = expected_len
+            && expected_len > MAX_BLOB_SIZE as u64
+        {
+            return Err(BlobProviderError::BlobTooLarge(expected_len));
+        }
+
+        let staging_id = self
+            .next_staging_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let staging = StagingFile::create(
+            self.storage.as_ref(),
+            staging_file_path(&self.root_blob_dir, &self.blob_file_prefix, staging_id),
+        )?;
+
+        Ok(BlobWriter {
+            provider: self,
+            key,
+            expected_len,
+            staging: Mutex::new(Some(staging)),
+        })
+    }
+
+    /// Reads the blob of the latest generation of `key`
+    pub fn get(&self, key: String) -> Result<Option<Vec<u8>>, BlobProviderError> {
+        let key = parse_key(&key)?;
+        self.touch_for_quota(&key)?;
+
+        if let Some(cache) = &self.cache
+            && let Some(data) = cache.get(&(self.namespace, key))?
+        {
+            return Ok(Some(data.as_ref().clone()));
+        }
+
+        self.with_snapshot(|snapshot| {
+            let Some(entry) = snapshot.find_latest(&key) else {
+                return Ok(None);
+            };
+
+            let data = self.read_entry_bytes(snapshot, &entry, 0, entry.len as u64)?;
+            if !entry.is_inline() {
+                self.cache_read(snapshot, key, &data)?;
+            }
+
+            Ok(Some(data))
+        })
+    }
+
+    /// Reads `len` bytes starting at `offset` of the blob without reading the rest of it
+    pub fn get_range(
+        &self,
+        key: String,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>, BlobProviderError> {
+        let key = parse_key(&key)?;
+        self.touch_for_quota(&key)?;
+
+        if let Some(cache) = &self.cache
+            && let Some(data) = cache.get(&(self.namespace, key))?
+        {
+            let blob_len = data.len() as u64;
+            return match offset.checked_add(len).filter(|end| *end <= blob_len) {
+                Some(end) => Ok(Some(data[offset as usize..end as usize].to_vec())),
+                None => Err(BlobProviderError::RangeOutOfBounds(offset, len, blob_len)),
+            };
+        }
+
+        self.with_snapshot(|snapshot| {
+            let Some(entry) = snapshot.find_latest(&key) else {
+                return Ok(None);
+            };
+
+            let blob_len = entry.len as u64;
+            if offset.checked_add(len).is_none_or(|end| end > blob_len) {
+                return Err(BlobProviderError::RangeOutOfBounds(offset, len, blob_len));
+            }
+
+            Ok(Some(self.read_entry_bytes(snapshot, &entry, offset, len)?))
+        })
+    }
+
+    pub fn contains(&self, key: String) -> Result<bool, BlobProviderError> {
+        let key = parse_key(&key)?;
+
+        Ok(self.snapshot.load().find_latest(&key).is_some())
+    }
+
+    /// Removes every generation of `key`, returning whether a blob was removed
+    pub fn delete(&self, key: String) -> Result<bool, BlobProviderError> {
+        let key = parse_key(&key)?;
+        let mut index = self.index.lock()?;
+
+        let tombstones = index
+            .snapshot()
+            .live_generations(&key)
+            .iter()
+            .map(|entry| IdxEntry::tombstone(key, entry.generation))
+            .collect::<Vec<_>>();
+        if tombstones.is_empty() {
+            return Ok(false);
+        }
+
+        self.ensure_active_chunk(&mut index, 0)?;
+        index.commit(&tombstones)?;
+        self.finish_delete(&[key]);
+
+        Ok(true)
+    }
+
+    /// Shrinks the read cache to at most `max_bytes`, e.g. on a memory warning. The
+    /// cache of a `BlobStore` namespace is shared with the other namespaces.
+    pub fn trim_cache(&self, max_bytes: u64) -> Result<(), BlobProviderError> {
+        match &self.cache {
+            Some(cache) => cache.trim(max_bytes),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    blob_provider::{BlobProvider, BlobProviderOptions, open_blob_provider},
+    consts::MIDX_EXTENSION,
+    data_structures::{blob_cache::BlobCache, fd_pool::FdPool},
+    err_type::BlobProviderError,
+    stats::{BlobCacheStats, BlobProviderStats},
+    storage::{Storage, fs_storage::FsStorage},
+};
+
+/// Several named blob families in one directory, e.g. grid thumbnails and larger
+/// previews. Every namespace is a provider with its own chunks, but they all read
+/// through one fd pool and one read cache, so the fd budget holds for the whole store.
+#[derive(uniffi::Object)]
+pub struct BlobStore {
+    storage: Arc<dyn Storage>,
+    root_blob_dir: PathBuf,
+    options: BlobProviderOptions,
+    dat_fd_pool: Arc<FdPool>,
+    cache: Option<Arc<BlobCache>>,
+    namespaces: Mutex<BTreeMap<String, Arc<BlobProvider>>>,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct BlobNamespaceStats {
+    pub name: String,
+    pub stats: BlobProviderStats,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct BlobStoreStats {
+    pub namespaces: Vec<BlobNamespaceStats>,
+    /// Sum over every namespace
+    pub total: BlobProviderStats,
+}
+
+/// Opens the store in `path`. `options` apply to every namespace, except for the
+/// cache budget which is shared by all of them.
+#[uniffi::export]
+pub fn new_blob_store(
+    path: String,
+    options: BlobProviderOptions,
+) -> Result<BlobStore, BlobProviderError> {
+    let root_blob_dir = Path::new(&path);
+
+    if path.is_empty() || !root_blob_dir.exists() || !root_blob_dir.is_dir() {
+        return Err(BlobProviderError::InvalidPath);
+    }
+
+    Ok(open_blob_store(Arc::new(FsStorage), root_blob_dir, options))
+}
+
+/// Opens a store on top of any storage backend, e.g. an in-memory one in tests
+#[cfg(feature = "test-support")]
+pub fn new_blob_store_with_storage(
+    storage: Arc<dyn Storage>,
+    root_blob_dir: &Path,
+    options: BlobProviderOptions,
+) -> BlobStore {
+    open_blob_store(storage, root_blob_dir, options)
+}
+
+fn open_blob_store(
+    storage: Arc<dyn Storage>,
+    root_blob_dir: &Path,
+    options: BlobProviderOptions,
+) -> BlobStore {
+    BlobStore {
+        dat_fd_pool: Arc::new(FdPool::new(storage.clone())),
+        cache: BlobCache::for_budget(options.cache_budget_bytes),
+        storage,
+        root_blob_dir: root_blob_dir.to_path_buf(),
+        options,
+        namespaces: Mutex::new(BTreeMap::new()),
+    }
+}
+
+#[uniffi::export]
+impl BlobStore {
+    /// Returns the provider of namespace `name`, creating the namespace on first use.
+    /// Names are made of ASCII letters, digits and `-`, since they prefix file names.
+    pub fn namespace(&self, name: String) -> Result<Arc<BlobProvider>, BlobProviderError> {
+        if !is_valid_namespace(&name) {
+            return Err(BlobProviderError::InvalidPrefix);
+        }
+
+        let mut namespaces = self.namespaces.lock()?;
+        if let Some(provider) = namespaces.get(&name) {
+            return Ok(provider.clone());
+        }
+
+        let provider = Arc::new(open_blob_provider(
+            self.storage.clone(),
+            &self.root_blob_dir,
+            name.clone(),
+            self.options.clone(),
+            namespaces.len() as u32,
+            self.dat_fd_pool.clone(),
+            self.cache.clone(),
+        )?);
+        namespaces.insert(name, provider.clone());
+
+        Ok(provider)
+    }
+
+    /// Every namespace in the directory, whether it was opened yet or not, sorted
+    pub fn namespace_names(&self) -> Result<Vec<String>, BlobProviderError> {
+        let mut names = self
+            .namespaces
+            .lock()?
+            .keys()
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        for file_name in self.storage.list(&self.root_blob_dir)? {
+            if let Some(name) = file_name
+                .strip_suffix(MIDX_EXTENSION)
+                .and_then(|rest| rest.strip_suffix('.'))
+                .filter(|name| is_valid_namespace(name))
+            {
+                names.insert(name.to_owned());
+            }
+        }
+
+        Ok(names.into_iter().collect())
+    }
+
+    pub fn stats(&self) -> Result<BlobStoreStats, BlobProviderError> {
+        self.collect_stats(|provider| provider.stats())
+    }
+
+    /// Compacts every namespace in turn
+    pub fn compact(&self) -> Result<BlobStoreStats, BlobProviderError> {
+        self.collect_stats(|provider| provider.compact())
+    }
+
+    /// Shrinks the shared read cache to at most `max_bytes`, e.g. on a memory warning
+    pub fn trim_cache(&self, max_bytes: u64) -> Result<(), BlobProviderError> {
+        match &self.cache {
+            Some(cache) => cache.trim(max_bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// Hit and miss counters of the shared read cache, `None` when caching is disabled
+    pub fn cache_stats(&self) -> Result<Option<BlobCacheStats>, BlobProviderError> {
+        self.cache.as_ref().map(|cache| cache.stats()).transpose()
+    }
+}
+
+impl BlobStore {
+    /// Opens every namespace and sums up what `stats_of` returns for each
+    fn collect_stats(
+        &self,
+        stats_of: impl Fn(&BlobProvider) -> Result<BlobProviderStats, BlobProviderError>,
+    ) -> Result<BlobStoreStats, BlobProviderError> {
+        let mut namespaces = Vec::new();
+        let mut total = BlobProviderStats::default();
+
+        for name in self.namespace_names()? {
+            let provider = self.namespace(name.clone())?;
+            let stats = stats_of(&provider)?;
+
+            total.num_chunks += stats.num_chunks;
+            total.num_blobs += stats.num_blobs;
+            total.logical_bytes += stats.logical_bytes;
+            total.stored_bytes += stats.stored_bytes;
+            total.inline_bytes += stats.inline_bytes;
+            total.dat_bytes += stats.dat_bytes;
+            total.deduplicated_bytes += stats.deduplicated_bytes;
+
+            namespaces.push(BlobNamespaceStats { name, stats });
+        }
+
+        Ok(BlobStoreStats { namespaces, total })
+    }
+}
+
+/// Rules out `_` and `.`, so no namespace can mistake another one's chunk, staging or
+/// sidecar files for its own
+fn is_valid_namespace(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+}
@@ -0,0 +1,140 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    blob_provider::BlobProvider,
+    consts::MAX_BLOB_SIZE,
+    data_structures::{blob_index::BlobMetadata, chunk_idx::BlobKey},
+    err_type::BlobProviderError,
+    fs::remove_if_exists,
+    storage::{OpenMode, Storage, StorageFile, read_all},
+};
+
+/// Streams a blob into a staging file next to the chunks. Nothing is visible to
+/// readers until `commit`, and staging files left by a crash are removed on open.
+#[derive(uniffi::Object)]
+pub struct BlobWriter {
+    pub(crate) provider: Arc<BlobProvider>,
+    pub(crate) key: BlobKey,
+    pub(crate) expected_len: Option<u64>,
+    pub(crate) staging: Mutex<Option<StagingFile>>,
+}
+
+pub(crate) struct StagingFile {
+    pub(crate) path: PathBuf,
+    pub(crate) file: Box<dyn StorageFile>,
+    pub(crate) written: u64,
+    pub(crate) hasher: blake3::Hasher,
+}
+
+impl StagingFile {
+    pub(crate) fn create(storage: &dyn Storage, path: PathBuf) -> Result<Self, BlobProviderError> {
+        let file = storage.open(&path, OpenMode::CreateNew)?;
+
+        Ok(Self {
+            path,
+            file,
+            written: 0,
+            hasher: blake3::Hasher::new(),
+        })
+    }
+}
+
+#[uniffi::export]
+impl BlobWriter {
+    pub fn write(&self, chunk: Vec<u8>) -> Result<(), BlobProviderError> {
+        let mut staging = self.staging.lock()?;
+        let staging = staging.as_mut().ok_or(BlobProviderError::WriterClosed)?;
+
+        let written = staging.written + chunk.len() as u64;
+        if written > MAX_BLOB_SIZE as u64 {
+            return Err(BlobProviderError::BlobTooLarge(written));
+        }
+
+        if let Some(expected_len) = self.expected_len
+            && written > expected_len
+        {
+            return Err(BlobProviderError::UnexpectedBlobLength(
+                expected_len,
+                written,
+            ));
+        }
+
+        staging.file.append(&chunk)?;
+        staging.hasher.update(&chunk);
+        staging.written = written;
+
+        Ok(())
+    }
+
+    /// Publishes the written bytes under the writer's key, replacing the blob of its
+    /// latest generation
+    pub fn commit(&self) -> Result<(), BlobProviderError> {
+        let staging = self
+            .staging
+            .lock()?
+            .take()
+            .ok_or(BlobProviderError::WriterClosed)?;
+
+        let result = self.commit_staging_file(&staging);
+        remove_if_exists(self.provider.storage.as_ref(), &staging.path)?;
+
+        result
+    }
+
+    /// Discards everything written so far
+    pub fn abort(&self) -> Result<(), BlobProviderError> {
+        if let Some(staging) = self.staging.lock()?.take() {
+            remove_if_exists(self.provider.storage.as_ref(), &staging.path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BlobWriter {
+    fn commit_staging_file(&self, staging: &StagingFile) -> Result<(), BlobProviderError> {
+        if let Some(expected_len) = self.expected_len
+            && staging.written != expected_len
+        {
+            return Err(BlobProviderError::UnexpectedBlobLength(
+                expected_len,
+                staging.written,
+            ));
+        }
+
+        let mut index = self.provider.index.lock()?;
+        let generation = index.snapshot().put_generation(&self.key);
+
+        if self.provider.stores_inline(staging.written) {
+            let data = read_all(staging.file.as_ref())?;
+            return self.provider.commit_inline_blob(
+                &mut index,
+                self.key,
+                generation,
+                BlobMetadata::default(),
+                &data,
+            );
+        }
+
+        let hash = *staging.hasher.finalize().as_bytes();
+
+        self.provider.commit_blob(
+            &mut index,
+            self.key,
+            generation,
+            BlobMetadata::default(),
+            staging.written,
+            Some(hash),
+            |active| active.append_from_file(staging.file.as_ref(), staging.written),
+        )
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        let _ = self.abort();
+    }
+}
@@ -0,0 +1,255 @@
+use std::f32::consts::PI;
+
+use crate::{
+    err_type::BlobProviderError,
+    yuv::{PixelLayout, YuvThumbnail},
+};
+
+/// Horizontal and vertical components of the placeholders stored for thumbnails,
+/// which makes for 28 character hashes
+pub const PLACEHOLDER_COMPONENTS: (u32, u32) = (4, 3);
+
+const BASE83: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn invalid(reason: &str) -> BlobProviderError {
+    BlobProviderError::InvalidThumbnail(reason.to_owned())
+}
+
+fn encode83(value: u32, digits: u32, hash: &mut String) {
+    for digit in (0..digits).rev() {
+        hash.push(BASE83[(value / 83u32.pow(digit) % 83) as usize] as char);
+    }
+}
+
+fn decode83(digits: &[u8]) -> Result<u32, BlobProviderError> {
+    digits.iter().try_fold(0, |value, digit| {
+        let digit = BASE83
+            .iter()
+            .position(|candidate| candidate == digit)
+            .ok_or_else(|| invalid("invalid BlurHash character"))?;
+        Ok(value * 83 + digit as u32)
+    })
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    match value <= 0.04045 {
+        true => value / 12.92,
+        false => ((value + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let srgb = match value <= 0.003_130_8 {
+        true => value * 12.92,
+        false => 1.055 * value.powf(1.0 / 2.4) - 0.055,
+    };
+    (srgb * 255.0 + 0.5) as u8
+}
+
+fn sign_pow(value: f32, exponent: f32) -> f32 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+/// `cos(PI * component * position / len)` of every position, for every component
+fn cosines(components: u32, len: u32) -> Vec<Vec<f32>> {
+    (0..components)
+        .map(|component| {
+            (0..len)
+                .map(|position| (PI * component as f32 * position as f32 / len as f32).cos())
+                .collect()
+        })
+        .collect()
+}
+
+/// Computes the BlurHash of `height` rows of `row_bytes` bytes of four channel pixels,
+/// with `components` horizontal and vertical components of 1 to 9 each
+pub fn encode_blurhash(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    row_bytes: u32,
+    layout: PixelLayout,
+    components: (u32, u32),
+) -> Result<String, BlobProviderError> {
+    let (components_x, components_y) = components;
+    if !(1..=9).contains(&components_x) || !(1..=9).contains(&components_y) {
+        return Err(invalid("BlurHash components must be between 1 and 9"));
+    }
+
+    let (w, h, row_bytes) = (width as usize, height as usize, row_bytes as usize);
+    if w == 0 || h == 0 {
+        return Err(invalid("empty thumbnail"));
+    }
+    if row_bytes < w * 4 || pixels.len() < row_bytes * (h - 1) + w * 4 {
+        return Err(invalid("pixels don't match the dimensions"));
+    }
+
+    let [ri, gi, bi] = layout.rgb_offsets();
+    let linear = pixels
+        .chunks(row_bytes)
+        .take(h)
+        .flat_map(|row| row[..w * 4].chunks_exact(4))
+        .map(|pixel| {
+            [
+                srgb_to_linear(pixel[ri]),
+                srgb_to_linear(pixel[gi]),
+                srgb_to_linear(pixel[bi]),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let (cos_x, cos_y) = (cosines(components_x, width), cosines(components_y, height));
+    let mut factors = Vec::with_capacity((components_x * components_y) as usize);
+    for (j, cos_y) in cos_y.iter().enumerate() {
+        for (i, cos_x) in cos_x.iter().enumerate() {
+            let mut factor = [0.0f32; 3];
+            for (row, cos_y) in linear.chunks_exact(w).zip(cos_y) {
+                for (pixel, cos_x) in row.iter().zip(cos_x) {
+                    let basis = cos_x * cos_y;
+                    for (factor, channel) in factor.iter_mut().zip(pixel) {
+                        *factor += basis * channel;
+                    }
+                }
+            }
+
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            factors.push(factor.map(|factor| factor * normalisation / (w * h) as f32));
+        }
+    }
+
+    let mut hash = String::with_capacity(4 + 2 * factors.len());
+    encode83((components_x - 1) + (components_y - 1) * 9, 1, &mut hash);
+
+    let (dc, ac) = factors
+        .split_first()
+        .ok_or_else(|| invalid("no components"))?;
+    let max_value = match ac
+        .iter()
+        .flatten()
+        .map(|value| value.abs())
+        .reduce(f32::max)
+    {
+        Some(actual_max) => {
+            let quantised = (actual_max * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+            encode83(quantised, 1, &mut hash);
+            (quantised + 1) as f32 / 166.0
+        }
+        None => {
+            encode83(0, 1, &mut hash);
+            1.0
+        }
+    };
+
+    let [r, g, b] = dc.map(linear_to_srgb);
+    encode83(
+        ((r as u32) << 16) | ((g as u32) << 8) | b as u32,
+        4,
+        &mut hash,
+    );
+
+    for factor in ac {
+        let [r, g, b] = factor.map(|value| {
+            (sign_pow(value / max_value, 0.5) * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        encode83(r * 19 * 19 + g * 19 + b, 2, &mut hash);
+    }
+
+    Ok(hash)
+}
+
+/// Renders `hash` as `width` by `height` opaque RGBA pixels, `width * 4` bytes per row
+pub fn decode_blurhash(hash: &str, width: u32, height: u32) -> Result<Vec<u8>, BlobProviderError> {
+    let hash = hash.as_bytes();
+    let size_flag = decode83(hash.get(..1).ok_or_else(|| invalid("empty BlurHash"))?)?;
+    let (components_x, components_y) = (size_flag % 9 + 1, size_flag / 9 + 1);
+    if hash.len() != 4 + 2 * (components_x * components_y) as usize {
+        return Err(invalid("BlurHash length doesn't match its components"));
+    }
+
+    let max_value = (decode83(&hash[1..2])? + 1) as f32 / 166.0;
+    let dc = decode83(&hash[2..6])?;
+    let mut factors = vec![[dc >> 16, (dc >> 8) & 255, dc & 255].map(|c| srgb_to_linear(c as u8))];
+    for digits in hash[6..].chunks_exact(2) {
+        let value = decode83(digits)?;
+        factors.push(
+            [value / (19 * 19), value / 19 % 19, value % 19]
+                .map(|quantised| sign_pow((quantised as f32 - 9.0) / 9.0, 2.0) * max_value),
+        );
+    }
+
+    let (cos_x, cos_y) = (cosines(components_x, width), cosines(components_y, height));
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            let mut pixel = [0.0f32; 3];
+            for (j, cos_y) in cos_y.iter().enumerate() {
+                for (i, cos_x) in cos_x.iter().enumerate() {
+                    let basis = cos_x[x] * cos_y[y];
+                    let factor = factors[j * components_x as usize + i];
+                    for (pixel, factor) in pixel.iter_mut().zip(factor) {
+                        *pixel += basis * factor;
+                    }
+                }
+            }
+
+            pixels.extend(pixel.map(linear_to_srgb));
+            pixels.push(255);
+        }
+    }
+
+    Ok(pixels)
+}
+
+/// BlurHash of a thumbnail with `PLACEHOLDER_COMPONENTS`
+pub fn thumbnail_placeholder(thumbnail: &YuvThumbnail) -> Result<String, BlobProviderError> {
+    let pixels = thumbnail.to_pixels(PixelLayout::Rgba)?;
+
+    encode_blurhash(
+        &pixels,
+        thumbnail.width,
+        thumbnail.height,
+        thumbnail.width * 4,
+        PixelLayout::Rgba,
+        PLACEHOLDER_COMPONENTS,
+    )
+}
+
+/// Computes the BlurHash of decoded pixels, see `encode_blurhash`
+#[uniffi::export]
+pub fn blurhash_from_pixels(
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+    row_bytes: u32,
+    layout: PixelLayout,
+) -> Result<String, BlobProviderError> {
+    encode_blurhash(
+        &pixels,
+        width,
+        height,
+        row_bytes,
+        layout,
+        PLACEHOLDER_COMPONENTS,
+    )
+}
+
+/// Computes the BlurHash of a YUV thumbnail, the placeholder `put_thumbnail` stores
+#[uniffi::export]
+pub fn blurhash_from_yuv_thumbnail(thumbnail: YuvThumbnail) -> Result<String, BlobProviderError> {
+    thumbnail_placeholder(&thumbnail)
+}
+
+/// Renders a BlurHash, see `decode_blurhash`
+#[uniffi::export]
+pub fn blurhash_to_pixels(
+    hash: String,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>, BlobProviderError> {
+    decode_blurhash(&hash, width, height)
+}
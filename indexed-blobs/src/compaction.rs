@@ -0,0 +1,172 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    blob_provider::BlobProvider,
+    consts::{BLOB_EXTENSION, IDX_EXTENSION},
+    data_structures::{
+        blob_index::BlobIndex,
+        chunk_idx::{BlobLocation, IdxEntry},
+        key_filter::KeyFilter,
+        mmap_midx::MIDX_FLAG_RETIRED,
+    },
+    err_type::BlobProviderError,
+    fs::{key_filter_file_path, remove_if_exists},
+    stats::BlobProviderStats,
+};
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Rewrites every live blob into fresh chunks and deletes the old ones, dropping
+    /// overwritten, deleted and pruned bytes along with their tombstones
+    pub fn compact(&self) -> Result<BlobProviderStats, BlobProviderError> {
+        let mut index = self.index.lock()?;
+
+        self.compact_index(&mut index)?;
+
+        BlobProviderStats::from_index(self, &index)
+    }
+}
+
+impl BlobProvider {
+    /// New chunks are committed before any old chunk is retired, and old chunks are
+    /// retired oldest first. A crash at any point leaves either duplicated blobs that
+    /// the next compaction cleans up, or tombstones that still shadow everything older.
+    pub(crate) fn compact_index(&self, index: &mut BlobIndex) -> Result<(), BlobProviderError> {
+        self.seal_active_chunk(index)?;
+
+        let old_chunks = index.sealed.keys().copied().collect::<Vec<_>>();
+        // Commits leave reference counts alone while the content index is taken out
+        let mut content = index.content.take();
+        let relocated = match self.move_live_blobs(index, &old_chunks) {
+            Ok(relocated) => relocated,
+            Err(err) => {
+                // Blobs moved before the failure are counted where they are now
+                if let Some(mut content) = content {
+                    let _ = content.recount(&index.snapshot().live_entries());
+                    index.content = Some(content);
+                }
+                return Err(err);
+            }
+        };
+
+        let live_entries = index.snapshot().live_entries();
+        let relocated_content = match content.as_mut() {
+            Some(content) => content.relocate(&relocated, &live_entries),
+            None => Ok(()),
+        };
+        index.content = content;
+        relocated_content?;
+
+        if let Some(quota) = &self.quota {
+            quota.relocate(&live_entries)?;
+        }
+
+        let live_keys = live_entries.iter().map(|entry| entry.key).collect();
+        index.retain_live_metadata(&live_keys)?;
+
+        // Drops deleted keys and resizes the filter for the keys that are left
+        index.filter = Arc::new(KeyFilter::build(
+            self.storage.clone(),
+            &key_filter_file_path(&self.root_blob_dir, &self.blob_file_prefix),
+            &live_keys.into_iter().collect::<Vec<_>>(),
+        )?);
+        index.publish();
+
+        Ok(())
+    }
+
+    /// Rewrites every live blob into fresh chunks and retires `old_chunks`, returning
+    /// where the bytes of every moved location went
+    fn move_live_blobs(
+        &self,
+        index: &mut BlobIndex,
+        old_chunks: &[usize],
+    ) -> Result<HashMap<BlobLocation, BlobLocation>, BlobProviderError> {
+        // Stays valid while old chunks are rewritten, they are only retired below
+        let snapshot = index.snapshot();
+        let mut live_entries = snapshot.live_entries();
+        if self.options.compact_by_secondary {
+            let secondaries = index.secondary.all()?;
+            live_entries.sort_unstable_by_key(|entry| {
+                let secondary = secondaries.get(&entry.key);
+                (
+                    secondary.is_none(),
+                    secondary.copied(),
+                    entry.key,
+                    entry.generation,
+                )
+            });
+        } else {
+            live_entries.sort_unstable_by_key(|entry| (entry.chunk, entry.offset));
+        }
+
+        // Keys sharing deduplicated bytes keep sharing them after the move
+        let mut relocated: HashMap<BlobLocation, BlobLocation> = HashMap::new();
+        let mut pending = Vec::new();
+
+        for entry in &live_entries {
+            let location = match relocated.get(&entry.location()) {
+                Some(location) => *location,
+                // Lands in the active chunk, which `pending` is committed to before it
+                // can be sealed
+                None if entry.is_inline() => {
+                    let data = snapshot.inline_bytes(entry)?.to_vec();
+                    self.ensure_active_chunk(index, 0)?.append_inline(&data)?
+                }
+                None => {
+                    let data = self.read_entry_bytes(&snapshot, entry, 0, entry.len as u64)?;
+
+                    let has_room = index
+                        .active
+                        .as_ref()
+                        .is_some_and(|active| active.has_room(data.len() as u64));
+                    if !has_room && !pending.is_empty() {
+                        index.commit(&pending)?;
+                        pending.clear();
+                    }
+
+                    let location = self
+                        .ensure_active_chunk(index, data.len() as u64)?
+                        .append_data(&data)?;
+                    relocated.insert(entry.location(), location);
+                    location
+                }
+            };
+
+            pending.push(IdxEntry::new(entry.key, entry.generation, location));
+        }
+
+        if !pending.is_empty() {
+            index.commit(&pending)?;
+        }
+        self.seal_active_chunk(index)?;
+
+        for &chunk in old_chunks {
+            let flags = index.midx[chunk].flags;
+            index.midx[chunk].flags |= MIDX_FLAG_RETIRED;
+            if let Err(err) = index.midx.flush() {
+                index.midx[chunk].flags = flags;
+                return Err(err);
+            }
+
+            // Readers still holding an older snapshot retry once its files are gone
+            index.sealed.remove(&chunk);
+            index.publish();
+            self.dat_fd_pool.remove_fd((self.namespace, chunk))?;
+            remove_if_exists(
+                self.storage.as_ref(),
+                &self.chunk_file_path(chunk, IDX_EXTENSION),
+            )?;
+            remove_if_exists(
+                self.storage.as_ref(),
+                &self.chunk_file_path(chunk, BLOB_EXTENSION),
+            )?;
+        }
+
+        index
+            .digests
+            .retain_sealed(|chunk| index.sealed.contains_key(&chunk))?;
+
+        Ok(relocated)
+    }
+}
@@ -1,3 +1,16 @@
 pub const MAX_BLOB_SIZE: usize = 512 * 1024 * 1024;
+pub const MAX_INLINE_BLOB_SIZE: u64 = 4096;
 pub const BLOB_EXTENSION: &str = "dat";
-pub const IDX_EXTENSION: &str = "idx";
\ No newline at end of file
+pub const IDX_EXTENSION: &str = "idx";
+pub const MIDX_EXTENSION: &str = "midx";
+pub const CONTENT_IDX_EXTENSION: &str = "cidx";
+pub const PINS_EXTENSION: &str = "pins";
+pub const EVICTION_LOG_EXTENSION: &str = "evicted";
+pub const GENERATION_FLOOR_EXTENSION: &str = "gen";
+pub const KEY_FILTER_EXTENSION: &str = "bloom";
+pub const SEQUENCE_EXTENSION: &str = "seq";
+pub const SECONDARY_IDX_EXTENSION: &str = "sidx";
+pub const PLACEHOLDER_IDX_EXTENSION: &str = "plh";
+pub const PERCEPTUAL_HASH_EXTENSION: &str = "phash";
+pub const CHUNK_DIGEST_EXTENSION: &str = "sum";
+pub const TMP_EXTENSION: &str = "tmp";
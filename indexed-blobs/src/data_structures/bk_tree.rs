@@ -0,0 +1,81 @@
+/// Metric tree over 64-bit hashes under the Hamming distance, finding every hash
+/// within a distance of a query without comparing it to all of them.
+///
+/// Every child sits at its distance to the parent, so by the triangle inequality
+/// a search within `max` of the query only descends into children at a distance
+/// of `d - max..=d + max`, where `d` is the query's distance to the parent.
+#[derive(Default)]
+pub(crate) struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+struct BkNode {
+    hash: u64,
+    /// Distance to this node and the child at it
+    children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    /// Adds `hash`, returning its node index, or the index of the node that already
+    /// holds it
+    pub(crate) fn insert(&mut self, hash: u64) -> usize {
+        let new_node = self.nodes.len();
+        if new_node == 0 {
+            self.nodes.push(BkNode {
+                hash,
+                children: Vec::new(),
+            });
+            return new_node;
+        }
+
+        let mut node = 0;
+        loop {
+            let distance = (self.nodes[node].hash ^ hash).count_ones();
+            if distance == 0 {
+                return node;
+            }
+
+            match self.nodes[node]
+                .children
+                .iter()
+                .find(|(child_distance, _)| *child_distance == distance)
+            {
+                Some((_, child)) => node = *child,
+                None => {
+                    self.nodes[node].children.push((distance, new_node));
+                    self.nodes.push(BkNode {
+                        hash,
+                        children: Vec::new(),
+                    });
+                    return new_node;
+                }
+            }
+        }
+    }
+
+    /// Node indices of every hash at most `max` bits away from `hash`
+    pub(crate) fn find_within(&self, hash: u64, max: u32) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut pending = match self.nodes.is_empty() {
+            true => Vec::new(),
+            false => vec![0],
+        };
+
+        while let Some(node) = pending.pop() {
+            let distance = (self.nodes[node].hash ^ hash).count_ones();
+            if distance <= max {
+                found.push(node);
+            }
+
+            pending.extend(
+                self.nodes[node]
+                    .children
+                    .iter()
+                    .filter(|(child_distance, _)| child_distance.abs_diff(distance) <= max)
+                    .map(|(_, child)| *child),
+            );
+        }
+
+        found
+    }
+}
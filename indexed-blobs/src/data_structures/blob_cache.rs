@@ -0,0 +1,148 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::{
+    data_structures::chunk_idx::BlobKey, err_type::BlobProviderError, stats::BlobCacheStats,
+};
+
+/// Namespace and key of a blob, so providers of one `BlobStore` can share a cache
+pub(crate) type CacheKey = (u32, BlobKey);
+
+struct CachedBlob {
+    data: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    blobs: HashMap<CacheKey, CachedBlob>,
+    /// Least recently used first
+    recency: BTreeMap<u64, CacheKey>,
+    next_tick: u64,
+    cached_bytes: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &CacheKey) -> Option<Arc<Vec<u8>>> {
+        let tick = self.next_tick;
+        let blob = self.blobs.get_mut(key)?;
+
+        self.recency.remove(&blob.last_used);
+        self.recency.insert(tick, *key);
+        blob.last_used = tick;
+        self.next_tick += 1;
+
+        Some(blob.data.clone())
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(blob) = self.blobs.remove(key) {
+            self.recency.remove(&blob.last_used);
+            self.cached_bytes -= blob.data.len() as u64;
+        }
+    }
+
+    fn evict_until(&mut self, max_bytes: u64) {
+        while self.cached_bytes > max_bytes {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+
+            if let Some(blob) = self.blobs.remove(&key) {
+                self.cached_bytes -= blob.data.len() as u64;
+            }
+        }
+    }
+}
+
+/// In-memory LRU of whole blobs bounded by a byte budget
+pub(crate) struct BlobCache {
+    budget_bytes: u64,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlobCache {
+    pub(crate) fn new(budget_bytes: u64) -> Self {
+        Self {
+            budget_bytes,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// A cache of `budget_bytes`, or none for a budget of 0
+    pub(crate) fn for_budget(budget_bytes: u64) -> Option<Arc<Self>> {
+        (budget_bytes > 0).then(|| Arc::new(Self::new(budget_bytes)))
+    }
+
+    pub(crate) fn get(&self, key: &CacheKey) -> Result<Option<Arc<Vec<u8>>>, BlobProviderError> {
+        let cached = self.state.lock()?.touch(key);
+
+        match cached {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        Ok(cached)
+    }
+
+    /// Checks for `key` without touching its recency or the hit counters
+    pub(crate) fn contains(&self, key: &CacheKey) -> Result<bool, BlobProviderError> {
+        Ok(self.state.lock()?.blobs.contains_key(key))
+    }
+
+    pub(crate) fn insert(&self, key: CacheKey, data: Vec<u8>) -> Result<(), BlobProviderError> {
+        if data.len() as u64 > self.budget_bytes {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock()?;
+        state.remove(&key);
+
+        let tick = state.next_tick;
+        state.next_tick += 1;
+        state.cached_bytes += data.len() as u64;
+        state.recency.insert(tick, key);
+        state.blobs.insert(
+            key,
+            CachedBlob {
+                data: Arc::new(data),
+                last_used: tick,
+            },
+        );
+        state.evict_until(self.budget_bytes);
+
+        Ok(())
+    }
+
+    pub(crate) fn invalidate(&self, key: &CacheKey) -> Result<(), BlobProviderError> {
+        self.state.lock()?.remove(key);
+        Ok(())
+    }
+
+    /// Evicts least recently used blobs until at most `max_bytes` remain cached
+    pub(crate) fn trim(&self, max_bytes: u64) -> Result<(), BlobProviderError> {
+        self.state.lock()?.evict_until(max_bytes);
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> Result<BlobCacheStats, BlobProviderError> {
+        let state = self.state.lock()?;
+
+        Ok(BlobCacheStats {
+            budget_bytes: self.budget_bytes,
+            cached_bytes: state.cached_bytes,
+            cached_blobs: state.blobs.len() as u64,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        })
+    }
+}
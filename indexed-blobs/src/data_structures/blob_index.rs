@@ -0,0 +1,567 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use arc_swap::ArcSwap;
+
+use crate::{
+    data_structures::{
+        chunk_digest_idx::ChunkDigestIdx,
+        chunk_idx::{ActiveChunk, BlobKey, BlobLocation, Generation, IdxEntry, SealedIdx},
+        content_idx::ContentIdx,
+        key_filter::KeyFilter,
+        keyed_sidecar::{CommitPoint, StagedChanges},
+        mmap_midx::MIdx,
+        perceptual_hash_idx::PerceptualHashIdx,
+        placeholder_idx::PlaceholderIdx,
+        secondary_idx::SecondaryIdx,
+        segmented_map::SegmentedMap,
+    },
+    err_type::BlobProviderError,
+    group_commit::{GroupCommit, SequenceReservation},
+};
+
+/// Everything needed to resolve a key to its location inside a chunk. Owned by the
+/// writer, which publishes an `IndexSnapshot` for readers after every change.
+pub(crate) struct BlobIndex {
+    pub(crate) midx: MIdx,
+    pub(crate) sealed: BTreeMap<usize, Arc<SealedIdx>>,
+    pub(crate) active: Option<ActiveChunk>,
+    /// Only present when deduplication is enabled
+    pub(crate) content: Option<ContentIdx>,
+    /// Records of older generations were pruned and are only kept until compaction
+    pub(crate) generation_floor: Generation,
+    /// Answers lookups of keys that were never written without searching any chunk
+    pub(crate) filter: Arc<KeyFilter>,
+    pub(crate) published: Arc<ArcSwap<IndexSnapshot>>,
+    // Metadata of individual keys, synced along with every commit so it's as durable
+    // as the blobs it was written with
+    pub(crate) secondary: Arc<SecondaryIdx>,
+    pub(crate) placeholders: Arc<PlaceholderIdx>,
+    pub(crate) perceptual_hashes: Arc<PerceptualHashIdx>,
+    /// Digest of every sealed chunk, added when the chunk is sealed
+    pub(crate) digests: Arc<ChunkDigestIdx>,
+    /// Only present when writes are committed in groups
+    pub(crate) group_commit: Option<GroupCommit>,
+    /// Sequence number of the last index commit
+    pub(crate) sequence: u64,
+    /// Commits up to this one are on disk, later ones are staged
+    pub(crate) durable_sequence: u64,
+    /// Persists sequence numbers ahead of the commits that take them, only missing
+    /// while the index is being loaded
+    pub(crate) sequence_reservation: Option<SequenceReservation>,
+    /// Metadata of the last commit if its midx update failed, but may have landed
+    unsettled_metadata: Option<StagedMetadata>,
+}
+
+/// Metadata a blob is written with. Metadata computed from the key's previous blob is
+/// dropped, and its secondary key is kept unless a new one is passed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BlobMetadata {
+    pub(crate) secondary: Option<i64>,
+    pub(crate) placeholder: Option<Arc<str>>,
+}
+
+/// Changes to the key sidecars written along with an index commit
+struct StagedMetadata {
+    secondary: StagedChanges<i64>,
+    placeholders: StagedChanges<Arc<str>>,
+    perceptual_hashes: StagedChanges<u64>,
+}
+
+impl StagedMetadata {
+    /// Appends the metadata changes of committing `records` to the sidecars: keys
+    /// losing their blob lose all of it, and keys in `written` get their new metadata.
+    /// Metadata describes the latest generation, so writing an older one keeps it.
+    fn stage(
+        index: &BlobIndex,
+        records: &[IdxEntry],
+        written: &HashMap<BlobKey, BlobMetadata>,
+        commit: CommitPoint,
+    ) -> Result<Self, BlobProviderError> {
+        let mut secondary = Vec::new();
+        let mut placeholders = Vec::new();
+        let mut perceptual_hashes = Vec::new();
+        let snapshot = index.snapshot();
+        for record in records {
+            let key = record.key;
+            let placeholder = match (record.is_tombstone(), written.get(&key)) {
+                (true, _) => {
+                    secondary.push((key, None));
+                    None
+                }
+                (false, Some(_))
+                    if snapshot
+                        .find_latest(&key)
+                        .is_some_and(|latest| latest.generation > record.generation) =>
+                {
+                    continue;
+                }
+                (false, Some(metadata)) => {
+                    if let Some(new_secondary) = metadata.secondary {
+                        secondary.push((key, Some(new_secondary)));
+                    }
+                    metadata.placeholder.clone()
+                }
+                (false, None) => continue,
+            };
+
+            placeholders.push((key, placeholder));
+            perceptual_hashes.push((key, None));
+        }
+
+        let secondary = index.secondary.stage(secondary, Some(commit))?;
+        let placeholders = index
+            .placeholders
+            .stage(placeholders, Some(commit))
+            .inspect_err(|_| {
+                let _ = index.secondary.discard(&secondary);
+            })?;
+        let perceptual_hashes = index
+            .perceptual_hashes
+            .stage(perceptual_hashes, Some(commit))
+            .inspect_err(|_| {
+                let _ = index.secondary.discard(&secondary);
+                let _ = index.placeholders.discard(&placeholders);
+            })?;
+
+        Ok(Self {
+            secondary,
+            placeholders,
+            perceptual_hashes,
+        })
+    }
+
+    fn apply(self, index: &BlobIndex) -> Result<(), BlobProviderError> {
+        index.secondary.apply(self.secondary)?;
+        index.placeholders.apply(self.placeholders)?;
+        index.perceptual_hashes.apply(self.perceptual_hashes)
+    }
+
+    /// Cuts the changes off the sidecars once their commit is known to have failed.
+    /// Values appended since outside of a commit go too, they are computed again.
+    /// Should cutting fail, the changes are still dropped on open since the commit
+    /// never landed.
+    fn discard(self, index: &BlobIndex) {
+        let _ = index.secondary.discard(&self.secondary);
+        let _ = index.placeholders.discard(&self.placeholders);
+        let _ = index.perceptual_hashes.discard(&self.perceptual_hashes);
+    }
+}
+
+/// Sidecars holding metadata of individual keys
+pub(crate) struct KeySidecars {
+    pub(crate) secondary: SecondaryIdx,
+    pub(crate) placeholders: PlaceholderIdx,
+    pub(crate) perceptual_hashes: PerceptualHashIdx,
+}
+
+/// Immutable view of the index that readers resolve keys with, without taking the
+/// index lock. Sealed chunks are mapped once and shared between snapshots, and their
+/// mappings are dropped along with the last snapshot that still refers to them.
+pub(crate) struct IndexSnapshot {
+    sealed: BTreeMap<usize, Arc<SealedIdx>>,
+    active: Option<ActiveSnapshot>,
+    generation_floor: Generation,
+    filter: Arc<KeyFilter>,
+}
+
+struct ActiveSnapshot {
+    chunk: usize,
+    entries: SegmentedMap<(BlobKey, Generation), IdxEntry>,
+    inline: SegmentedMap<u32, Arc<[u8]>>,
+}
+
+impl ActiveSnapshot {
+    fn of(active: &ActiveChunk) -> Self {
+        Self {
+            chunk: active.chunk,
+            entries: active.entries.clone(),
+            inline: active.inline.clone(),
+        }
+    }
+}
+
+impl IndexSnapshot {
+    /// Finds the newest record of `key` at `generation`, tombstones included
+    pub(crate) fn find(&self, key: &BlobKey, generation: Generation) -> Option<IdxEntry> {
+        if !self.filter.may_contain(key) {
+            return None;
+        }
+
+        if let Some(active) = &self.active
+            && let Some(entry) = active.entries.get(&(*key, generation))
+        {
+            return Some(*entry);
+        }
+
+        self.sealed
+            .values()
+            .rev()
+            .find_map(|sealed| sealed.find(key, generation).copied())
+    }
+
+    /// Finds the location of `key` at `generation` if it currently holds a blob
+    pub(crate) fn find_live(&self, key: &BlobKey, generation: Generation) -> Option<IdxEntry> {
+        if generation < self.generation_floor {
+            return None;
+        }
+
+        self.find(key, generation)
+            .filter(|entry| !entry.is_tombstone())
+    }
+
+    /// The newest record of every generation of `key` that currently holds a blob,
+    /// oldest generation first
+    pub(crate) fn live_generations(&self, key: &BlobKey) -> Vec<IdxEntry> {
+        if !self.filter.may_contain(key) {
+            return Vec::new();
+        }
+
+        let mut newest = BTreeMap::new();
+
+        let active_entries = self.active.iter().flat_map(|active| {
+            active
+                .entries
+                .range((*key, self.generation_floor)..=(*key, Generation::MAX))
+                .map(|(_, entry)| entry)
+        });
+        let sealed_entries = self
+            .sealed
+            .values()
+            .rev()
+            .flat_map(|sealed| sealed.find_generations(key));
+
+        for entry in active_entries.chain(sealed_entries) {
+            newest.entry(entry.generation).or_insert(*entry);
+        }
+
+        newest
+            .into_values()
+            .filter(|entry| !entry.is_tombstone() && entry.generation >= self.generation_floor)
+            .collect()
+    }
+
+    /// Finds the location of the highest generation of `key` that holds a blob
+    pub(crate) fn find_latest(&self, key: &BlobKey) -> Option<IdxEntry> {
+        self.live_generations(key).pop()
+    }
+
+    /// Bytes of an inline blob, served from memory for the active chunk and from the
+    /// mapped `.idx` file for sealed ones
+    pub(crate) fn inline_bytes(&self, entry: &IdxEntry) -> Result<&[u8], BlobProviderError> {
+        let chunk = entry.chunk as usize;
+
+        let bytes = match &self.active {
+            Some(active) if active.chunk == chunk => active
+                .inline
+                .get(&entry.offset)
+                .map(AsRef::as_ref)
+                .filter(|bytes: &&[u8]| bytes.len() == entry.len as usize),
+            _ => self
+                .sealed
+                .get(&chunk)
+                .and_then(|sealed| sealed.inline_bytes(entry.offset, entry.len)),
+        };
+
+        bytes.ok_or_else(|| BlobProviderError::InvalidIdx {
+            chunk: chunk as u64,
+            offset: Some(entry.offset as u64),
+            reason: "inline blob is out of bounds".to_owned(),
+        })
+    }
+
+    /// Generation a plain put of `key` replaces: its latest one, or the oldest kept
+    /// generation for new keys
+    pub(crate) fn put_generation(&self, key: &BlobKey) -> Generation {
+        self.find_latest(key)
+            .map_or(self.generation_floor, |entry| entry.generation)
+    }
+
+    /// Where the bytes of every live generation of `key` are stored
+    pub(crate) fn live_locations(&self, key: &BlobKey) -> Vec<BlobLocation> {
+        self.live_generations(key)
+            .iter()
+            .map(IdxEntry::location)
+            .collect()
+    }
+
+    /// Every sealed chunk by its number
+    pub(crate) fn sealed_chunks(&self) -> &BTreeMap<usize, Arc<SealedIdx>> {
+        &self.sealed
+    }
+
+    /// Number of the active chunk and the newest record of every key and generation
+    /// it holds, tombstones included, sorted like the records of a sealed chunk
+    pub(crate) fn active_records(&self) -> Option<(usize, Vec<IdxEntry>)> {
+        self.active.as_ref().map(|active| {
+            (
+                active.chunk,
+                active.entries.merged().into_values().collect(),
+            )
+        })
+    }
+
+    /// The newest record of every key and generation that currently holds a blob
+    pub(crate) fn live_entries(&self) -> Vec<IdxEntry> {
+        let mut seen = HashSet::new();
+        let mut live_entries = Vec::new();
+
+        let active_entries = self
+            .active
+            .iter()
+            .flat_map(|active| active.entries.iter().map(|(_, entry)| entry));
+        let sealed_entries = self
+            .sealed
+            .values()
+            .rev()
+            .flat_map(|sealed| sealed.entries().iter());
+
+        for entry in active_entries.chain(sealed_entries) {
+            if seen.insert(entry.id())
+                && !entry.is_tombstone()
+                && entry.generation >= self.generation_floor
+            {
+                live_entries.push(*entry);
+            }
+        }
+
+        live_entries
+    }
+}
+
+impl BlobIndex {
+    pub(crate) fn new(
+        midx: MIdx,
+        sealed: BTreeMap<usize, Arc<SealedIdx>>,
+        active: Option<ActiveChunk>,
+        generation_floor: Generation,
+        filter: KeyFilter,
+        sidecars: KeySidecars,
+        digests: ChunkDigestIdx,
+    ) -> Self {
+        let filter = Arc::new(filter);
+        let snapshot = IndexSnapshot {
+            sealed: sealed.clone(),
+            active: active.as_ref().map(ActiveSnapshot::of),
+            generation_floor,
+            filter: filter.clone(),
+        };
+
+        Self {
+            midx,
+            sealed,
+            active,
+            content: None,
+            generation_floor,
+            filter,
+            published: Arc::new(ArcSwap::from_pointee(snapshot)),
+            secondary: Arc::new(sidecars.secondary),
+            placeholders: Arc::new(sidecars.placeholders),
+            perceptual_hashes: Arc::new(sidecars.perceptual_hashes),
+            digests: Arc::new(digests),
+            group_commit: None,
+            sequence: 0,
+            durable_sequence: 0,
+            sequence_reservation: None,
+            unsettled_metadata: None,
+        }
+    }
+
+    /// The snapshot last published, which the writer holding the index lock always
+    /// sees as current
+    pub(crate) fn snapshot(&self) -> Arc<IndexSnapshot> {
+        self.published.load_full()
+    }
+
+    /// Makes the current state visible to readers. Cheap, since it only clones the
+    /// list of sealed chunks and the segment lists of the active chunk.
+    pub(crate) fn publish(&self) {
+        self.published.store(Arc::new(IndexSnapshot {
+            sealed: self.sealed.clone(),
+            active: self.active.as_ref().map(ActiveSnapshot::of),
+            generation_floor: self.generation_floor,
+            filter: self.filter.clone(),
+        }));
+    }
+
+    /// Appends `records` to the active chunk and publishes them to readers. They are
+    /// made durable with a single midx update, right away or together with the other
+    /// staged writes once the group is due.
+    pub(crate) fn commit(&mut self, records: &[IdxEntry]) -> Result<(), BlobProviderError> {
+        self.commit_with_metadata(records, &HashMap::new())
+    }
+
+    /// Like `commit`, and also replaces the metadata of the keys in `written`, whose
+    /// new blobs are among `records`. The metadata is written along with the records
+    /// and only applied if they are committed, also after a crash.
+    pub(crate) fn commit_with_metadata(
+        &mut self,
+        records: &[IdxEntry],
+        written: &HashMap<BlobKey, BlobMetadata>,
+    ) -> Result<(), BlobProviderError> {
+        // This commit overwrites the records it was written with
+        if let Some(unsettled) = self.unsettled_metadata.take() {
+            unsettled.discard(self);
+        }
+        if let Some(reservation) = self.sequence_reservation.as_mut() {
+            reservation.reserve(self.sequence + 1)?;
+        }
+
+        let active = self.active.as_mut().ok_or_else(no_active_chunk)?;
+        let staged_from = active.num_records;
+
+        active.write_records(records)?;
+
+        let commit = CommitPoint {
+            chunk: active.chunk as u32,
+            num_records: active.num_records as u32,
+        };
+        let metadata = match StagedMetadata::stage(self, records, written, commit) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                if let Some(active) = self.active.as_mut() {
+                    active.num_records = staged_from;
+                }
+                return Err(err);
+            }
+        };
+
+        let is_due = self
+            .group_commit
+            .as_ref()
+            .is_none_or(|group_commit| group_commit.is_due(1));
+        if is_due {
+            let synced = match self.sync_active_data() {
+                Ok(()) => self.flush_commit_point().map_err(|err| (err, true)),
+                Err(err) => Err((err, false)),
+            };
+
+            if let Err((err, may_have_landed)) = synced {
+                // Overwritten by the next commit, writes staged before stay staged
+                if let Some(active) = self.active.as_mut() {
+                    active.num_records = staged_from;
+                }
+                // A failed midx write may still be on disk, and with it the commit
+                match may_have_landed {
+                    true => self.unsettled_metadata = Some(metadata),
+                    false => metadata.discard(self),
+                }
+                return Err(err);
+            }
+        }
+
+        let snapshot = self.snapshot();
+        let mut committed: BTreeMap<_, IdxEntry> = BTreeMap::new();
+        for record in records {
+            self.filter.insert(&record.key);
+
+            if let Some(content) = self.content.as_mut() {
+                // Records earlier in the batch replace what the snapshot holds
+                let previous = match committed.get(&record.id()) {
+                    Some(previous) => Some(*previous).filter(|entry| !entry.is_tombstone()),
+                    None => snapshot.find_live(&record.key, record.generation),
+                };
+
+                // Acquire first so rewriting a key with its own bytes keeps the hash
+                if !record.is_tombstone() {
+                    content.acquire(record.location());
+                }
+                if let Some(previous) = previous {
+                    content.release(previous.location());
+                }
+            }
+
+            committed.insert(record.id(), *record);
+        }
+
+        self.active
+            .as_mut()
+            .ok_or_else(no_active_chunk)?
+            .entries
+            .extend(committed);
+        self.publish();
+
+        self.sequence += 1;
+        if is_due {
+            self.mark_durable();
+        } else if let Some(group_commit) = self.group_commit.as_mut() {
+            group_commit.stage();
+        }
+
+        metadata.apply(self)
+    }
+
+    /// Drops the metadata of keys that no longer hold a blob
+    pub(crate) fn retain_live_metadata(
+        &self,
+        live_keys: &HashSet<BlobKey>,
+    ) -> Result<(), BlobProviderError> {
+        self.secondary.retain_live(live_keys)?;
+        self.placeholders.retain_live(live_keys)?;
+        self.perceptual_hashes.retain_live(live_keys)
+    }
+
+    /// Makes every staged write durable
+    pub(crate) fn commit_staged(&mut self) -> Result<(), BlobProviderError> {
+        if self.durable_sequence == self.sequence {
+            return Ok(());
+        }
+
+        self.sync_active()?;
+        self.mark_durable();
+
+        Ok(())
+    }
+
+    /// Called once every record of the active chunk is on disk, e.g. after sealing it
+    pub(crate) fn mark_durable(&mut self) {
+        self.durable_sequence = self.sequence;
+        if let Some(group_commit) = self.group_commit.as_mut() {
+            group_commit.reset();
+        }
+    }
+
+    /// Syncs the active chunk and commits all its records in the midx
+    fn sync_active(&mut self) -> Result<(), BlobProviderError> {
+        self.sync_active_data()?;
+        self.flush_commit_point()
+    }
+
+    /// Makes the records of the active chunk and the key sidecars durable
+    fn sync_active_data(&self) -> Result<(), BlobProviderError> {
+        self.active.as_ref().ok_or_else(no_active_chunk)?.sync()?;
+        self.secondary.sync()?;
+        self.placeholders.sync()?;
+        self.perceptual_hashes.sync()
+    }
+
+    /// Commits every record of the active chunk with a midx update
+    fn flush_commit_point(&mut self) -> Result<(), BlobProviderError> {
+        let active = self.active.as_ref().ok_or_else(no_active_chunk)?;
+        let committed = self.midx[active.chunk].num_entries;
+        self.midx[active.chunk].num_entries = active.num_records as u32;
+        if let Err(err) = self.midx.flush() {
+            self.midx[active.chunk].num_entries = committed;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
+
+/// Error of a write that found no active chunk, which writes always create first
+pub(crate) fn no_active_chunk() -> BlobProviderError {
+    BlobProviderError::InvalidMIdx {
+        chunk: None,
+        reason: "no active chunk".to_owned(),
+    }
+}
+
+impl Drop for BlobIndex {
+    fn drop(&mut self) {
+        // Only a crash should lose staged writes
+        let _ = self.commit_staged();
+    }
+}
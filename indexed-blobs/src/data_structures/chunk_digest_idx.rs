@@ -0,0 +1,95 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use crate::{
+    data_structures::{chunk_idx::BlobKey, keyed_sidecar::SidecarFile},
+    err_type::BlobProviderError,
+    storage::Storage,
+};
+
+/// Summary of the records of a chunk, see `BlobProvider::digest_records`
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromBytes, IntoBytes, KnownLayout, Immutable)]
+pub(crate) struct ChunkDigestEntry {
+    pub(crate) chunk: u32,
+    pub(crate) num_records: u32,
+    /// Smallest and largest key of the chunk's records, zero for empty chunks
+    pub(crate) first_key: BlobKey,
+    pub(crate) last_key: BlobKey,
+    pub(crate) digest: [u8; 32],
+}
+
+const _: () = assert!(size_of::<ChunkDigestEntry>() == 72);
+
+/// Reads the records of a `.sum` file, skipping a torn tail
+pub(crate) fn parse_digests(buffer: &[u8]) -> Vec<ChunkDigestEntry> {
+    buffer
+        .chunks_exact(size_of::<ChunkDigestEntry>())
+        .filter_map(|record| ChunkDigestEntry::read_from_bytes(record).ok())
+        .collect()
+}
+
+/// Digest of every sealed chunk, computed once when the chunk is sealed, since its
+/// records never change afterwards.
+///
+/// Digests are appended and synced right after the seal they belong to, so a crash
+/// can at worst lose one, which is computed again when digests are next listed.
+/// Digests of retired chunks are dropped on open and by compaction, and readers only
+/// ever look up chunks of their snapshot.
+pub(crate) struct ChunkDigestIdx {
+    file: SidecarFile,
+    by_chunk: RwLock<BTreeMap<usize, ChunkDigestEntry>>,
+}
+
+impl ChunkDigestIdx {
+    /// Loads every digest in the sidecar, including those of chunks retired since,
+    /// which the `retain_sealed` that follows on open drops
+    pub(crate) fn open(storage: Arc<dyn Storage>, path: &Path) -> Result<Self, BlobProviderError> {
+        let (file, buffer) = SidecarFile::open(storage, path)?;
+        let by_chunk = parse_digests(&buffer)
+            .into_iter()
+            .map(|record| (record.chunk as usize, record))
+            .collect();
+
+        Ok(Self {
+            file,
+            by_chunk: RwLock::new(by_chunk),
+        })
+    }
+
+    pub(crate) fn get(&self, chunk: usize) -> Result<Option<ChunkDigestEntry>, BlobProviderError> {
+        Ok(self.by_chunk.read()?.get(&chunk).copied())
+    }
+
+    pub(crate) fn insert(&self, digest: ChunkDigestEntry) -> Result<(), BlobProviderError> {
+        // Same lock order as `retain_sealed`, so a rewrite never misses the digest
+        let mut by_chunk = self.by_chunk.write()?;
+        self.file.append(digest.as_bytes())?;
+        self.file.sync()?;
+        by_chunk.insert(digest.chunk as usize, digest);
+
+        Ok(())
+    }
+
+    /// Drops the digests of chunks that aren't sealed anymore and replaces the sidecar
+    /// with the ones that are left
+    pub(crate) fn retain_sealed(
+        &self,
+        is_sealed: impl Fn(usize) -> bool,
+    ) -> Result<(), BlobProviderError> {
+        let mut by_chunk = self.by_chunk.write()?;
+        by_chunk.retain(|chunk, _| is_sealed(*chunk));
+
+        let mut buffer = Vec::new();
+        for digest in by_chunk.values() {
+            buffer.extend_from_slice(digest.as_bytes());
+        }
+
+        self.file.rewrite(&buffer)
+    }
+}
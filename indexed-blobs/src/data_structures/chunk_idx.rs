@@ -0,0 +1,490 @@
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
+
+use crate::{
+    consts::MAX_BLOB_SIZE,
+    data_structures::segmented_map::SegmentedMap,
+    err_type::BlobProviderError,
+    key::format_key,
+    storage::{MappedBytes, OpenMode, Storage, StorageFile, write_atomically},
+};
+
+/// Size of the buffer used to copy staged blobs into a chunk
+const COPY_BUFFER_SIZE: usize = 1 << 20;
+
+pub type BlobKey = [u8; 16];
+
+/// Several generations of a key are stored side by side, reads default to the highest
+pub type Generation = u16;
+
+/// Record shadows every older record of the same key and generation
+pub const IDX_FLAG_TOMBSTONE: u16 = 1 << 0;
+
+/// Blob bytes live in the chunk's `.idx` file instead of its `.dat` file, at `offset`
+pub const IDX_FLAG_INLINE: u16 = 1 << 1;
+
+/// Not a blob record but the header of `len` inline bytes that fill the next records
+/// of the `.idx`
+pub const IDX_FLAG_INLINE_PAYLOAD: u16 = 1 << 2;
+
+const IDX_ENTRY_SIZE: usize = std::mem::size_of::<IdxEntry>();
+
+/// Where a blob's bytes live. Several keys may share one location once their
+/// content is deduplicated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BlobLocation {
+    pub(crate) chunk: u32,
+    pub(crate) offset: u32,
+    pub(crate) len: u32,
+    /// `offset` points into the chunk's `.idx` file
+    pub(crate) inline: bool,
+}
+
+/// A single record of a chunk's `.idx` file, pointing into a chunk's `.dat` file.
+/// Active chunks append these in write order, sealed chunks store them sorted by key
+/// and generation. Offsets fit in 32 bits because a chunk never grows past
+/// `MAX_BLOB_SIZE`.
+///
+/// `generation` took over the upper half of what used to be a 32-bit `flags` field,
+/// so records written before generations existed read as generation 0.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromBytes, IntoBytes, KnownLayout, Immutable)]
+pub struct IdxEntry {
+    pub(crate) key: BlobKey,
+    pub(crate) chunk: u32,
+    pub(crate) offset: u32,
+    pub(crate) len: u32,
+    pub(crate) flags: u16,
+    pub(crate) generation: Generation,
+}
+
+const _: () = assert!(size_of::<IdxEntry>() == 32);
+const _: () = assert!(MAX_BLOB_SIZE <= u32::MAX as usize);
+
+impl IdxEntry {
+    pub(crate) fn new(key: BlobKey, generation: Generation, location: BlobLocation) -> Self {
+        Self {
+            key,
+            chunk: location.chunk,
+            offset: location.offset,
+            len: location.len,
+            flags: if location.inline { IDX_FLAG_INLINE } else { 0 },
+            generation,
+        }
+    }
+
+    pub(crate) fn tombstone(key: BlobKey, generation: Generation) -> Self {
+        Self {
+            key,
+            chunk: 0,
+            offset: 0,
+            len: 0,
+            flags: IDX_FLAG_TOMBSTONE,
+            generation,
+        }
+    }
+
+    /// What the record is looked up by, and the order of sealed `.idx` files
+    pub(crate) fn id(&self) -> (BlobKey, Generation) {
+        (self.key, self.generation)
+    }
+
+    pub(crate) fn location(&self) -> BlobLocation {
+        BlobLocation {
+            chunk: self.chunk,
+            offset: self.offset,
+            len: self.len,
+            inline: self.is_inline(),
+        }
+    }
+
+    pub(crate) fn is_tombstone(&self) -> bool {
+        self.flags & IDX_FLAG_TOMBSTONE != 0
+    }
+
+    pub(crate) fn is_inline(&self) -> bool {
+        self.flags & IDX_FLAG_INLINE != 0
+    }
+}
+
+/// Reads the first `num_entries` records of an `.idx` file
+pub(crate) fn read_idx_entries(
+    file: &dyn StorageFile,
+    num_entries: usize,
+) -> Result<Vec<IdxEntry>, BlobProviderError> {
+    let mut entries = vec![IdxEntry::new_zeroed(); num_entries];
+    file.read_at(entries.as_mut_bytes(), 0)?;
+
+    Ok(entries)
+}
+
+/// Number of records `len` inline bytes take up
+fn payload_records(len: u32) -> usize {
+    (len as usize).div_ceil(IDX_ENTRY_SIZE)
+}
+
+/// Inline bytes encoded as `.idx` records: a header followed by the zero padded bytes
+fn inline_payload(chunk: usize, data: &[u8]) -> Vec<IdxEntry> {
+    let header = IdxEntry {
+        key: [0; 16],
+        chunk: chunk as u32,
+        offset: 0,
+        len: data.len() as u32,
+        flags: IDX_FLAG_INLINE_PAYLOAD,
+        generation: 0,
+    };
+
+    let mut records = vec![header];
+    records.resize(1 + payload_records(header.len), IdxEntry::new_zeroed());
+    records[1..].as_mut_bytes()[..data.len()].copy_from_slice(data);
+
+    records
+}
+
+/// Atomically writes `entries` sorted by key to `path`, followed by the bytes of
+/// inline blobs, so a crash never leaves a half written index behind. `inline` maps
+/// the offsets inline entries currently point at to their bytes, and the entries are
+/// updated to point into the new file.
+///
+/// The bytes keep the layout of an active chunk. If the seal never makes it into the
+/// midx, the file still reads as the same active `.idx` as long as the midx committed
+/// every record in it, since an active chunk is opened with the committed number of
+/// records.
+pub(crate) fn write_sorted_idx(
+    storage: &dyn Storage,
+    path: &Path,
+    chunk: usize,
+    entries: &mut [IdxEntry],
+    inline: &SegmentedMap<u32, Arc<[u8]>>,
+) -> Result<(), BlobProviderError> {
+    entries.sort_unstable_by_key(|entry| entry.id());
+
+    let num_entries = entries.len();
+    let mut payloads = Vec::new();
+    for entry in entries.iter_mut().filter(|entry| entry.is_inline()) {
+        let payload = inline
+            .get(&entry.offset)
+            .ok_or_else(|| BlobProviderError::InvalidIdx {
+                chunk: chunk as u64,
+                offset: None,
+                reason: format!("missing inline payload of key {}", format_key(&entry.key)),
+            })?;
+
+        // Right behind the payload's header
+        entry.offset = ((num_entries + payloads.len() + 1) * IDX_ENTRY_SIZE) as u32;
+        payloads.extend(inline_payload(chunk, payload));
+    }
+
+    let mut buffer = entries.as_bytes().to_vec();
+    buffer.extend_from_slice(payloads.as_bytes());
+    write_atomically(storage, path, &buffer)?;
+
+    Ok(())
+}
+
+/// Read-only view over the key-sorted `.idx` file of a sealed chunk
+pub(crate) struct SealedIdx {
+    mmap: MappedBytes,
+    num_entries: usize,
+}
+
+impl SealedIdx {
+    pub(crate) fn open(
+        storage: &dyn Storage,
+        path: &Path,
+        num_entries: usize,
+        chunk: usize,
+    ) -> Result<Self, BlobProviderError> {
+        let mmap = storage.open(path, OpenMode::Read)?.map()?;
+
+        // Checks both the length and the alignment of the mapping
+        if <[IdxEntry]>::ref_from_prefix_with_elems(&mmap, num_entries).is_err() {
+            return Err(BlobProviderError::InvalidIdx {
+                chunk: chunk as u64,
+                offset: None,
+                reason: format!(
+                    "{} bytes can't hold the {num_entries} committed records",
+                    mmap.len()
+                ),
+            });
+        }
+
+        Ok(Self { mmap, num_entries })
+    }
+
+    pub(crate) fn entries(&self) -> &[IdxEntry] {
+        // Validated on open
+        <[IdxEntry]>::ref_from_prefix_with_elems(&self.mmap, self.num_entries)
+            .map_or(&[], |(entries, _)| entries)
+    }
+
+    pub(crate) fn find(&self, key: &BlobKey, generation: Generation) -> Option<&IdxEntry> {
+        let entries = self.entries();
+
+        entries
+            .binary_search_by(|entry| entry.id().cmp(&(*key, generation)))
+            .ok()
+            .map(|position| &entries[position])
+    }
+
+    /// Bytes of an inline blob, which follow the sorted entries
+    pub(crate) fn inline_bytes(&self, offset: u32, len: u32) -> Option<&[u8]> {
+        let start = offset as usize;
+        if start < self.num_entries * IDX_ENTRY_SIZE {
+            return None;
+        }
+
+        self.mmap.get(start..start.checked_add(len as usize)?)
+    }
+
+    /// Records of every generation of `key`, oldest generation first
+    pub(crate) fn find_generations(&self, key: &BlobKey) -> &[IdxEntry] {
+        let entries = self.entries();
+        let start = entries.partition_point(|entry| entry.key < *key);
+        let end = start + entries[start..].partition_point(|entry| entry.key == *key);
+
+        &entries[start..end]
+    }
+}
+
+/// Chunk currently receiving appends. Its `.idx` is in write order, so the latest
+/// record per key is kept in memory, along with the bytes of inline blobs. Both maps
+/// are shared with the index snapshots readers hold.
+pub(crate) struct ActiveChunk {
+    pub(crate) chunk: usize,
+    pub(crate) idx_file: Box<dyn StorageFile>,
+    pub(crate) dat_file: Box<dyn StorageFile>,
+    pub(crate) dat_len: u64,
+    pub(crate) num_records: usize,
+    pub(crate) entries: SegmentedMap<(BlobKey, Generation), IdxEntry>,
+    /// Inline blob bytes by their offset in the `.idx` file
+    pub(crate) inline: SegmentedMap<u32, Arc<[u8]>>,
+}
+
+/// Records of an active `.idx` as they are kept in memory
+struct ActiveRecords {
+    /// Newest record of every key and generation
+    entries: BTreeMap<(BlobKey, Generation), IdxEntry>,
+    /// Inline blob bytes by their offset in the `.idx` file
+    inline: BTreeMap<u32, Arc<[u8]>>,
+    /// How far the records reach into the `.dat` file
+    dat_len: u64,
+}
+
+fn parse_active_records(
+    chunk: usize,
+    records: &[IdxEntry],
+) -> Result<ActiveRecords, BlobProviderError> {
+    let mut entries = BTreeMap::new();
+    let mut inline = BTreeMap::new();
+    let mut dat_len = 0;
+
+    let mut position = 0;
+    while let Some(entry) = records.get(position) {
+        position += 1;
+
+        if entry.flags & IDX_FLAG_INLINE_PAYLOAD != 0 {
+            let payload = records
+                .get(position..position + payload_records(entry.len))
+                .ok_or_else(|| BlobProviderError::InvalidIdx {
+                    chunk: chunk as u64,
+                    offset: Some(((position - 1) * IDX_ENTRY_SIZE) as u64),
+                    reason: "truncated inline payload".to_owned(),
+                })?;
+
+            let offset = (position * IDX_ENTRY_SIZE) as u32;
+            inline.insert(offset, Arc::from(&payload.as_bytes()[..entry.len as usize]));
+            position += payload.len();
+            continue;
+        }
+
+        if !entry.is_tombstone() && !entry.is_inline() && entry.chunk as usize == chunk {
+            dat_len = dat_len.max(entry.offset as u64 + entry.len as u64);
+        }
+        entries.insert(entry.id(), *entry);
+    }
+
+    Ok(ActiveRecords {
+        entries,
+        inline,
+        dat_len,
+    })
+}
+
+impl ActiveChunk {
+    pub(crate) fn open(
+        storage: &dyn Storage,
+        chunk: usize,
+        idx_path: &Path,
+        dat_path: &Path,
+        num_entries: usize,
+    ) -> Result<Self, BlobProviderError> {
+        let idx_file = storage.open(idx_path, OpenMode::ReadWrite)?;
+        let dat_file = storage.open(dat_path, OpenMode::ReadWrite)?;
+
+        // Records are synced before they are committed, so a shorter file can only be a
+        // sorted rewrite whose seal never made it into the midx
+        let num_records = num_entries.min(idx_file.len()? as usize / IDX_ENTRY_SIZE);
+        let committed_len = (num_records * IDX_ENTRY_SIZE) as u64;
+
+        let records = read_idx_entries(idx_file.as_ref(), num_records)?;
+        let ActiveRecords {
+            entries,
+            inline,
+            dat_len,
+        } = parse_active_records(chunk, &records)?;
+
+        if dat_len > MAX_BLOB_SIZE as u64 {
+            return Err(BlobProviderError::InvalidIdx {
+                chunk: chunk as u64,
+                offset: None,
+                reason: format!("records reach byte {dat_len}, past the maximum chunk size"),
+            });
+        }
+
+        let dat_file_len = dat_file.len()?;
+        if dat_file_len < dat_len {
+            return Err(BlobProviderError::InvalidBlobFile {
+                path: dat_path.to_string_lossy().into_owned(),
+                reason: format!("{dat_file_len} bytes, but committed records reach byte {dat_len}"),
+            });
+        }
+
+        // Drop anything written after the last commit
+        if idx_file.len()? != committed_len {
+            idx_file.truncate(committed_len)?;
+        }
+        if dat_file.len()? != dat_len {
+            dat_file.truncate(dat_len)?;
+        }
+
+        Ok(Self {
+            chunk,
+            idx_file,
+            dat_file,
+            dat_len,
+            num_records,
+            entries: entries.into(),
+            inline: inline.into(),
+        })
+    }
+
+    /// Appends to the `.idx` at `idx_path` from now on, after a failed seal may have
+    /// replaced the file with its sorted rewrite. The rewrite holds the newest record
+    /// of every key and generation, so it reads as the same chunk, with inline bytes
+    /// at new offsets. `.dat` bytes past the records are kept, a batch may still
+    /// commit records pointing at them from the next chunk.
+    pub(crate) fn reopen_idx(
+        &mut self,
+        storage: &dyn Storage,
+        idx_path: &Path,
+    ) -> Result<(), BlobProviderError> {
+        let idx_file = storage.open(idx_path, OpenMode::ReadWrite)?;
+        let num_records = self
+            .num_records
+            .min(idx_file.len()? as usize / IDX_ENTRY_SIZE);
+
+        let records = read_idx_entries(idx_file.as_ref(), num_records)?;
+        let records = parse_active_records(self.chunk, &records)?;
+
+        self.idx_file = idx_file;
+        self.num_records = num_records;
+        self.entries = records.entries.into();
+        self.inline = records.inline.into();
+
+        Ok(())
+    }
+
+    pub(crate) fn has_room(&self, incoming: u64) -> bool {
+        self.dat_len == 0 || self.dat_len + incoming <= MAX_BLOB_SIZE as u64
+    }
+
+    fn location_at_end(&self, len: u64) -> BlobLocation {
+        BlobLocation {
+            chunk: self.chunk as u32,
+            offset: self.dat_len as u32,
+            len: len as u32,
+            inline: false,
+        }
+    }
+
+    /// Appends blob bytes to the `.dat` file, returning where they were written
+    pub(crate) fn append_data(&mut self, data: &[u8]) -> Result<BlobLocation, BlobProviderError> {
+        let location = self.location_at_end(data.len() as u64);
+
+        self.dat_file.write_at(data, self.dat_len)?;
+        self.dat_len += data.len() as u64;
+
+        Ok(location)
+    }
+
+    /// Copies `len` bytes from the start of `source` to the end of the `.dat` file,
+    /// returning where they were written
+    pub(crate) fn append_from_file(
+        &mut self,
+        source: &dyn StorageFile,
+        len: u64,
+    ) -> Result<BlobLocation, BlobProviderError> {
+        let location = self.location_at_end(len);
+
+        let source_len = source.len()?;
+        if source_len < len {
+            return Err(BlobProviderError::UnexpectedBlobLength(len, source_len));
+        }
+
+        let mut buffer = vec![0; COPY_BUFFER_SIZE.min(len as usize)];
+        let mut copied = 0;
+        while copied < len {
+            let part = &mut buffer[..COPY_BUFFER_SIZE.min((len - copied) as usize)];
+            source.read_at(part, copied)?;
+            self.dat_file.write_at(part, self.dat_len + copied)?;
+            copied += part.len() as u64;
+        }
+        self.dat_len += len;
+
+        Ok(location)
+    }
+
+    /// Appends the bytes of a small blob to the `.idx` file, behind a header record,
+    /// returning where they were written. Unlike `.dat` bytes they are lost if the
+    /// chunk gets sealed before a record pointing at them is committed.
+    pub(crate) fn append_inline(&mut self, data: &[u8]) -> Result<BlobLocation, BlobProviderError> {
+        let records = inline_payload(self.chunk, data);
+        self.idx_file.write_at(
+            records.as_bytes(),
+            (self.num_records * IDX_ENTRY_SIZE) as u64,
+        )?;
+
+        let offset = ((self.num_records + 1) * IDX_ENTRY_SIZE) as u32;
+        self.num_records += records.len();
+        self.inline.insert(offset, Arc::from(data));
+
+        Ok(BlobLocation {
+            chunk: self.chunk as u32,
+            offset,
+            len: data.len() as u32,
+            inline: true,
+        })
+    }
+
+    /// Appends records to the `.idx` file. They only become durable once `sync` ran
+    /// and the caller bumped the chunk's midx entry.
+    pub(crate) fn write_records(&mut self, records: &[IdxEntry]) -> Result<(), BlobProviderError> {
+        self.idx_file.write_at(
+            records.as_bytes(),
+            (self.num_records * IDX_ENTRY_SIZE) as u64,
+        )?;
+        self.num_records += records.len();
+
+        Ok(())
+    }
+
+    /// Syncs the blob bytes before the records pointing at them
+    pub(crate) fn sync(&self) -> Result<(), BlobProviderError> {
+        self.dat_file.fsync()?;
+        self.idx_file.fsync()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use crate::{
+    data_structures::chunk_idx::{BlobLocation, IdxEntry},
+    err_type::BlobProviderError,
+    storage::{OpenMode, Storage, StorageFile, read_all, write_atomically},
+};
+
+pub type ContentHash = [u8; 32];
+
+/// A single record of the `.cidx` sidecar, mapping a content hash to the first
+/// location that stored those bytes
+#[repr(C)]
+#[derive(Debug, Copy, Clone, FromBytes, IntoBytes, KnownLayout, Immutable)]
+struct ContentIdxEntry {
+    hash: ContentHash,
+    chunk: u32,
+    offset: u32,
+    len: u32,
+    reserved: u32,
+}
+
+const _: () = assert!(size_of::<ContentIdxEntry>() == 48);
+
+pub(crate) fn hash_content(data: &[u8]) -> ContentHash {
+    *blake3::hash(data).as_bytes()
+}
+
+/// Content hash index used for deduplication, plus the number of live keys
+/// referencing every stored location.
+///
+/// The sidecar is only appended to after the index commit it belongs to, so a crash
+/// can at worst lose a hash. Hashes whose location has no live reference are dropped
+/// on open and after compaction.
+pub(crate) struct ContentIdx {
+    storage: Arc<dyn Storage>,
+    file_path: PathBuf,
+    file: Box<dyn StorageFile>,
+    by_hash: HashMap<ContentHash, BlobLocation>,
+    hash_of_location: HashMap<BlobLocation, ContentHash>,
+    ref_counts: HashMap<BlobLocation, u32>,
+}
+
+impl ContentIdx {
+    pub(crate) fn open(
+        storage: Arc<dyn Storage>,
+        path: &Path,
+        live_entries: &[IdxEntry],
+    ) -> Result<Self, BlobProviderError> {
+        let file = storage.open(path, OpenMode::ReadWrite)?;
+        let buffer = read_all(file.as_ref())?;
+
+        let ref_counts = Self::count_references(live_entries);
+        let hashes = buffer
+            .chunks_exact(std::mem::size_of::<ContentIdxEntry>())
+            .filter_map(|record| ContentIdxEntry::read_from_bytes(record).ok())
+            .map(|record| {
+                (
+                    record.hash,
+                    BlobLocation {
+                        chunk: record.chunk,
+                        offset: record.offset,
+                        len: record.len,
+                        inline: false,
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut content_idx = Self {
+            storage,
+            file_path: path.to_path_buf(),
+            file,
+            by_hash: HashMap::new(),
+            hash_of_location: HashMap::new(),
+            ref_counts,
+        };
+        content_idx.rewrite(hashes)?;
+
+        Ok(content_idx)
+    }
+
+    fn count_references(live_entries: &[IdxEntry]) -> HashMap<BlobLocation, u32> {
+        let mut ref_counts = HashMap::new();
+        for entry in live_entries {
+            *ref_counts.entry(entry.location()).or_insert(0) += 1;
+        }
+
+        ref_counts
+    }
+
+    /// Replaces the sidecar with the `hashes` that still point at referenced
+    /// locations
+    fn rewrite(
+        &mut self,
+        hashes: impl IntoIterator<Item = (ContentHash, BlobLocation)>,
+    ) -> Result<(), BlobProviderError> {
+        self.by_hash.clear();
+        self.hash_of_location.clear();
+
+        for (hash, location) in hashes {
+            if self.ref_counts.contains_key(&location) {
+                self.by_hash.insert(hash, location);
+                self.hash_of_location.insert(location, hash);
+            }
+        }
+
+        let mut buffer = Vec::new();
+        for (hash, location) in &self.by_hash {
+            buffer.extend_from_slice(Self::record(hash, location).as_bytes());
+        }
+
+        write_atomically(self.storage.as_ref(), &self.file_path, &buffer)?;
+
+        self.file = self.storage.open(&self.file_path, OpenMode::ReadWrite)?;
+
+        Ok(())
+    }
+
+    fn record(hash: &ContentHash, location: &BlobLocation) -> ContentIdxEntry {
+        ContentIdxEntry {
+            hash: *hash,
+            chunk: location.chunk,
+            offset: location.offset,
+            len: location.len,
+            reserved: 0,
+        }
+    }
+
+    pub(crate) fn find(&self, hash: &ContentHash) -> Option<BlobLocation> {
+        self.by_hash.get(hash).copied()
+    }
+
+    /// Remembers the hash of freshly written bytes
+    pub(crate) fn insert(
+        &mut self,
+        hash: ContentHash,
+        location: BlobLocation,
+    ) -> Result<(), BlobProviderError> {
+        if !self.ref_counts.contains_key(&location) || self.by_hash.contains_key(&hash) {
+            return Ok(());
+        }
+
+        self.file
+            .append(Self::record(&hash, &location).as_bytes())?;
+        self.by_hash.insert(hash, location);
+        self.hash_of_location.insert(location, hash);
+
+        Ok(())
+    }
+
+    pub(crate) fn acquire(&mut self, location: BlobLocation) {
+        *self.ref_counts.entry(location).or_insert(0) += 1;
+    }
+
+    /// Drops a reference, forgetting the location's hash once nothing points at it
+    pub(crate) fn release(&mut self, location: BlobLocation) {
+        let Some(ref_count) = self.ref_counts.get_mut(&location) else {
+            return;
+        };
+
+        *ref_count -= 1;
+        if *ref_count == 0 {
+            self.ref_counts.remove(&location);
+            if let Some(hash) = self.hash_of_location.remove(&location) {
+                self.by_hash.remove(&hash);
+            }
+        }
+    }
+
+    /// Recounts references after records stopped being live without a commit, e.g.
+    /// when old generations are pruned
+    pub(crate) fn recount(&mut self, live_entries: &[IdxEntry]) -> Result<(), BlobProviderError> {
+        let hashes = self
+            .by_hash
+            .iter()
+            .map(|(hash, location)| (*hash, *location))
+            .collect::<Vec<_>>();
+
+        self.ref_counts = Self::count_references(live_entries);
+        self.rewrite(hashes)
+    }
+
+    /// Points every hash at the location its bytes were moved to by compaction
+    pub(crate) fn relocate(
+        &mut self,
+        relocated: &HashMap<BlobLocation, BlobLocation>,
+        live_entries: &[IdxEntry],
+    ) -> Result<(), BlobProviderError> {
+        let hashes = self
+            .by_hash
+            .iter()
+            .filter_map(|(hash, location)| relocated.get(location).map(|new| (*hash, *new)))
+            .collect::<Vec<_>>();
+
+        self.ref_counts = Self::count_references(live_entries);
+        self.rewrite(hashes)
+    }
+}
@@ -1,22 +1,30 @@
 use std::{
     collections::{HashMap, VecDeque},
-    io::{Read, Seek},
-    path::PathBuf,
-    sync::{Arc, Mutex, MutexGuard, RwLock},
+    path::Path,
+    sync::{Arc, RwLock},
 };
 
-use crate::err_type::BlobProviderError;
+use crate::{
+    err_type::BlobProviderError,
+    storage::{OpenMode, Storage, StorageFile},
+};
 
 const MAX_OPEN_FILE_DESCRIPTORS: usize = 12;
 
+/// Namespace and chunk of a `.dat` file, so providers of one `BlobStore` can share a
+/// pool and its budget
+pub(crate) type FdKey = (u32, usize);
+
 pub(crate) struct FdPool {
-    open_file_descriptors: RwLock<HashMap<usize, Arc<Mutex<std::fs::File>>>>,
-    vec_deque: RwLock<VecDeque<usize>>,
+    storage: Arc<dyn Storage>,
+    open_file_descriptors: RwLock<HashMap<FdKey, Arc<dyn StorageFile>>>,
+    vec_deque: RwLock<VecDeque<FdKey>>,
 }
 
 impl FdPool {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(storage: Arc<dyn Storage>) -> Self {
         Self {
+            storage,
             open_file_descriptors: HashMap::new().into(),
             vec_deque: VecDeque::new().into(),
         }
@@ -24,64 +32,91 @@ impl FdPool {
 
     pub(crate) fn blocking_read(
         &self,
-        index: usize,
+        index: FdKey,
+        path: &Path,
         offset: u64,
         len: u64,
     ) -> Result<Vec<u8>, BlobProviderError> {
-        let file_descriptor_mutex_lock = self.get_file_descriptor_mutex(index)?;
-        let mut current_file_descriptor = file_descriptor_mutex_lock.lock()?;
-
-        current_file_descriptor.seek(std::io::SeekFrom::Start(offset))?;
+        let file_descriptor = self.get_or_open_file_descriptor(index, path)?;
 
         let mut buffer = vec![0; len as usize];
-        current_file_descriptor.read_exact(&mut buffer)?;
+        file_descriptor.read_at(&mut buffer, offset)?;
 
         Ok(buffer)
     }
 
-    pub(crate) fn does_fd_exist(&self, index: usize) -> Result<bool, BlobProviderError> {
-        let read_descriptor = self.open_file_descriptors.read()?;
-        Ok(read_descriptor.contains_key(&index))
+    /// Hints the kernel to start reading the range into the page cache
+    pub(crate) fn advise_will_need(
+        &self,
+        index: FdKey,
+        path: &Path,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), BlobProviderError> {
+        let file_descriptor = self.get_or_open_file_descriptor(index, path)?;
+
+        Ok(file_descriptor.advise_will_need(offset, len)?)
     }
 
     pub(crate) fn insert_fd(
         &self,
-        index: usize,
-        fd: std::fs::File,
-    ) -> Result<(), BlobProviderError> {
-        let read_descriptor = self.open_file_descriptors.read()?;
-        if read_descriptor.contains_key(&index) {
-            return Err(BlobProviderError::FileDescriptorAlreadyExists(index as u64));
+        index: FdKey,
+        fd: Arc<dyn StorageFile>,
+    ) -> Result<Arc<dyn StorageFile>, BlobProviderError> {
+        let mut writing_descriptor = self.open_file_descriptors.write()?;
+        if writing_descriptor.contains_key(&index) {
+            return Err(BlobProviderError::FileDescriptorAlreadyExists(
+                index.1 as u64,
+            ));
         }
 
-        let mut writing_descriptor = self.open_file_descriptors.write()?;
         let mut writing_vec_deque = self.vec_deque.write()?;
 
         // Remove the oldest file descriptor if we've reached the limit
-        if writing_descriptor.len() >= MAX_OPEN_FILE_DESCRIPTORS {
-            if let Some(removed_index) = writing_vec_deque.pop_front() {
-                writing_descriptor.remove(&removed_index);
-            }
+        if writing_descriptor.len() >= MAX_OPEN_FILE_DESCRIPTORS
+            && let Some(removed_index) = writing_vec_deque.pop_front()
+        {
+            writing_descriptor.remove(&removed_index);
         }
 
-        writing_descriptor.insert(index, Arc::new(Mutex::new(fd)));
+        writing_descriptor.insert(index, fd.clone());
         writing_vec_deque.push_back(index);
 
+        Ok(fd)
+    }
+
+    pub(crate) fn remove_fd(&self, index: FdKey) -> Result<(), BlobProviderError> {
+        let mut writing_descriptor = self.open_file_descriptors.write()?;
+        let mut writing_vec_deque = self.vec_deque.write()?;
+
+        writing_descriptor.remove(&index);
+        writing_vec_deque.retain(|open_index| *open_index != index);
+
         Ok(())
     }
 }
 
 // Private helper methods
 impl FdPool {
-    fn get_file_descriptor_mutex(
+    fn get_or_open_file_descriptor(
         &self,
-        index: usize,
-    ) -> Result<Arc<Mutex<std::fs::File>>, BlobProviderError> {
-        Ok(self
-            .open_file_descriptors
-            .read()?
-            .get(&index)
-            .ok_or(BlobProviderError::InvalidChunkIndex(index as u64))?
-            .clone())
+        index: FdKey,
+        path: &Path,
+    ) -> Result<Arc<dyn StorageFile>, BlobProviderError> {
+        if let Some(file_descriptor) = self.open_file_descriptors.read()?.get(&index) {
+            return Ok(file_descriptor.clone());
+        }
+
+        let file_descriptor = self.storage.open(path, OpenMode::Read)?;
+        match self.insert_fd(index, file_descriptor.into()) {
+            // Another reader opened it in the meantime
+            Err(BlobProviderError::FileDescriptorAlreadyExists(_)) => Ok(self
+                .open_file_descriptors
+                .read()?
+                .get(&index)
+                .ok_or(BlobProviderError::InvalidChunkIndex(index.1 as u64))?
+                .clone()),
+            result => result,
+        }
     }
 }
@@ -0,0 +1,199 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use zerocopy::{
+    FromBytes, Immutable, IntoBytes, KnownLayout, byteorder::little_endian::U64, transmute,
+};
+
+use crate::{
+    data_structures::chunk_idx::BlobKey,
+    err_type::BlobProviderError,
+    storage::{OpenMode, Storage, read_all, write_atomically},
+};
+
+const FILTER_VERSION: u16 = 1;
+/// About 1% false positives while at capacity
+const BITS_PER_KEY: u64 = 10;
+const NUM_HASHES: u16 = 7;
+const MIN_CAPACITY: u64 = 1024;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, FromBytes, IntoBytes, KnownLayout, Immutable)]
+struct FilterHeader {
+    /// Keys the filter was sized for
+    capacity: u64,
+    /// Distinct keys added so far, as far as the filter can tell
+    num_keys: u64,
+    num_words: u32,
+    num_hashes: u16,
+    version: u16,
+}
+
+const _: () = assert!(size_of::<FilterHeader>() == 24);
+
+/// Bloom filter over every key that was ever written, persisted in a sidecar next to
+/// the midx, so lookups of missing keys don't have to search any `.idx`.
+///
+/// Keys are added in memory on commit and the sidecar is rewritten whenever a chunk
+/// is sealed, before the seal is published. Keys of the active chunk are added again
+/// on open, so the filter never misses a committed key. It is rebuilt from the live
+/// keys by compaction, from every key by a seal that finds it overfull, or on open if
+/// it is missing, unreadable or overfull.
+///
+/// Bits are set atomically, so index snapshots share the filter with the writer.
+/// Keys show up in it before their records are published, which only costs readers
+/// of older snapshots a few extra lookups.
+pub(crate) struct KeyFilter {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    header: FilterHeader,
+    num_keys: AtomicU64,
+    words: Vec<AtomicU64>,
+}
+
+impl KeyFilter {
+    /// Opens the persisted filter, or builds one from `keys` if it can't be used
+    pub(crate) fn open(
+        storage: Arc<dyn Storage>,
+        path: &Path,
+        keys: impl Fn() -> Vec<BlobKey>,
+    ) -> Result<Self, BlobProviderError> {
+        match Self::load(storage.as_ref(), path)? {
+            Some((header, words)) if header.num_keys <= header.capacity => Ok(Self {
+                storage,
+                path: path.to_path_buf(),
+                header,
+                num_keys: AtomicU64::new(header.num_keys),
+                words: words.into_iter().map(AtomicU64::new).collect(),
+            }),
+            _ => Self::build(storage, path, &keys()),
+        }
+    }
+
+    /// Creates and persists a filter holding `keys`, with room for as many again
+    pub(crate) fn build(
+        storage: Arc<dyn Storage>,
+        path: &Path,
+        keys: &[BlobKey],
+    ) -> Result<Self, BlobProviderError> {
+        let capacity = (keys.len() as u64 * 2).max(MIN_CAPACITY);
+        let num_words = (capacity * BITS_PER_KEY).div_ceil(64);
+
+        let filter = Self {
+            storage,
+            path: path.to_path_buf(),
+            header: FilterHeader {
+                capacity,
+                num_keys: 0,
+                num_words: num_words as u32,
+                num_hashes: NUM_HASHES,
+                version: FILTER_VERSION,
+            },
+            num_keys: AtomicU64::new(0),
+            words: (0..num_words).map(|_| AtomicU64::new(0)).collect(),
+        };
+        for key in keys {
+            filter.insert(key);
+        }
+        filter.persist()?;
+
+        Ok(filter)
+    }
+
+    fn load(
+        storage: &dyn Storage,
+        path: &Path,
+    ) -> Result<Option<(FilterHeader, Vec<u64>)>, BlobProviderError> {
+        let buffer = match storage.open(path, OpenMode::Read) {
+            Ok(file) => read_all(file.as_ref())?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let Ok((header, words)) = FilterHeader::read_from_prefix(&buffer) else {
+            return Ok(None);
+        };
+        let Ok(words) = <[U64]>::ref_from_bytes(words) else {
+            return Ok(None);
+        };
+
+        let is_valid = header.version == FILTER_VERSION
+            && header.num_words > 0
+            && header.num_words as usize == words.len()
+            && (1..=16).contains(&header.num_hashes);
+        if !is_valid {
+            return Ok(None);
+        }
+
+        Ok(Some((
+            header,
+            words.iter().map(|word| word.get()).collect(),
+        )))
+    }
+
+    /// Bit positions of `key`, derived from two independent hashes
+    fn bits(&self, key: &BlobKey) -> impl Iterator<Item = (usize, u64)> + use<> {
+        let [low, high]: [U64; 2] = transmute!(*key);
+        let first = mix(low.get() ^ mix(high.get()));
+        let second = mix(high.get() ^ 0x9e37_79b9_7f4a_7c15) | 1;
+        let num_bits = self.header.num_words as u64 * 64;
+
+        (0..self.header.num_hashes as u64).map(move |i| {
+            let bit = first.wrapping_add(i.wrapping_mul(second)) % num_bits;
+            ((bit / 64) as usize, 1 << (bit % 64))
+        })
+    }
+
+    /// `false` means `key` was never added
+    pub(crate) fn may_contain(&self, key: &BlobKey) -> bool {
+        self.bits(key)
+            .all(|(word, mask)| self.words[word].load(Ordering::Acquire) & mask != 0)
+    }
+
+    /// Only ever called by the writer holding the index lock, so checking and setting
+    /// the bits doesn't race with another insert
+    pub(crate) fn insert(&self, key: &BlobKey) {
+        if self.may_contain(key) {
+            return;
+        }
+
+        for (word, mask) in self.bits(key) {
+            self.words[word].fetch_or(mask, Ordering::Release);
+        }
+        self.num_keys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether more keys were added than the filter was sized for, so it has to be
+    /// built again to keep its false positive rate
+    pub(crate) fn is_overfull(&self) -> bool {
+        self.num_keys.load(Ordering::Relaxed) > self.header.capacity
+    }
+
+    /// Replaces the sidecar with the current filter
+    pub(crate) fn persist(&self) -> Result<(), BlobProviderError> {
+        let header = FilterHeader {
+            num_keys: self.num_keys.load(Ordering::Relaxed),
+            ..self.header
+        };
+        let mut buffer = header.as_bytes().to_vec();
+        for word in &self.words {
+            buffer.extend_from_slice(&word.load(Ordering::Acquire).to_le_bytes());
+        }
+
+        write_atomically(self.storage.as_ref(), &self.path, &buffer)?;
+
+        Ok(())
+    }
+}
+
+/// Finalizer of splitmix64, spreads every input bit over the whole output
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
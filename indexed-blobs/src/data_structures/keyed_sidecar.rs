@@ -0,0 +1,321 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use crate::{
+    data_structures::chunk_idx::BlobKey,
+    err_type::BlobProviderError,
+    storage::{OpenMode, Storage, StorageFile, read_all, write_atomically},
+};
+
+/// Sidecar file that changes are appended to, and that is replaced as a whole once
+/// it only holds stale records
+pub(crate) struct SidecarFile {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    file: Mutex<Box<dyn StorageFile>>,
+    is_dirty: AtomicBool,
+}
+
+impl SidecarFile {
+    /// Opens the sidecar at `path`, creating it if it's missing, along with its contents
+    pub(crate) fn open(
+        storage: Arc<dyn Storage>,
+        path: &Path,
+    ) -> Result<(Self, Vec<u8>), BlobProviderError> {
+        let file = storage.open(path, OpenMode::ReadWrite)?;
+        let buffer = read_all(file.as_ref())?;
+
+        let sidecar = Self {
+            storage,
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            is_dirty: AtomicBool::new(false),
+        };
+
+        Ok((sidecar, buffer))
+    }
+
+    pub(crate) fn len(&self) -> Result<u64, BlobProviderError> {
+        Ok(self.file.lock()?.len()?)
+    }
+
+    pub(crate) fn append(&self, bytes: &[u8]) -> Result<(), BlobProviderError> {
+        let file = self.file.lock()?;
+        let len = file.len()?;
+        if let Err(err) = file.write_at(bytes, len) {
+            // A torn record would hide every record appended after it
+            let _ = file.truncate(len);
+            return Err(err.into());
+        }
+        self.is_dirty.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Cuts off everything appended after the sidecar was `len` bytes long
+    pub(crate) fn truncate(&self, len: u64) -> Result<(), BlobProviderError> {
+        self.file.lock()?.truncate(len)?;
+        self.is_dirty.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Makes every append so far durable
+    pub(crate) fn sync(&self) -> Result<(), BlobProviderError> {
+        if self.is_dirty.swap(false, Ordering::AcqRel)
+            && let Err(err) = self.file.lock()?.fsync()
+        {
+            self.is_dirty.store(true, Ordering::Release);
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the sidecar with `bytes`, which also drops a torn tail
+    pub(crate) fn rewrite(&self, bytes: &[u8]) -> Result<(), BlobProviderError> {
+        let mut file = self.file.lock()?;
+        write_atomically(self.storage.as_ref(), &self.path, bytes)?;
+
+        *file = self.storage.open(&self.path, OpenMode::ReadWrite)?;
+        self.is_dirty.store(false, Ordering::Release);
+
+        Ok(())
+    }
+}
+
+/// The key no longer has a value
+const KEYED_FLAG_REMOVED: u32 = 1 << 0;
+/// The record was written with the index commit at `commit`, and is dropped if that
+/// commit never landed
+const KEYED_FLAG_WITH_COMMIT: u32 = 1 << 1;
+
+/// Index commit of records to chunk `chunk` that leaves it holding `num_records`
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, FromBytes, IntoBytes, KnownLayout, Immutable)]
+pub(crate) struct CommitPoint {
+    pub(crate) chunk: u32,
+    pub(crate) num_records: u32,
+}
+
+/// Starts a record of a `KeyedSidecar`, followed by `len` bytes of value. The last
+/// record of a key wins.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, FromBytes, IntoBytes, KnownLayout, Immutable)]
+struct KeyedRecordHeader {
+    key: BlobKey,
+    flags: u32,
+    len: u32,
+    commit: CommitPoint,
+}
+
+const _: () = assert!(size_of::<KeyedRecordHeader>() == 32);
+
+/// What a `KeyedSidecar` stores for every key
+pub(crate) trait SidecarValue: Clone + PartialEq {
+    fn as_record_bytes(&self) -> &[u8];
+
+    /// `None` if `bytes` aren't a valid value, which ends the sidecar like a torn tail
+    fn from_record_bytes(bytes: &[u8]) -> Option<Self>;
+
+    /// Rejects values that must not be stored
+    fn validate(&self) -> Result<(), BlobProviderError> {
+        Ok(())
+    }
+}
+
+/// Changes appended to a `KeyedSidecar` ahead of the index commit they belong to.
+/// Readers only see them once they are applied after that commit succeeded, and
+/// they are cut off the sidecar again if it failed.
+pub(crate) struct StagedChanges<V> {
+    changes: Vec<(BlobKey, Option<V>)>,
+    rollback_len: u64,
+}
+
+impl<V> StagedChanges<V> {
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &BlobKey> {
+        self.changes.iter().map(|(key, _)| key)
+    }
+}
+
+/// Metadata of individual keys, kept in memory and persisted as a log of changes.
+///
+/// Changes are appended to the sidecar and synced along with the next index commit.
+/// Values of keys that don't hold a blob anymore are skipped by readers, and dropped
+/// on open and by compaction.
+pub(crate) struct KeyedSidecar<V> {
+    file: SidecarFile,
+    by_key: RwLock<HashMap<BlobKey, V>>,
+}
+
+impl<V: SidecarValue> KeyedSidecar<V> {
+    /// Loads every value in the sidecar, skipping records of index commits that
+    /// `is_committed` rejects. `retain_live` must be called next, it drops the values
+    /// of keys that no longer hold a blob and rewrites a torn tail.
+    pub(crate) fn open(
+        storage: Arc<dyn Storage>,
+        path: &Path,
+        is_committed: impl Fn(CommitPoint) -> bool,
+    ) -> Result<Self, BlobProviderError> {
+        let (file, buffer) = SidecarFile::open(storage, path)?;
+
+        let mut by_key = HashMap::new();
+        let mut rest = buffer.as_slice();
+        while let Ok((header, tail)) = KeyedRecordHeader::read_from_prefix(rest)
+            && let Some(bytes) = tail.get(..header.len as usize)
+        {
+            rest = &tail[header.len as usize..];
+
+            if header.flags & KEYED_FLAG_WITH_COMMIT != 0 && !is_committed(header.commit) {
+                continue;
+            }
+
+            match header.flags & KEYED_FLAG_REMOVED != 0 {
+                true => by_key.remove(&header.key),
+                false => match V::from_record_bytes(bytes) {
+                    Some(value) => by_key.insert(header.key, value),
+                    None => break,
+                },
+            };
+        }
+
+        Ok(Self {
+            file,
+            by_key: RwLock::new(by_key),
+        })
+    }
+
+    /// Record setting `key` to `value`, or removing its value for `None`, that only
+    /// applies once `commit` landed if one is passed
+    fn record(key: &BlobKey, value: Option<&V>, commit: Option<CommitPoint>) -> Vec<u8> {
+        let bytes = value.map_or(&[][..], V::as_record_bytes);
+        let mut flags = match value {
+            Some(_) => 0,
+            None => KEYED_FLAG_REMOVED,
+        };
+        if commit.is_some() {
+            flags |= KEYED_FLAG_WITH_COMMIT;
+        }
+
+        let header = KeyedRecordHeader {
+            key: *key,
+            flags,
+            len: bytes.len() as u32,
+            commit: commit.unwrap_or_default(),
+        };
+
+        let mut record = header.as_bytes().to_vec();
+        record.extend_from_slice(bytes);
+        record
+    }
+
+    pub(crate) fn get(&self, key: &BlobKey) -> Result<Option<V>, BlobProviderError> {
+        Ok(self.by_key.read()?.get(key).cloned())
+    }
+
+    /// Every key's value
+    pub(crate) fn all(&self) -> Result<HashMap<BlobKey, V>, BlobProviderError> {
+        Ok(self.by_key.read()?.clone())
+    }
+
+    /// Every key that has a value
+    pub(crate) fn keys(&self) -> Result<HashSet<BlobKey>, BlobProviderError> {
+        Ok(self.by_key.read()?.keys().copied().collect())
+    }
+
+    pub(crate) fn set(&self, key: BlobKey, value: V) -> Result<(), BlobProviderError> {
+        let staged = self.stage([(key, Some(value))], None)?;
+        self.apply(staged)
+    }
+
+    /// Appends `changes` without applying them yet, each setting a key's value or
+    /// removing it for `None`. Later changes of a key win. Changes written with the
+    /// index commit at `commit` are dropped on open if it never landed.
+    pub(crate) fn stage(
+        &self,
+        changes: impl IntoIterator<Item = (BlobKey, Option<V>)>,
+        commit: Option<CommitPoint>,
+    ) -> Result<StagedChanges<V>, BlobProviderError> {
+        let changes = {
+            let by_key = self.by_key.read()?;
+            changes
+                .into_iter()
+                .filter(|(key, value)| by_key.get(key) != value.as_ref())
+                .collect::<Vec<_>>()
+        };
+
+        let mut buffer = Vec::new();
+        for (key, value) in &changes {
+            if let Some(value) = value {
+                value.validate()?;
+            }
+            buffer.extend_from_slice(&Self::record(key, value.as_ref(), commit));
+        }
+
+        let rollback_len = self.file.len()?;
+        if !buffer.is_empty() {
+            self.file.append(&buffer)?;
+        }
+
+        Ok(StagedChanges {
+            changes,
+            rollback_len,
+        })
+    }
+
+    /// Makes `staged` visible to readers, once the commit it belongs to succeeded
+    pub(crate) fn apply(&self, staged: StagedChanges<V>) -> Result<(), BlobProviderError> {
+        let mut by_key = self.by_key.write()?;
+        for (key, value) in staged.changes {
+            match value {
+                Some(value) => by_key.insert(key, value),
+                None => by_key.remove(&key),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Cuts `staged` off the sidecar after the commit it belongs to failed
+    pub(crate) fn discard(&self, staged: &StagedChanges<V>) -> Result<(), BlobProviderError> {
+        self.file.truncate(staged.rollback_len)
+    }
+
+    pub(crate) fn sync(&self) -> Result<(), BlobProviderError> {
+        self.file.sync()
+    }
+
+    /// Drops the values of keys that no longer hold a blob and replaces the sidecar
+    /// with the ones that are left
+    pub(crate) fn retain_live(
+        &self,
+        live_keys: &HashSet<BlobKey>,
+    ) -> Result<(), BlobProviderError> {
+        let mut by_key = self.by_key.write()?;
+        by_key.retain(|key, _| live_keys.contains(key));
+
+        self.file.rewrite(&Self::records(&by_key))
+    }
+
+    /// Contents of a sidecar holding every value, without any index commit to wait for
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, BlobProviderError> {
+        Ok(Self::records(&*self.by_key.read()?))
+    }
+
+    fn records(by_key: &HashMap<BlobKey, V>) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for (key, value) in by_key {
+            buffer.extend_from_slice(&Self::record(key, Some(value), None));
+        }
+
+        buffer
+    }
+}
@@ -1,12 +1,21 @@
 use std::{
-    fs::OpenOptions,
     ops::{Index, IndexMut},
-    path::{Path, PathBuf},
+    path::Path,
 };
 
-use memmap2::{Mmap, MmapMut};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
-use crate::err_type::BlobProviderError;
+use crate::{
+    err_type::BlobProviderError,
+    storage::{OpenMode, Storage, StorageFile, read_all},
+};
+
+/// Chunk has been sorted and will no longer receive appends
+pub const MIDX_FLAG_SEALED: u16 = 1 << 0;
+/// Chunk was compacted away, its files are (or are about to be) deleted
+pub const MIDX_FLAG_RETIRED: u16 = 1 << 1;
+
+const MIDX_KNOWN_FLAGS: u16 = MIDX_FLAG_SEALED | MIDX_FLAG_RETIRED;
 
 #[repr(u16)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -14,90 +23,141 @@ pub enum Version {
     V1 = 1,
 }
 
+impl TryFrom<u16> for Version {
+    type Error = BlobProviderError;
+
+    fn try_from(version: u16) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(Version::V1),
+            _ => Err(BlobProviderError::InvalidMIdx {
+                chunk: None,
+                reason: format!("unknown version {version}"),
+            }),
+        }
+    }
+}
+
+/// One entry per chunk. `num_entries` is the commit point of the chunk: any
+/// `.idx` records past it were never committed and are discarded on open.
+///
+/// Every field is a plain integer so any bytes read from disk are a valid value,
+/// `validate` then rejects the ones this version doesn't understand.
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, FromBytes, IntoBytes, KnownLayout, Immutable)]
 pub struct MIdxEntry {
-    num_entries: u32,
-    reserved: u16,
-    version: Version,
+    pub(crate) num_entries: u32,
+    pub(crate) flags: u16,
+    version: u16,
 }
 
 const _: () = assert!(size_of::<MIdxEntry>() == 8);
 
-pub struct MIdx {
-    file_path: PathBuf,
-    mmap: MmapMut,
+impl MIdxEntry {
+    pub(crate) fn new() -> Self {
+        Self {
+            num_entries: 0,
+            flags: 0,
+            version: Version::V1 as u16,
+        }
+    }
+
+    pub(crate) fn version(&self) -> Result<Version, BlobProviderError> {
+        Version::try_from(self.version)
+    }
+
+    fn validate(&self, chunk: usize) -> Result<(), BlobProviderError> {
+        let reason = match (self.version(), self.flags & !MIDX_KNOWN_FLAGS) {
+            (Err(_), _) => format!("unknown version {}", self.version),
+            (Ok(_), 0) => return Ok(()),
+            (Ok(_), unknown_flags) => format!("unknown flags {unknown_flags:#06x}"),
+        };
+
+        Err(BlobProviderError::InvalidMIdx {
+            chunk: Some(chunk as u64),
+            reason,
+        })
+    }
+
+    pub(crate) fn is_sealed(&self) -> bool {
+        self.flags & MIDX_FLAG_SEALED != 0
+    }
+
+    pub(crate) fn is_retired(&self) -> bool {
+        self.flags & MIDX_FLAG_RETIRED != 0
+    }
 }
 
-pub fn open_or_create_midx(path: &Path) -> Result<MIdx, BlobProviderError> {
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(path)?;
+/// In-memory copy of the `.midx` file, written back whole on every flush. The file
+/// holds one small entry per chunk, so rewriting it is cheap.
+pub struct MIdx {
+    file: Box<dyn StorageFile>,
+    entries: Vec<MIdxEntry>,
+}
 
-    let mmap = unsafe { MmapMut::map_mut(&file)? };
+pub fn open_or_create_midx(storage: &dyn Storage, path: &Path) -> Result<MIdx, BlobProviderError> {
+    let file = storage.open(path, OpenMode::ReadWrite)?;
+    let mut buffer = read_all(file.as_ref())?;
 
-    // Check entry count
-    if mmap.len() % std::mem::size_of::<MIdxEntry>() != 0 {
-        return Err(BlobProviderError::InvalidMIdx);
+    // A partial trailing entry can only come from an `add_entry` that never completed,
+    // so its chunk was never written to
+    let complete_len = buffer.len() - buffer.len() % std::mem::size_of::<MIdxEntry>();
+    if complete_len != buffer.len() {
+        buffer.truncate(complete_len);
+        file.truncate(complete_len as u64)?;
     }
 
-    Ok(MIdx {
-        file_path: path.to_path_buf(),
-        mmap,
-    })
+    let entries = buffer
+        .chunks_exact(std::mem::size_of::<MIdxEntry>())
+        .enumerate()
+        .map(|(chunk, record)| {
+            let entry =
+                MIdxEntry::read_from_bytes(record).map_err(|_| BlobProviderError::InvalidMIdx {
+                    chunk: Some(chunk as u64),
+                    reason: "truncated entry".to_owned(),
+                })?;
+            entry.validate(chunk)?;
+
+            Ok(entry)
+        })
+        .collect::<Result<_, BlobProviderError>>()?;
+
+    Ok(MIdx { file, entries })
 }
 
 impl Index<usize> for MIdx {
     type Output = MIdxEntry;
 
     fn index(&self, index: usize) -> &Self::Output {
-        &self.entries()[index]
+        &self.entries[index]
     }
 }
 
 impl IndexMut<usize> for MIdx {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.entries_mut()[index]
+        &mut self.entries[index]
     }
 }
 
 impl MIdx {
-    fn entry_count(&self) -> usize {
-        self.mmap.len() / std::mem::size_of::<MIdxEntry>()
+    pub(crate) fn entry_count(&self) -> usize {
+        self.entries.len()
     }
 
-    fn entries(&self) -> &[MIdxEntry] {
-        unsafe {
-            std::slice::from_raw_parts(self.mmap.as_ptr() as *const MIdxEntry, self.entry_count())
+    /// Appends a new chunk entry and returns its index
+    pub(crate) fn add_entry(&mut self, entry: MIdxEntry) -> Result<usize, BlobProviderError> {
+        self.entries.push(entry);
+        if let Err(err) = self.flush() {
+            self.entries.pop();
+            return Err(err);
         }
-    }
 
-    fn entries_mut(&mut self) -> &mut [MIdxEntry] {
-        unsafe {
-            std::slice::from_raw_parts_mut(
-                self.mmap.as_mut_ptr() as *mut MIdxEntry,
-                self.entry_count(),
-            )
-        }
+        Ok(self.entries.len() - 1)
     }
 
-    fn add_entry(&mut self, entry: MIdxEntry) -> Result<(), BlobProviderError> {
-        let entry_count = self.entry_count();
-        let new_len = entry_count + 1;
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&self.file_path)?;
-
-        file.set_len((new_len * std::mem::size_of::<MIdxEntry>()) as u64)?;
-
-        self.mmap = unsafe { MmapMut::map_mut(&file)? };
-        self.entries_mut()
-            .last_mut()
-            .ok_or(BlobProviderError::InvalidMIdx)?
-            .clone_from(&entry);
+    /// Makes every change to the entries durable
+    pub(crate) fn flush(&self) -> Result<(), BlobProviderError> {
+        self.file.write_at(self.entries.as_bytes(), 0)?;
+        self.file.fsync()?;
 
         Ok(())
     }
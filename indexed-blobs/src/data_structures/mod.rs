@@ -1,2 +1,15 @@
+pub mod bk_tree;
+pub mod blob_cache;
+pub mod blob_index;
+pub mod chunk_digest_idx;
+pub mod chunk_idx;
+pub mod content_idx;
 pub mod fd_pool;
+pub mod key_filter;
+pub mod keyed_sidecar;
 pub mod mmap_midx;
+pub mod perceptual_hash_idx;
+pub mod placeholder_idx;
+pub mod quota_tracker;
+pub mod secondary_idx;
+pub mod segmented_map;
@@ -0,0 +1,18 @@
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::data_structures::keyed_sidecar::{KeyedSidecar, SidecarValue};
+
+/// 64-bit perceptual hash of every thumbnail that was hashed so far, so finding
+/// duplicates only has to read thumbnails written since the last search. A hash is
+/// dropped when its key's blob is replaced or deleted.
+pub(crate) type PerceptualHashIdx = KeyedSidecar<u64>;
+
+impl SidecarValue for u64 {
+    fn as_record_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn from_record_bytes(bytes: &[u8]) -> Option<Self> {
+        u64::read_from_bytes(bytes).ok()
+    }
+}
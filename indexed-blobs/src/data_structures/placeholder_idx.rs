@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use crate::{
+    data_structures::keyed_sidecar::{KeyedSidecar, SidecarValue},
+    err_type::BlobProviderError,
+};
+
+/// Longer placeholders are rejected, a BlurHash of the most detail takes 164 bytes
+pub(crate) const MAX_PLACEHOLDER_LEN: usize = 255;
+
+/// Tiny placeholder of every key that was given one, e.g. the BlurHash of its
+/// thumbnail, which the grid renders until the thumbnail itself is read
+pub(crate) type PlaceholderIdx = KeyedSidecar<Arc<str>>;
+
+impl SidecarValue for Arc<str> {
+    fn as_record_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn from_record_bytes(bytes: &[u8]) -> Option<Self> {
+        std::str::from_utf8(bytes).ok().map(Arc::from)
+    }
+
+    fn validate(&self) -> Result<(), BlobProviderError> {
+        match self.len() > MAX_PLACEHOLDER_LEN {
+            true => Err(BlobProviderError::InvalidThumbnail(format!(
+                "placeholder of {} bytes",
+                self.len()
+            ))),
+            false => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    data_structures::chunk_idx::{BlobKey, BlobLocation, IdxEntry},
+    err_type::BlobProviderError,
+    storage::{OpenMode, Storage, StorageFile, read_all, write_atomically},
+};
+
+struct TrackedBlob {
+    last_used: u64,
+    /// Where every live generation of the key is stored
+    locations: Vec<BlobLocation>,
+}
+
+struct QuotaState {
+    blobs: HashMap<BlobKey, TrackedBlob>,
+    /// Least recently used first
+    recency: BTreeMap<u64, BlobKey>,
+    next_tick: u64,
+    /// Number of tracked keys referencing every location, so deduplicated bytes are
+    /// only counted once
+    ref_counts: HashMap<BlobLocation, u32>,
+    live_bytes: u64,
+    /// Bytes of `.dat` files no live blob references anymore, until compaction
+    /// reclaims them
+    reclaimable_bytes: u64,
+    pinned: HashSet<BlobKey>,
+}
+
+impl QuotaState {
+    fn touch(&mut self, key: &BlobKey) {
+        let tick = self.next_tick;
+        let Some(blob) = self.blobs.get_mut(key) else {
+            return;
+        };
+
+        self.recency.remove(&blob.last_used);
+        self.recency.insert(tick, *key);
+        blob.last_used = tick;
+        self.next_tick += 1;
+    }
+
+    fn acquire(&mut self, locations: &[BlobLocation]) {
+        for location in locations {
+            let ref_count = self.ref_counts.entry(*location).or_insert(0);
+            if *ref_count == 0 {
+                self.live_bytes += location.len as u64;
+            }
+            *ref_count += 1;
+        }
+    }
+
+    fn release(&mut self, locations: &[BlobLocation]) {
+        for location in locations {
+            let Some(ref_count) = self.ref_counts.get_mut(location) else {
+                continue;
+            };
+
+            *ref_count -= 1;
+            if *ref_count == 0 {
+                self.ref_counts.remove(location);
+                self.live_bytes -= location.len as u64;
+                if !location.inline {
+                    self.reclaimable_bytes += location.len as u64;
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &BlobKey) {
+        if let Some(blob) = self.blobs.remove(key) {
+            self.recency.remove(&blob.last_used);
+            self.release(&blob.locations);
+        }
+    }
+
+    fn insert(&mut self, key: BlobKey, locations: Vec<BlobLocation>) {
+        // Acquired first, so bytes the key keeps aren't counted as released
+        self.acquire(&locations);
+        self.remove(&key);
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.recency.insert(tick, key);
+        self.blobs.insert(
+            key,
+            TrackedBlob {
+                last_used: tick,
+                locations,
+            },
+        );
+    }
+}
+
+/// Access recency and size of every live blob, used to evict least recently used
+/// blobs once the provider holds more than `quota_bytes`. Bytes shared by
+/// deduplicated keys count once.
+///
+/// Recency lives in memory only. On open, blobs are ordered by where they were
+/// written, which is the order they were last put in. Pins and the eviction log are
+/// persisted next to the midx.
+///
+/// Replaced and evicted bytes stay in the `.dat` files until compaction, which is
+/// due once they take up more than the quota as well.
+pub(crate) struct QuotaTracker {
+    quota_bytes: u64,
+    storage: Arc<dyn Storage>,
+    pins_path: PathBuf,
+    eviction_log: Mutex<Box<dyn StorageFile>>,
+    state: Mutex<QuotaState>,
+}
+
+impl QuotaTracker {
+    /// `dat_bytes` is the total size of the `.dat` files
+    pub(crate) fn open(
+        storage: Arc<dyn Storage>,
+        quota_bytes: u64,
+        pins_path: &Path,
+        eviction_log_path: &Path,
+        live_entries: &[IdxEntry],
+        dat_bytes: u64,
+    ) -> Result<Self, BlobProviderError> {
+        let mut live_entries = live_entries.to_vec();
+        live_entries.sort_unstable_by_key(|entry| (entry.chunk, entry.offset));
+
+        let mut state = QuotaState {
+            blobs: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_tick: 0,
+            ref_counts: HashMap::new(),
+            live_bytes: 0,
+            reclaimable_bytes: 0,
+            pinned: read_keys(storage.as_ref(), pins_path)?
+                .into_iter()
+                .collect(),
+        };
+        // Generations of a key are accounted together
+        for entry in live_entries {
+            let mut locations = state
+                .blobs
+                .get(&entry.key)
+                .map_or_else(Vec::new, |blob| blob.locations.clone());
+            locations.push(entry.location());
+            state.insert(entry.key, locations);
+        }
+        let live_dat_bytes = state
+            .ref_counts
+            .keys()
+            .filter(|location| !location.inline)
+            .map(|location| location.len as u64)
+            .sum::<u64>();
+        state.reclaimable_bytes = dat_bytes.saturating_sub(live_dat_bytes);
+
+        let eviction_log = storage.open(eviction_log_path, OpenMode::ReadWrite)?;
+
+        Ok(Self {
+            quota_bytes,
+            storage,
+            pins_path: pins_path.to_path_buf(),
+            eviction_log: Mutex::new(eviction_log),
+            state: Mutex::new(state),
+        })
+    }
+
+    pub(crate) fn touch(&self, key: &BlobKey) -> Result<(), BlobProviderError> {
+        self.state.lock()?.touch(key);
+        Ok(())
+    }
+
+    pub(crate) fn record_write(
+        &self,
+        key: BlobKey,
+        locations: Vec<BlobLocation>,
+    ) -> Result<(), BlobProviderError> {
+        self.state.lock()?.insert(key, locations);
+        Ok(())
+    }
+
+    /// Updates the locations of `key` without counting it as a use, e.g. after some
+    /// of its generations were pruned
+    pub(crate) fn record_resize(
+        &self,
+        key: &BlobKey,
+        locations: Vec<BlobLocation>,
+    ) -> Result<(), BlobProviderError> {
+        let mut state = self.state.lock()?;
+
+        if locations.is_empty() {
+            state.remove(key);
+        } else if let Some(blob) = state.blobs.get_mut(key) {
+            let previous = std::mem::replace(&mut blob.locations, locations.clone());
+            state.acquire(&locations);
+            state.release(&previous);
+        }
+
+        Ok(())
+    }
+
+    /// Points every key at the locations compaction moved its bytes to, keeping
+    /// their recency
+    pub(crate) fn relocate(&self, live_entries: &[IdxEntry]) -> Result<(), BlobProviderError> {
+        let mut locations: HashMap<BlobKey, Vec<BlobLocation>> = HashMap::new();
+        for entry in live_entries {
+            locations
+                .entry(entry.key)
+                .or_default()
+                .push(entry.location());
+        }
+
+        let mut guard = self.state.lock()?;
+        let state = &mut *guard;
+
+        state.ref_counts.clear();
+        state.live_bytes = 0;
+        state.reclaimable_bytes = 0;
+        for (key, blob) in state.blobs.iter_mut() {
+            blob.locations = locations.remove(key).unwrap_or_default();
+        }
+
+        let relocated = state
+            .blobs
+            .values()
+            .flat_map(|blob| blob.locations.iter().copied())
+            .collect::<Vec<_>>();
+        state.acquire(&relocated);
+
+        Ok(())
+    }
+
+    /// Whether the bytes compaction would reclaim take up more than the quota
+    pub(crate) fn is_compaction_due(&self) -> Result<bool, BlobProviderError> {
+        Ok(self.state.lock()?.reclaimable_bytes > self.quota_bytes)
+    }
+
+    pub(crate) fn record_delete(&self, key: &BlobKey) -> Result<(), BlobProviderError> {
+        self.state.lock()?.remove(key);
+        Ok(())
+    }
+
+    /// Least recently used unpinned keys that have to go to get back under the quota.
+    /// `keep` is never picked, so blobs that were just written survive their own put.
+    /// Evicting a key only frees the bytes no remaining key shares.
+    pub(crate) fn pick_victims(
+        &self,
+        keep: &HashSet<BlobKey>,
+    ) -> Result<Vec<BlobKey>, BlobProviderError> {
+        let state = self.state.lock()?;
+        let mut live_bytes = state.live_bytes;
+        let mut released: HashMap<BlobLocation, u32> = HashMap::new();
+        let mut victims = Vec::new();
+
+        for key in state.recency.values() {
+            if live_bytes <= self.quota_bytes {
+                break;
+            }
+
+            if keep.contains(key) || state.pinned.contains(key) {
+                continue;
+            }
+
+            let locations = state.blobs.get(key).map_or(&[][..], |blob| &blob.locations);
+            for location in locations {
+                let ref_count = released.entry(*location).or_insert(0);
+                *ref_count += 1;
+                if state.ref_counts.get(location) == Some(ref_count) {
+                    live_bytes -= location.len as u64;
+                }
+            }
+            victims.push(*key);
+        }
+
+        Ok(victims)
+    }
+
+    /// Called before the tombstones of `keys` are committed, so a crash can't lose
+    /// an eviction
+    pub(crate) fn log_evictions(&self, keys: &[BlobKey]) -> Result<(), BlobProviderError> {
+        let eviction_log = self.eviction_log.lock()?;
+        eviction_log.append(&keys.concat())?;
+        eviction_log.fsync()?;
+
+        Ok(())
+    }
+
+    /// Returns every key logged since the last call, once, and clears the log
+    pub(crate) fn take_evictions(&self) -> Result<Vec<BlobKey>, BlobProviderError> {
+        let eviction_log = self.eviction_log.lock()?;
+
+        let buffer = read_all(eviction_log.as_ref())?;
+        eviction_log.truncate(0)?;
+        eviction_log.fsync()?;
+
+        let mut seen = HashSet::new();
+        Ok(keys_from_bytes(&buffer)
+            .into_iter()
+            .filter(|key| seen.insert(*key))
+            .collect())
+    }
+
+    pub(crate) fn set_pinned(
+        &self,
+        keys: &[BlobKey],
+        pinned: bool,
+    ) -> Result<(), BlobProviderError> {
+        let mut state = self.state.lock()?;
+
+        for key in keys {
+            if pinned {
+                state.pinned.insert(*key);
+            } else {
+                state.pinned.remove(key);
+            }
+        }
+
+        write_keys(self.storage.as_ref(), &self.pins_path, state.pinned.iter())
+    }
+}
+
+fn keys_from_bytes(buffer: &[u8]) -> Vec<BlobKey> {
+    buffer
+        .chunks_exact(std::mem::size_of::<BlobKey>())
+        .filter_map(|key| key.try_into().ok())
+        .collect()
+}
+
+fn read_keys(storage: &dyn Storage, path: &Path) -> Result<Vec<BlobKey>, BlobProviderError> {
+    match storage.open(path, OpenMode::Read) {
+        Ok(file) => Ok(keys_from_bytes(&read_all(file.as_ref())?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_keys<'a>(
+    storage: &dyn Storage,
+    path: &Path,
+    keys: impl Iterator<Item = &'a BlobKey>,
+) -> Result<(), BlobProviderError> {
+    write_atomically(storage, path, &keys.copied().collect::<Vec<_>>().concat())?;
+
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::{
+    data_structures::{
+        chunk_idx::BlobKey,
+        keyed_sidecar::{CommitPoint, KeyedSidecar, SidecarValue, StagedChanges},
+    },
+    err_type::BlobProviderError,
+    storage::Storage,
+};
+
+impl SidecarValue for i64 {
+    fn as_record_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn from_record_bytes(bytes: &[u8]) -> Option<Self> {
+        i64::read_from_bytes(bytes).ok()
+    }
+}
+
+/// Sortable secondary key of every key that was given one, e.g. a capture timestamp,
+/// so keys can be listed in timeline order. The `.sidx` sidecar is a `KeyedSidecar`,
+/// kept in memory in secondary key order too.
+pub(crate) struct SecondaryIdx {
+    by_key: KeyedSidecar<i64>,
+    ordered: RwLock<BTreeSet<(i64, BlobKey)>>,
+}
+
+impl SecondaryIdx {
+    /// See `KeyedSidecar::open`
+    pub(crate) fn open(
+        storage: Arc<dyn Storage>,
+        path: &Path,
+        is_committed: impl Fn(CommitPoint) -> bool,
+    ) -> Result<Self, BlobProviderError> {
+        let by_key = KeyedSidecar::open(storage, path, is_committed)?;
+        let ordered = by_key
+            .all()?
+            .into_iter()
+            .map(|(key, secondary)| (secondary, key))
+            .collect();
+
+        Ok(Self {
+            by_key,
+            ordered: RwLock::new(ordered),
+        })
+    }
+
+    pub(crate) fn get(&self, key: &BlobKey) -> Result<Option<i64>, BlobProviderError> {
+        self.by_key.get(key)
+    }
+
+    /// Every key's secondary key
+    pub(crate) fn all(&self) -> Result<HashMap<BlobKey, i64>, BlobProviderError> {
+        self.by_key.all()
+    }
+
+    /// See `KeyedSidecar::stage`
+    pub(crate) fn stage(
+        &self,
+        changes: impl IntoIterator<Item = (BlobKey, Option<i64>)>,
+        commit: Option<CommitPoint>,
+    ) -> Result<StagedChanges<i64>, BlobProviderError> {
+        self.by_key.stage(changes, commit)
+    }
+
+    pub(crate) fn apply(&self, staged: StagedChanges<i64>) -> Result<(), BlobProviderError> {
+        let keys = staged.keys().copied().collect::<HashSet<_>>();
+
+        let mut ordered = self.ordered.write()?;
+        for key in &keys {
+            if let Some(previous) = self.get(key)? {
+                ordered.remove(&(previous, *key));
+            }
+        }
+
+        self.by_key.apply(staged)?;
+        for key in keys {
+            if let Some(secondary) = self.get(&key)? {
+                ordered.insert((secondary, key));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn discard(&self, staged: &StagedChanges<i64>) -> Result<(), BlobProviderError> {
+        self.by_key.discard(staged)
+    }
+
+    /// Keys whose secondary key is in `start..end`, ordered by secondary key and
+    /// then key, that `is_live` accepts, at most `limit` of them
+    pub(crate) fn range(
+        &self,
+        start: i64,
+        end: i64,
+        limit: usize,
+        is_live: impl Fn(&BlobKey) -> bool,
+    ) -> Result<Vec<BlobKey>, BlobProviderError> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .ordered
+            .read()?
+            .range((start, BlobKey::default())..(end, BlobKey::default()))
+            .map(|(_, key)| *key)
+            .filter(|key| is_live(key))
+            .take(limit)
+            .collect())
+    }
+
+    pub(crate) fn sync(&self) -> Result<(), BlobProviderError> {
+        self.by_key.sync()
+    }
+
+    /// See `KeyedSidecar::encode`
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, BlobProviderError> {
+        self.by_key.encode()
+    }
+
+    pub(crate) fn retain_live(
+        &self,
+        live_keys: &HashSet<BlobKey>,
+    ) -> Result<(), BlobProviderError> {
+        self.by_key.retain_live(live_keys)?;
+        self.ordered
+            .write()?
+            .retain(|(_, key)| live_keys.contains(key));
+
+        Ok(())
+    }
+}
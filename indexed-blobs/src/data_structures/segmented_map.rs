@@ -0,0 +1,93 @@
+use std::{collections::BTreeMap, ops::RangeBounds, sync::Arc};
+
+/// Map made of immutable sorted segments, oldest first, which index snapshots share
+/// instead of copying the whole map on every commit.
+///
+/// Every batch of inserts becomes a new segment that is merged into the segments
+/// before it while they are no larger, so a map of `n` entries has at most `log n`
+/// segments and every entry is copied `log n` times over its lifetime.
+#[derive(Clone)]
+pub(crate) struct SegmentedMap<K, V> {
+    segments: Vec<Arc<BTreeMap<K, V>>>,
+}
+
+impl<K, V> Default for SegmentedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            segments: Vec::new(),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> From<BTreeMap<K, V>> for SegmentedMap<K, V> {
+    fn from(entries: BTreeMap<K, V>) -> Self {
+        let mut map = Self::default();
+        map.extend(entries);
+        map
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> SegmentedMap<K, V> {
+    /// Adds `entries`, replacing the values of keys that are already present
+    pub(crate) fn extend(&mut self, entries: BTreeMap<K, V>) {
+        if entries.is_empty() {
+            return;
+        }
+
+        let mut segment = entries;
+        while self
+            .segments
+            .last()
+            .is_some_and(|last| last.len() <= segment.len())
+            && let Some(older) = self.segments.pop()
+        {
+            let mut merged = Arc::unwrap_or_clone(older);
+            merged.extend(segment);
+            segment = merged;
+        }
+
+        self.segments.push(Arc::new(segment));
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        self.extend(BTreeMap::from([(key, value)]));
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Option<&V> {
+        self.segments
+            .iter()
+            .rev()
+            .find_map(|segment| segment.get(key))
+    }
+
+    /// Entries within `range`, newest segment first, so a key is yielded once per
+    /// segment holding it and the first time is its current value
+    pub(crate) fn range<R: RangeBounds<K> + Clone>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = (&K, &V)> {
+        self.segments
+            .iter()
+            .rev()
+            .flat_map(move |segment| segment.range(range.clone()))
+    }
+
+    /// Every entry in the same order as `range`
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.range(..)
+    }
+
+    /// The current value of every key, sorted by key
+    pub(crate) fn merged(&self) -> BTreeMap<K, V> {
+        let mut merged = BTreeMap::new();
+        for segment in &self.segments {
+            merged.extend(
+                segment
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+
+        merged
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{
+    blob_provider::BlobProvider,
+    data_structures::{bk_tree::BkTree, chunk_idx::BlobKey},
+    err_type::BlobProviderError,
+    key::format_key,
+    perceptual_hash::perceptual_hash,
+};
+
+/// Root of `node` in the union-find `parents`, halving the path on the way
+fn find_root(parents: &mut [usize], mut node: usize) -> usize {
+    while parents[node] != node {
+        parents[node] = parents[parents[node]];
+        node = parents[node];
+    }
+
+    node
+}
+
+impl BlobProvider {
+    /// Hashes every thumbnail that doesn't have a perceptual hash yet. Thumbnails are
+    /// read and hashed without holding the index lock, like `backfill_placeholders`.
+    fn hash_missing_thumbnails(&self) -> Result<(), BlobProviderError> {
+        let existing = self.perceptual_hashes.all()?;
+        let keys = self
+            .snapshot
+            .load()
+            .live_entries()
+            .iter()
+            .map(|entry| entry.key)
+            .filter(|key| !existing.contains_key(key))
+            .collect::<BTreeSet<BlobKey>>();
+
+        for key in keys {
+            let Some((entry, thumbnail)) = self.read_thumbnail(&key)? else {
+                continue;
+            };
+            let hash = perceptual_hash(&thumbnail)?;
+
+            // Skipped if the blob was replaced or moved by compaction meanwhile, the
+            // next search picks it up again
+            let index = self.index.lock()?;
+            if index.snapshot().find_latest(&key) == Some(entry) {
+                index.perceptual_hashes.set(key, hash)?;
+            }
+        }
+
+        self.index.lock()?.perceptual_hashes.sync()
+    }
+}
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Groups of thumbnails whose perceptual hashes are at most `max_hamming` bits
+    /// apart, directly or through other thumbnails of the group. Each group holds at
+    /// least two keys, sorted, and groups are sorted by their first key. Blobs that
+    /// aren't YUV thumbnails are never part of a group.
+    ///
+    /// Thumbnails written since the last search are hashed first, the others reuse
+    /// their persisted hash. Neighbours are looked up in a BK-tree, so the search
+    /// doesn't compare every pair of thumbnails.
+    pub fn find_near_duplicates(
+        &self,
+        max_hamming: u32,
+    ) -> Result<Vec<Vec<String>>, BlobProviderError> {
+        self.hash_missing_thumbnails()?;
+
+        let snapshot = self.snapshot.load();
+        let mut keys_by_hash = BTreeMap::<u64, Vec<BlobKey>>::new();
+        for (key, hash) in self.perceptual_hashes.all()? {
+            if snapshot.find_latest(&key).is_some() {
+                keys_by_hash.entry(hash).or_default().push(key);
+            }
+        }
+
+        // Equal hashes share a node, so nodes are numbered like `keys_by_hash`
+        let mut tree = BkTree::default();
+        for hash in keys_by_hash.keys() {
+            tree.insert(*hash);
+        }
+
+        let mut parents = (0..keys_by_hash.len()).collect::<Vec<_>>();
+        for (node, hash) in keys_by_hash.keys().enumerate() {
+            for neighbour in tree.find_within(*hash, max_hamming.min(u64::BITS)) {
+                let (root, neighbour_root) = (
+                    find_root(&mut parents, node),
+                    find_root(&mut parents, neighbour),
+                );
+                parents[root.max(neighbour_root)] = root.min(neighbour_root);
+            }
+        }
+
+        let mut groups = HashMap::<usize, Vec<BlobKey>>::new();
+        for (node, keys) in keys_by_hash.into_values().enumerate() {
+            let root = find_root(&mut parents, node);
+            groups.entry(root).or_default().extend(keys);
+        }
+
+        let mut groups = groups
+            .into_values()
+            .filter(|keys| keys.len() > 1)
+            .map(|mut keys| {
+                keys.sort();
+                keys.iter().map(format_key).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        groups.sort();
+
+        Ok(groups)
+    }
+}
@@ -1,4 +1,63 @@
-use std::sync::PoisonError;
+use std::{error::Error, fmt, io, sync::PoisonError};
+
+use crate::storage::FileError;
+
+/// What an I/O error was caused by, so apps can tell e.g. a full disk from missing
+/// permissions
+#[derive(Debug, Copy, Clone, PartialEq, Eq, uniffi::Enum)]
+pub enum IoErrorKind {
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    StorageFull,
+    QuotaExceeded,
+    ReadOnlyFilesystem,
+    /// The file ended before everything was read
+    UnexpectedEof,
+    /// Only part of the buffer could be written
+    WriteZero,
+    Interrupted,
+    Other,
+}
+
+impl From<io::ErrorKind> for IoErrorKind {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::NotFound => IoErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied => IoErrorKind::PermissionDenied,
+            io::ErrorKind::AlreadyExists => IoErrorKind::AlreadyExists,
+            io::ErrorKind::StorageFull => IoErrorKind::StorageFull,
+            io::ErrorKind::QuotaExceeded => IoErrorKind::QuotaExceeded,
+            io::ErrorKind::ReadOnlyFilesystem => IoErrorKind::ReadOnlyFilesystem,
+            io::ErrorKind::UnexpectedEof => IoErrorKind::UnexpectedEof,
+            io::ErrorKind::WriteZero => IoErrorKind::WriteZero,
+            io::ErrorKind::Interrupted => IoErrorKind::Interrupted,
+            _ => IoErrorKind::Other,
+        }
+    }
+}
+
+/// The `io::Error` an `IoError` was caused by, including the `FileError` of storage
+/// backends. It crosses the FFI as its message.
+#[derive(Debug)]
+pub struct IoSource(pub io::Error);
+
+impl fmt::Display for IoSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for IoSource {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.source()
+    }
+}
+
+uniffi::custom_type!(IoSource, String, {
+    lower: |source| source.to_string(),
+    try_lift: |msg| Ok(IoSource(io::Error::other(msg))),
+});
 
 #[derive(Debug, uniffi::Error, thiserror::Error)]
 pub enum BlobProviderError {
@@ -8,11 +67,19 @@ pub enum BlobProviderError {
     #[error("Invalid blob file prefix")]
     InvalidPrefix,
 
-    #[error("I/O error {0}")]
-    IoError(String),
+    /// `path` and `offset` are known for errors of the storage backends, `offset`
+    /// only for reads and writes
+    #[error("I/O error {source}")]
+    IoError {
+        kind: IoErrorKind,
+        path: Option<String>,
+        offset: Option<u64>,
+        #[source]
+        source: IoSource,
+    },
 
-    #[error("Invalid blob file with name {0}")]
-    InvalidBlobFile(String),
+    #[error("Invalid blob file {path}: {reason}")]
+    InvalidBlobFile { path: String, reason: String },
 
     #[error("Uneven number of blob chunks")]
     UnevenBlobChunks,
@@ -26,13 +93,71 @@ pub enum BlobProviderError {
     #[error("File descriptor already exists: {0}")]
     FileDescriptorAlreadyExists(u64),
 
-    #[error("Invalid MIdx File")]
-    InvalidMIdx,
+    /// `chunk` is the midx entry that is invalid, if a single one is
+    #[error("Invalid MIdx File: {reason}")]
+    InvalidMIdx { chunk: Option<u64>, reason: String },
+
+    /// `offset` is the byte offset of the invalid record or payload in the `.idx`
+    /// file, or in the `.dat` file for a blob that is out of bounds
+    #[error("Invalid index file for chunk {chunk}: {reason}")]
+    InvalidIdx {
+        chunk: u64,
+        offset: Option<u64>,
+        reason: String,
+    },
+
+    #[error("Invalid blob key {0}")]
+    InvalidKey(String),
+
+    #[error("Blob of size {0} exceeds the maximum blob size")]
+    BlobTooLarge(u64),
+
+    #[error("Range at offset {0} with length {1} is out of bounds for blob of length {2}")]
+    RangeOutOfBounds(u64, u64, u64),
+
+    #[error("Blob writer was already committed or aborted")]
+    WriterClosed,
+
+    #[error("Blob writer expected {0} bytes but received {1}")]
+    UnexpectedBlobLength(u64, u64),
+
+    #[error("Blob provider was opened without a quota")]
+    QuotaDisabled,
+
+    #[error("Generation {0} was pruned, the oldest kept generation is {1}")]
+    GenerationPruned(u16, u16),
+
+    #[error("Invalid sprite sheet: {0}")]
+    InvalidSpriteSheet(String),
+
+    #[error("Tile {0} is out of bounds for sprite sheet of {1} tiles")]
+    TileOutOfBounds(u32, u32),
+
+    #[error("Invalid thumbnail: {0}")]
+    InvalidThumbnail(String),
+
+    #[error("Invalid image: {0}")]
+    InvalidImage(String),
+}
+
+impl From<io::Error> for BlobProviderError {
+    fn from(err: io::Error) -> Self {
+        let file = err
+            .get_ref()
+            .and_then(|source| source.downcast_ref::<FileError>());
+
+        BlobProviderError::IoError {
+            kind: IoErrorKind::from(err.kind()),
+            path: file.map(|file| file.path.to_string_lossy().into_owned()),
+            offset: file.and_then(|file| file.offset),
+            source: IoSource(err),
+        }
+    }
 }
 
-impl From<std::io::Error> for BlobProviderError {
-    fn from(err: std::io::Error) -> Self {
-        BlobProviderError::IoError(err.to_string())
+impl From<image::ImageError> for BlobProviderError {
+    fn from(err: image::ImageError) -> Self {
+        BlobProviderError::InvalidImage(err.to_string())
     }
 }
 
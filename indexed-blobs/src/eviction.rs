@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+
+use crate::{
+    blob_provider::BlobProvider,
+    data_structures::{
+        blob_index::BlobIndex,
+        chunk_idx::{BlobKey, IdxEntry},
+    },
+    err_type::BlobProviderError,
+    key::{format_key, parse_key},
+};
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Protects `keys` from quota eviction, including keys that aren't written yet
+    pub fn pin(&self, keys: Vec<String>) -> Result<(), BlobProviderError> {
+        self.set_pinned(keys, true)
+    }
+
+    pub fn unpin(&self, keys: Vec<String>) -> Result<(), BlobProviderError> {
+        self.set_pinned(keys, false)
+    }
+
+    /// Keys evicted to stay under the quota since the last call, so the caller can
+    /// update its own records. The log survives restarts until it is taken. Keys
+    /// that were put again since, or whose eviction was interrupted, are live and
+    /// left out.
+    pub fn take_evicted_keys(&self) -> Result<Vec<String>, BlobProviderError> {
+        let Some(quota) = &self.quota else {
+            return Ok(Vec::new());
+        };
+
+        // Evictions log their keys before committing, so the lock keeps a pending
+        // eviction from being taken while its key is still live
+        let index = self.index.lock()?;
+        let snapshot = index.snapshot();
+
+        Ok(quota
+            .take_evictions()?
+            .iter()
+            .filter(|key| snapshot.find_latest(key).is_none())
+            .map(format_key)
+            .collect())
+    }
+}
+
+impl BlobProvider {
+    fn set_pinned(&self, keys: Vec<String>, pinned: bool) -> Result<(), BlobProviderError> {
+        let quota = self
+            .quota
+            .as_ref()
+            .ok_or(BlobProviderError::QuotaDisabled)?;
+        let keys = keys
+            .iter()
+            .map(|key| parse_key(key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        quota.set_pinned(&keys, pinned)
+    }
+
+    pub(crate) fn touch_for_quota(&self, key: &BlobKey) -> Result<(), BlobProviderError> {
+        match &self.quota {
+            Some(quota) => quota.touch(key),
+            None => Ok(()),
+        }
+    }
+
+    /// Tracks committed writes to `written` and evicts least recently used blobs if
+    /// they pushed the provider over its quota, then compacts if the bytes they left
+    /// behind are due. A key's generations are accounted and evicted together. Must be
+    /// called with the index lock held.
+    pub(crate) fn enforce_quota_after_write(
+        &self,
+        index: &mut BlobIndex,
+        written: &[BlobKey],
+    ) -> Result<(), BlobProviderError> {
+        let Some(quota) = &self.quota else {
+            return Ok(());
+        };
+
+        let snapshot = index.snapshot();
+        for key in written {
+            quota.record_write(*key, snapshot.live_locations(key))?;
+        }
+
+        let keep = written.iter().copied().collect::<HashSet<_>>();
+        let victims = quota.pick_victims(&keep)?;
+        if !victims.is_empty() {
+            let tombstones = victims
+                .iter()
+                .flat_map(|victim| snapshot.live_generations(victim))
+                .map(|entry| IdxEntry::tombstone(entry.key, entry.generation))
+                .collect::<Vec<_>>();
+
+            quota.log_evictions(&victims)?;
+            self.ensure_active_chunk(index, 0)?;
+            index.commit(&tombstones)?;
+            self.finish_delete(&victims);
+        }
+
+        if quota.is_compaction_due()? {
+            self.compact_index(index)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,37 +1,458 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::{blob_provider::BlobProvider, err_type::BlobProviderError};
+use crate::{
+    blob_provider::{BlobProvider, BlobProviderOptions},
+    consts::{
+        BLOB_EXTENSION, CHUNK_DIGEST_EXTENSION, CONTENT_IDX_EXTENSION, EVICTION_LOG_EXTENSION,
+        GENERATION_FLOOR_EXTENSION, IDX_EXTENSION, KEY_FILTER_EXTENSION, MAX_BLOB_SIZE,
+        MIDX_EXTENSION, PERCEPTUAL_HASH_EXTENSION, PINS_EXTENSION, PLACEHOLDER_IDX_EXTENSION,
+        SECONDARY_IDX_EXTENSION, SEQUENCE_EXTENSION, TMP_EXTENSION,
+    },
+    data_structures::{
+        blob_index::{BlobIndex, IndexSnapshot, KeySidecars},
+        chunk_digest_idx::ChunkDigestIdx,
+        chunk_idx::{ActiveChunk, BlobKey, IdxEntry, SealedIdx},
+        content_idx::ContentIdx,
+        key_filter::KeyFilter,
+        keyed_sidecar::CommitPoint,
+        mmap_midx::open_or_create_midx,
+        perceptual_hash_idx::PerceptualHashIdx,
+        placeholder_idx::PlaceholderIdx,
+        secondary_idx::SecondaryIdx,
+    },
+    err_type::BlobProviderError,
+    generations::read_generation_floor,
+    group_commit::{GroupCommit, SequenceReservation},
+    storage::Storage,
+    yuv::YuvThumbnail,
+};
+
+pub(crate) fn chunk_file_path(
+    root_blob_dir: &Path,
+    blob_file_prefix: &str,
+    chunk: usize,
+    extension: &str,
+) -> PathBuf {
+    root_blob_dir.join(format!("{}_{}.{}", blob_file_prefix, chunk, extension))
+}
+
+pub(crate) fn staging_file_path(root_blob_dir: &Path, blob_file_prefix: &str, id: u64) -> PathBuf {
+    root_blob_dir.join(format!(
+        "{}_staging{}.{}",
+        blob_file_prefix, id, TMP_EXTENSION
+    ))
+}
+
+pub(crate) fn content_idx_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!("{}.{}", blob_file_prefix, CONTENT_IDX_EXTENSION))
+}
+
+pub(crate) fn pins_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!("{}.{}", blob_file_prefix, PINS_EXTENSION))
+}
+
+pub(crate) fn eviction_log_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!("{}.{}", blob_file_prefix, EVICTION_LOG_EXTENSION))
+}
+
+pub(crate) fn generation_floor_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!(
+        "{}.{}",
+        blob_file_prefix, GENERATION_FLOOR_EXTENSION
+    ))
+}
+
+pub(crate) fn key_filter_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!("{}.{}", blob_file_prefix, KEY_FILTER_EXTENSION))
+}
+
+pub(crate) fn secondary_idx_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!("{}.{}", blob_file_prefix, SECONDARY_IDX_EXTENSION))
+}
+
+pub(crate) fn placeholder_idx_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!(
+        "{}.{}",
+        blob_file_prefix, PLACEHOLDER_IDX_EXTENSION
+    ))
+}
+
+pub(crate) fn perceptual_hash_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!(
+        "{}.{}",
+        blob_file_prefix, PERCEPTUAL_HASH_EXTENSION
+    ))
+}
+
+pub(crate) fn chunk_digest_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!("{}.{}", blob_file_prefix, CHUNK_DIGEST_EXTENSION))
+}
+
+pub(crate) fn sequence_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!("{}.{}", blob_file_prefix, SEQUENCE_EXTENSION))
+}
+
+pub(crate) fn midx_file_path(root_blob_dir: &Path, blob_file_prefix: &str) -> PathBuf {
+    root_blob_dir.join(format!("{}.{}", blob_file_prefix, MIDX_EXTENSION))
+}
+
+/// Extensions of the files named `{prefix}.{extension}`
+const SIDECAR_EXTENSIONS: [&str; 11] = [
+    MIDX_EXTENSION,
+    CONTENT_IDX_EXTENSION,
+    PINS_EXTENSION,
+    EVICTION_LOG_EXTENSION,
+    GENERATION_FLOOR_EXTENSION,
+    KEY_FILTER_EXTENSION,
+    SEQUENCE_EXTENSION,
+    SECONDARY_IDX_EXTENSION,
+    PLACEHOLDER_IDX_EXTENSION,
+    PERCEPTUAL_HASH_EXTENSION,
+    CHUNK_DIGEST_EXTENSION,
+];
+
+fn is_number(digits: &str) -> bool {
+    !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Parses the chunk number out of a `{prefix}_{chunk}.idx` or `.dat` file name. Files
+/// of other providers never match, even if their prefix starts with `{prefix}_`.
+fn chunk_of_file_name(file_name: &str, blob_file_prefix: &str) -> Option<usize> {
+    let (chunk, extension) = file_name
+        .strip_prefix(blob_file_prefix)?
+        .strip_prefix('_')?
+        .split_once('.')?;
+
+    let is_chunk_file = extension == IDX_EXTENSION || extension == BLOB_EXTENSION;
+    match is_chunk_file && is_number(chunk) {
+        true => chunk.parse().ok(),
+        false => None,
+    }
+}
+
+/// Whether `file_name` is a temporary file of the provider of `blob_file_prefix`: the
+/// staging file of a blob writer, or the rewrite of one of its chunk or sidecar files
+fn is_own_tmp_file(file_name: &str, blob_file_prefix: &str) -> bool {
+    let Some(name) = file_name.strip_suffix(&format!(".{}", TMP_EXTENSION)) else {
+        return false;
+    };
+    let Some(rest) = name.strip_prefix(blob_file_prefix) else {
+        return false;
+    };
+
+    let is_staging = rest.strip_prefix("_staging").is_some_and(is_number);
+    let is_sidecar = rest
+        .strip_prefix('.')
+        .is_some_and(|extension| SIDECAR_EXTENSIONS.contains(&extension));
+
+    is_staging || is_sidecar || chunk_of_file_name(name, blob_file_prefix).is_some()
+}
 
 impl BlobProvider {
-    pub fn get_num_chunks(
-        root_blob_dir: PathBuf,
+    pub(crate) fn chunk_file_path(&self, chunk: usize, extension: &str) -> PathBuf {
+        chunk_file_path(
+            &self.root_blob_dir,
+            &self.blob_file_prefix,
+            chunk,
+            extension,
+        )
+    }
+
+    /// Opens the midx and every chunk it references, cleaning up whatever an
+    /// interrupted write or compaction left behind
+    pub(crate) fn load_blob_index(
+        storage: &Arc<dyn Storage>,
+        root_blob_dir: &Path,
         blob_file_prefix: &str,
-    ) -> Result<usize, BlobProviderError> {
-        let mut num_chunks = 0;
-
-        for file in std::fs::read_dir(root_blob_dir)? {
-            let file_path = file?.path();
-            let file_name = file_path
-                .file_name()
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default();
-
-            if !file_path.is_file() {
-                return Err(BlobProviderError::InvalidBlobFile(file_name.to_owned()));
+        options: &BlobProviderOptions,
+    ) -> Result<BlobIndex, BlobProviderError> {
+        let midx = open_or_create_midx(
+            storage.as_ref(),
+            &midx_file_path(root_blob_dir, blob_file_prefix),
+        )?;
+        let num_chunks = midx.entry_count();
+
+        Self::remove_stray_files(
+            storage.as_ref(),
+            root_blob_dir,
+            blob_file_prefix,
+            num_chunks,
+        )?;
+
+        let mut sealed = BTreeMap::new();
+        let mut active = None;
+
+        for chunk in 0..num_chunks {
+            let entry = midx[chunk];
+            let idx_path = chunk_file_path(root_blob_dir, blob_file_prefix, chunk, IDX_EXTENSION);
+            let dat_path = chunk_file_path(root_blob_dir, blob_file_prefix, chunk, BLOB_EXTENSION);
+
+            if entry.is_retired() {
+                remove_if_exists(storage.as_ref(), &idx_path)?;
+                remove_if_exists(storage.as_ref(), &dat_path)?;
+            } else if entry.is_sealed() {
+                sealed.insert(
+                    chunk,
+                    Arc::new(SealedIdx::open(
+                        storage.as_ref(),
+                        &idx_path,
+                        entry.num_entries as usize,
+                        chunk,
+                    )?),
+                );
+            } else if chunk + 1 == num_chunks {
+                active = Some(ActiveChunk::open(
+                    storage.as_ref(),
+                    chunk,
+                    &idx_path,
+                    &dat_path,
+                    entry.num_entries as usize,
+                )?);
+            } else {
+                // Chunks are always sealed before the next one is created
+                return Err(BlobProviderError::InvalidMIdx {
+                    chunk: Some(chunk as u64),
+                    reason: "unsealed chunk before the last one".to_owned(),
+                });
             }
+        }
+
+        // Every key in the active chunk was committed after the filter was last persisted
+        let filter = KeyFilter::open(
+            storage.clone(),
+            &key_filter_file_path(root_blob_dir, blob_file_prefix),
+            || {
+                sealed
+                    .values()
+                    .flat_map(|sealed| sealed.entries())
+                    .map(|entry| entry.key)
+                    .collect()
+            },
+        )?;
+        for (key, _) in active.iter().flat_map(|active| active.entries.iter()) {
+            filter.insert(&key.0);
+        }
+
+        let generation_floor = read_generation_floor(
+            storage.as_ref(),
+            &generation_floor_file_path(root_blob_dir, blob_file_prefix),
+        )?;
+        // Metadata written with a commit that never landed is dropped. Chunks are only
+        // sealed once every commit to them landed, and sealing merges their records,
+        // so commits to sealed chunks count as landed however many records they left.
+        let committed_records = (0..num_chunks)
+            .map(
+                |chunk| match midx[chunk].is_sealed() || midx[chunk].is_retired() {
+                    true => u32::MAX,
+                    false => midx[chunk].num_entries,
+                },
+            )
+            .collect::<Vec<_>>();
+        let is_committed = |commit: CommitPoint| {
+            committed_records
+                .get(commit.chunk as usize)
+                .is_some_and(|num_entries| *num_entries >= commit.num_records)
+        };
+        let mut index = BlobIndex::new(
+            midx,
+            sealed,
+            active,
+            generation_floor,
+            filter,
+            KeySidecars {
+                secondary: SecondaryIdx::open(
+                    storage.clone(),
+                    &secondary_idx_file_path(root_blob_dir, blob_file_prefix),
+                    is_committed,
+                )?,
+                placeholders: PlaceholderIdx::open(
+                    storage.clone(),
+                    &placeholder_idx_file_path(root_blob_dir, blob_file_prefix),
+                    is_committed,
+                )?,
+                perceptual_hashes: PerceptualHashIdx::open(
+                    storage.clone(),
+                    &perceptual_hash_file_path(root_blob_dir, blob_file_prefix),
+                    is_committed,
+                )?,
+            },
+            ChunkDigestIdx::open(
+                storage.clone(),
+                &chunk_digest_file_path(root_blob_dir, blob_file_prefix),
+            )?,
+        );
+
+        index.group_commit = GroupCommit::from_options(options);
+        let sequence_reservation = SequenceReservation::open(
+            storage.clone(),
+            &sequence_file_path(root_blob_dir, blob_file_prefix),
+        )?;
+        index.sequence = sequence_reservation.reserved();
+        index.durable_sequence = index.sequence;
+        index.sequence_reservation = Some(sequence_reservation);
+
+        let live_entries = index.snapshot().live_entries();
+        index.retain_live_metadata(&live_entries.iter().map(|entry| entry.key).collect())?;
+        index
+            .digests
+            .retain_sealed(|chunk| index.sealed.contains_key(&chunk))?;
 
-            if !file_name.starts_with(blob_file_prefix) {
-                return Err(BlobProviderError::InvalidBlobFile(file_name.to_owned()));
+        if options.deduplicate {
+            index.content = Some(ContentIdx::open(
+                storage.clone(),
+                &content_idx_file_path(root_blob_dir, blob_file_prefix),
+                &live_entries,
+            )?);
+        }
+
+        Ok(index)
+    }
+
+    /// Removes temporary files and chunk files the midx doesn't know about. Only file
+    /// names this provider creates match, so providers whose prefix starts with this
+    /// one's can share the directory.
+    fn remove_stray_files(
+        storage: &dyn Storage,
+        root_blob_dir: &Path,
+        blob_file_prefix: &str,
+        num_chunks: usize,
+    ) -> Result<(), BlobProviderError> {
+        for file_name in storage.list(root_blob_dir)? {
+            // Uncommitted blob writers and interrupted rewrites
+            let is_tmp = is_own_tmp_file(&file_name, blob_file_prefix);
+            let is_unknown_chunk = chunk_of_file_name(&file_name, blob_file_prefix)
+                .is_some_and(|chunk| chunk >= num_chunks);
+
+            if is_tmp || is_unknown_chunk {
+                storage.remove(&root_blob_dir.join(&file_name))?;
             }
+        }
+
+        Ok(())
+    }
+
+    /// Runs `read` against the latest index snapshot without blocking writers. If it
+    /// fails after a newer snapshot was published, e.g. because compaction deleted
+    /// the chunk it was reading, it is retried against the newer one.
+    pub(crate) fn with_snapshot<T>(
+        &self,
+        read: impl Fn(&Arc<IndexSnapshot>) -> Result<T, BlobProviderError>,
+    ) -> Result<T, BlobProviderError> {
+        loop {
+            let snapshot = self.snapshot.load_full();
 
-            num_chunks += 1;
+            match read(&snapshot) {
+                Err(_) if !Arc::ptr_eq(&snapshot, &self.snapshot.load()) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Caches `data`, read through `snapshot`. A writer may have replaced the blob and
+    /// invalidated its cache entry before the insert, so the entry is dropped again
+    /// unless `snapshot` is still the latest one.
+    pub(crate) fn cache_read(
+        &self,
+        snapshot: &Arc<IndexSnapshot>,
+        key: BlobKey,
+        data: &[u8],
+    ) -> Result<(), BlobProviderError> {
+        let Some(cache) = &self.cache else {
+            return Ok(());
+        };
+
+        cache.insert((self.namespace, key), data.to_vec())?;
+        if !Arc::ptr_eq(snapshot, &self.snapshot.load()) {
+            cache.invalidate(&(self.namespace, key))?;
         }
 
-        if num_chunks % 2 != 0 {
-            return Err(BlobProviderError::UnevenBlobChunks);
+        Ok(())
+    }
+
+    /// Reads `len` bytes at `offset` of the blob `entry` points at. Inline blobs are
+    /// served straight from the index without touching a `.dat` file.
+    pub(crate) fn read_entry_bytes(
+        &self,
+        index: &IndexSnapshot,
+        entry: &IdxEntry,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, BlobProviderError> {
+        if !entry.is_inline() {
+            return self.read_blob_bytes(entry.chunk as usize, entry.offset as u64 + offset, len);
         }
 
-        Ok(num_chunks / 2)
+        index
+            .inline_bytes(entry)?
+            .get(offset as usize..(offset + len) as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| BlobProviderError::InvalidIdx {
+                chunk: entry.chunk as u64,
+                offset: Some(entry.offset as u64),
+                reason: format!("range {offset}+{len} is out of bounds of the inline blob"),
+            })
+    }
+
+    /// Reads the latest blob of `key` as a YUV thumbnail, along with the record it was
+    /// read through. `None` if the key holds no blob or the blob isn't a thumbnail.
+    pub(crate) fn read_thumbnail(
+        &self,
+        key: &BlobKey,
+    ) -> Result<Option<(IdxEntry, YuvThumbnail)>, BlobProviderError> {
+        let read = self.with_snapshot(|snapshot| {
+            let Some(entry) = snapshot.find_latest(key) else {
+                return Ok(None);
+            };
+
+            let data = self.read_entry_bytes(snapshot, &entry, 0, entry.len as u64)?;
+            Ok(Some((entry, data)))
+        })?;
+        let Some((entry, data)) = read else {
+            return Ok(None);
+        };
+
+        match YuvThumbnail::decode(&data) {
+            Ok(thumbnail) => Ok(Some((entry, thumbnail))),
+            Err(BlobProviderError::InvalidThumbnail(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads `len` bytes at `offset` of a chunk's `.dat` file through the fd pool
+    pub(crate) fn read_blob_bytes(
+        &self,
+        chunk: usize,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, BlobProviderError> {
+        // Records come from disk, don't let a corrupt one allocate unbounded memory
+        if len > MAX_BLOB_SIZE as u64 {
+            return Err(BlobProviderError::InvalidIdx {
+                chunk: chunk as u64,
+                offset: Some(offset),
+                reason: format!("blob of {len} bytes exceeds the maximum blob size"),
+            });
+        }
+
+        self.dat_fd_pool.blocking_read(
+            (self.namespace, chunk),
+            &self.chunk_file_path(chunk, BLOB_EXTENSION),
+            offset,
+            len,
+        )
+    }
+}
+
+pub(crate) fn remove_if_exists(
+    storage: &dyn Storage,
+    path: &Path,
+) -> Result<(), BlobProviderError> {
+    match storage.remove(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
     }
 }
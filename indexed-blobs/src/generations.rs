@@ -0,0 +1,163 @@
+use std::{collections::HashSet, path::Path};
+
+use crate::{
+    blob_provider::BlobProvider,
+    consts::MAX_BLOB_SIZE,
+    data_structures::{blob_index::BlobMetadata, chunk_idx::Generation, content_idx::hash_content},
+    err_type::BlobProviderError,
+    fs::generation_floor_file_path,
+    key::parse_key,
+    storage::{OpenMode, Storage, read_all, write_atomically},
+};
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Stores `data` as `generation` of `key`, next to its other generations, e.g. a
+    /// thumbnail in a newer format. `get` returns the highest generation of a key, and
+    /// its placeholder and perceptual hash are only dropped when that one is written.
+    pub fn put_version(
+        &self,
+        key: String,
+        generation: Generation,
+        data: Vec<u8>,
+    ) -> Result<(), BlobProviderError> {
+        let key = parse_key(&key)?;
+
+        if data.len() > MAX_BLOB_SIZE {
+            return Err(BlobProviderError::BlobTooLarge(data.len() as u64));
+        }
+
+        let inline = self.stores_inline(data.len() as u64);
+        let hash = (self.options.deduplicate && !inline).then(|| hash_content(&data));
+        let mut index = self.index.lock()?;
+
+        if generation < index.generation_floor {
+            return Err(BlobProviderError::GenerationPruned(
+                generation,
+                index.generation_floor,
+            ));
+        }
+
+        if inline {
+            return self.commit_inline_blob(
+                &mut index,
+                key,
+                generation,
+                BlobMetadata::default(),
+                &data,
+            );
+        }
+
+        self.commit_blob(
+            &mut index,
+            key,
+            generation,
+            BlobMetadata::default(),
+            data.len() as u64,
+            hash,
+            |active| active.append_data(&data),
+        )
+    }
+
+    /// Reads `generation` of `key`, whether or not it is the latest. The read cache
+    /// only holds latest generations, so this always goes to disk.
+    pub fn get_version(
+        &self,
+        key: String,
+        generation: Generation,
+    ) -> Result<Option<Vec<u8>>, BlobProviderError> {
+        let key = parse_key(&key)?;
+        self.touch_for_quota(&key)?;
+
+        self.with_snapshot(|snapshot| {
+            let Some(entry) = snapshot.find_live(&key, generation) else {
+                return Ok(None);
+            };
+
+            Ok(Some(self.read_entry_bytes(
+                snapshot,
+                &entry,
+                0,
+                entry.len as u64,
+            )?))
+        })
+    }
+
+    /// Drops every generation older than `generation`, including keys that have no
+    /// newer one, and rejects writes to them from now on. The bytes are reclaimed by
+    /// `compact`. Returns the number of dropped blobs.
+    pub fn prune_older_than(&self, generation: Generation) -> Result<u64, BlobProviderError> {
+        let mut index = self.index.lock()?;
+
+        if generation <= index.generation_floor {
+            return Ok(0);
+        }
+
+        let pruned = index
+            .snapshot()
+            .live_entries()
+            .into_iter()
+            .filter(|entry| entry.generation < generation)
+            .collect::<Vec<_>>();
+
+        // Pruning takes effect atomically once the new floor is on disk
+        write_generation_floor(
+            self.storage.as_ref(),
+            &generation_floor_file_path(&self.root_blob_dir, &self.blob_file_prefix),
+            generation,
+        )?;
+        index.generation_floor = generation;
+        index.publish();
+
+        let snapshot = index.snapshot();
+        let live_entries = snapshot.live_entries();
+        if let Some(content) = index.content.as_mut() {
+            content.recount(&live_entries)?;
+        }
+
+        let pruned_keys = pruned.iter().map(|entry| entry.key).collect::<HashSet<_>>();
+        for key in &pruned_keys {
+            self.invalidate_cached(key)?;
+
+            if let Some(quota) = &self.quota {
+                quota.record_resize(key, snapshot.live_locations(key))?;
+            }
+        }
+
+        Ok(pruned.len() as u64)
+    }
+}
+
+/// Reads the oldest generation that is still kept, 0 if nothing was pruned yet
+pub(crate) fn read_generation_floor(
+    storage: &dyn Storage,
+    path: &Path,
+) -> Result<Generation, BlobProviderError> {
+    let buffer = match storage.open(path, OpenMode::Read) {
+        Ok(file) => read_all(file.as_ref())?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    buffer
+        .try_into()
+        .map(Generation::from_le_bytes)
+        .map_err(|buffer: Vec<u8>| BlobProviderError::InvalidBlobFile {
+            path: path.to_string_lossy().into_owned(),
+            reason: format!(
+                "expected {} bytes, found {}",
+                size_of::<Generation>(),
+                buffer.len()
+            ),
+        })
+}
+
+fn write_generation_floor(
+    storage: &dyn Storage,
+    path: &Path,
+    generation: Generation,
+) -> Result<(), BlobProviderError> {
+    write_atomically(storage, path, &generation.to_le_bytes())?;
+
+    Ok(())
+}
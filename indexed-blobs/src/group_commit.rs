@@ -0,0 +1,193 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    blob_provider::{BlobProvider, BlobProviderOptions},
+    data_structures::blob_index::BlobIndex,
+    err_type::BlobProviderError,
+    storage::{OpenMode, Storage, read_all, write_atomically},
+};
+
+/// Sequence numbers reserved at once, so only every so many commits persist one
+const SEQUENCE_RESERVATION: u64 = 1024;
+
+/// Writes staged in the active chunk whose records aren't committed in the midx yet
+pub(crate) struct GroupCommit {
+    max_writes: u64,
+    max_delay: Duration,
+    staged_writes: u64,
+    staged_since: Option<Instant>,
+}
+
+impl GroupCommit {
+    /// `None` when every write is committed on its own
+    pub(crate) fn from_options(options: &BlobProviderOptions) -> Option<Self> {
+        if options.group_commit_max_writes <= 1 && options.group_commit_max_delay_ms == 0 {
+            return None;
+        }
+
+        Some(Self {
+            max_writes: options.group_commit_max_writes,
+            max_delay: Duration::from_millis(options.group_commit_max_delay_ms),
+            staged_writes: 0,
+            staged_since: None,
+        })
+    }
+
+    /// Whether the staged writes plus `incoming` more have to be committed now
+    pub(crate) fn is_due(&self, incoming: u64) -> bool {
+        let too_many = self.max_writes > 0 && self.staged_writes + incoming >= self.max_writes;
+        too_many || self.delay_left().is_some_and(|left| left.is_zero())
+    }
+
+    /// Time until the oldest staged write is due, `None` when nothing is staged or
+    /// there is no time limit
+    fn delay_left(&self) -> Option<Duration> {
+        let since = self.staged_since.filter(|_| !self.max_delay.is_zero())?;
+        Some(self.max_delay.saturating_sub(since.elapsed()))
+    }
+
+    pub(crate) fn stage(&mut self) {
+        self.staged_writes += 1;
+        self.staged_since.get_or_insert_with(Instant::now);
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.staged_writes = 0;
+        self.staged_since = None;
+    }
+}
+
+/// Highest sequence number commits may take, persisted before any commit takes it.
+/// A restart counts on from there, so sequence numbers never repeat, skipping the
+/// ones that weren't taken.
+pub(crate) struct SequenceReservation {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    reserved: u64,
+}
+
+impl SequenceReservation {
+    pub(crate) fn open(storage: Arc<dyn Storage>, path: &Path) -> Result<Self, BlobProviderError> {
+        let reserved = read_sequence(storage.as_ref(), path)?;
+
+        Ok(Self {
+            storage,
+            path: path.to_path_buf(),
+            reserved,
+        })
+    }
+
+    /// Every sequence number up to this one may have been taken before the restart
+    pub(crate) fn reserved(&self) -> u64 {
+        self.reserved
+    }
+
+    /// Makes sure `sequence` is reserved, ahead of the commit that takes it
+    pub(crate) fn reserve(&mut self, sequence: u64) -> Result<(), BlobProviderError> {
+        if sequence <= self.reserved {
+            return Ok(());
+        }
+
+        let reserved = sequence + SEQUENCE_RESERVATION - 1;
+        write_sequence(self.storage.as_ref(), &self.path, reserved)?;
+        self.reserved = reserved;
+
+        Ok(())
+    }
+}
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Commits every staged write and returns the durable sequence number, which
+    /// grows with every index commit and never goes backwards, also across restarts.
+    /// Callers can store it along with their own saves to tell which writes made it
+    /// to disk.
+    pub fn flush(&self) -> Result<u64, BlobProviderError> {
+        let mut index = self.index.lock()?;
+
+        index.commit_staged()?;
+
+        Ok(index.durable_sequence)
+    }
+
+    /// Sequence number of the last write that is durable. After a restart it starts
+    /// out past every number handed out before.
+    pub fn durable_sequence(&self) -> Result<u64, BlobProviderError> {
+        Ok(self.index.lock()?.durable_sequence)
+    }
+}
+
+/// Commits staged writes once they waited `max_delay`, even if no further write comes
+/// along. Stops once the provider is dropped.
+pub(crate) fn spawn_group_committer(
+    index: Weak<Mutex<BlobIndex>>,
+    max_delay: Duration,
+) -> Result<(), BlobProviderError> {
+    std::thread::Builder::new()
+        .name("blob-group-commit".to_owned())
+        .spawn(move || {
+            loop {
+                let wait = {
+                    let Some(index) = index.upgrade() else {
+                        return;
+                    };
+                    let Ok(mut index) = index.lock() else {
+                        return;
+                    };
+
+                    match index
+                        .group_commit
+                        .as_ref()
+                        .and_then(GroupCommit::delay_left)
+                    {
+                        Some(left) if left.is_zero() => {
+                            // A failure is reported by the next write or flush, which retry
+                            let _ = index.commit_staged();
+                            max_delay
+                        }
+                        Some(left) => left,
+                        None => max_delay,
+                    }
+                };
+
+                std::thread::sleep(wait);
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Reads the sequence number reserved last, 0 if there was none
+fn read_sequence(storage: &dyn Storage, path: &Path) -> Result<u64, BlobProviderError> {
+    let buffer = match storage.open(path, OpenMode::Read) {
+        Ok(file) => read_all(file.as_ref())?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    buffer
+        .try_into()
+        .map(u64::from_le_bytes)
+        .map_err(|buffer: Vec<u8>| BlobProviderError::InvalidBlobFile {
+            path: path.to_string_lossy().into_owned(),
+            reason: format!(
+                "expected {} bytes, found {}",
+                size_of::<u64>(),
+                buffer.len()
+            ),
+        })
+}
+
+fn write_sequence(
+    storage: &dyn Storage,
+    path: &Path,
+    sequence: u64,
+) -> Result<(), BlobProviderError> {
+    write_atomically(storage, path, &sequence.to_le_bytes())?;
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+use crate::{data_structures::chunk_idx::BlobKey, err_type::BlobProviderError};
+
+/// Keys are the UUIDs of the assets the blobs belong to
+pub(crate) fn parse_key(key: &str) -> Result<BlobKey, BlobProviderError> {
+    uuid::Uuid::parse_str(key)
+        .map(|uuid| *uuid.as_bytes())
+        .map_err(|_| BlobProviderError::InvalidKey(key.to_owned()))
+}
+
+/// Formats a key the way Swift's `UUID.uuidString` does
+pub(crate) fn format_key(key: &BlobKey) -> String {
+    uuid::Uuid::from_bytes(*key)
+        .hyphenated()
+        .encode_upper(&mut uuid::Uuid::encode_buffer())
+        .to_owned()
+}
@@ -1,8 +1,33 @@
 uniffi::setup_scaffolding!();
 
+pub mod backup;
+pub mod batch;
 pub mod blob_provider;
+pub mod blob_store;
+pub mod blob_writer;
+pub mod blurhash;
 pub mod err_type;
+pub mod merkle;
+pub mod perceptual_hash;
+pub mod sprite_sheet;
+pub mod stats;
+#[cfg(feature = "test-support")]
+pub mod storage;
+pub mod thumbnail;
+pub mod yuv;
 
+mod compaction;
 mod consts;
+mod data_structures;
+mod duplicates;
+mod eviction;
 mod fs;
-mod data_structures;
\ No newline at end of file
+mod generations;
+mod group_commit;
+mod key;
+mod placeholder;
+mod prefetch;
+mod secondary;
+#[cfg(not(feature = "test-support"))]
+mod storage;
+mod write;
@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+
+use crate::{
+    blob_provider::BlobProvider,
+    data_structures::{
+        blob_index::IndexSnapshot,
+        chunk_digest_idx::ChunkDigestEntry,
+        chunk_idx::{IdxEntry, SealedIdx},
+    },
+    err_type::BlobProviderError,
+    key::format_key,
+};
+
+/// Summary of the records of a chunk, which another device holding the same records
+/// computes the same digest for
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct ChunkDigest {
+    pub chunk: u64,
+    pub num_records: u64,
+    /// Smallest key of the chunk's records, `None` for an empty chunk
+    pub first_key: Option<String>,
+    /// Largest key of the chunk's records, `None` for an empty chunk
+    pub last_key: Option<String>,
+    /// 32-byte BLAKE3 digest
+    pub digest: Vec<u8>,
+}
+
+impl From<&ChunkDigestEntry> for ChunkDigest {
+    fn from(entry: &ChunkDigestEntry) -> Self {
+        let key = |key| (entry.num_records > 0).then(|| format_key(key));
+
+        Self {
+            chunk: entry.chunk as u64,
+            num_records: entry.num_records as u64,
+            first_key: key(&entry.first_key),
+            last_key: key(&entry.last_key),
+            digest: entry.digest.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct ChunkDiff {
+    /// Local chunks whose records no remote chunk holds, oldest first
+    pub local_only: Vec<ChunkDigest>,
+    /// Remote chunks whose records no local chunk holds, in the order they were passed
+    pub remote_only: Vec<ChunkDigest>,
+}
+
+/// Root of a binary Merkle tree over `leaves`, where a node without a sibling is
+/// carried up to the next level as is. The root of no leaves is the hash of nothing.
+fn merkle_root_of(leaves: Vec<[u8; 32]>) -> [u8; 32] {
+    let mut level = leaves;
+    if level.is_empty() {
+        return *blake3::hash(&[]).as_bytes();
+    }
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => *blake3::Hasher::new()
+                    .update(left)
+                    .update(right)
+                    .finalize()
+                    .as_bytes(),
+                _ => pair[0],
+            })
+            .collect();
+    }
+
+    level[0]
+}
+
+/// Digest of `records` of `chunk`, sorted by key and generation: the key,
+/// generation and tombstone flag of every record, along with the length and BLAKE3
+/// hash of the blob `read` returns for it. Where the bytes are stored doesn't
+/// matter, so a chunk digests the same with or without deduplication and inline
+/// blobs.
+pub(crate) fn digest_records(
+    chunk: usize,
+    records: &[IdxEntry],
+    mut read: impl FnMut(&IdxEntry) -> Result<Vec<u8>, BlobProviderError>,
+) -> Result<ChunkDigestEntry, BlobProviderError> {
+    let mut hasher = blake3::Hasher::new();
+    for record in records {
+        hasher.update(&record.key);
+        hasher.update(&record.generation.to_le_bytes());
+
+        match record.is_tombstone() {
+            true => hasher.update(&[1]),
+            false => {
+                let data = read(record)?;
+                hasher
+                    .update(&[0])
+                    .update(&(data.len() as u64).to_le_bytes())
+                    .update(blake3::hash(&data).as_bytes())
+            }
+        };
+    }
+
+    Ok(ChunkDigestEntry {
+        chunk: chunk as u32,
+        num_records: records.len() as u32,
+        first_key: records.first().map_or([0; 16], |record| record.key),
+        last_key: records.last().map_or([0; 16], |record| record.key),
+        digest: *hasher.finalize().as_bytes(),
+    })
+}
+
+impl BlobProvider {
+    /// Digest of `records` of `chunk`, reading blobs through `snapshot`
+    pub(crate) fn digest_chunk(
+        &self,
+        snapshot: &IndexSnapshot,
+        chunk: usize,
+        records: &[IdxEntry],
+    ) -> Result<ChunkDigestEntry, BlobProviderError> {
+        digest_records(chunk, records, |record| {
+            self.read_entry_bytes(snapshot, record, 0, record.len as u64)
+        })
+    }
+
+    /// Digest of a sealed chunk of `snapshot`, the one computed when it was sealed if
+    /// there is one
+    pub(crate) fn sealed_digest(
+        &self,
+        snapshot: &IndexSnapshot,
+        chunk: usize,
+        sealed: &SealedIdx,
+    ) -> Result<ChunkDigestEntry, BlobProviderError> {
+        if let Some(digest) = self.digests.get(chunk)? {
+            return Ok(digest);
+        }
+
+        // Sealed before digests existed, or its digest was lost in a crash
+        let digest = self.digest_chunk(snapshot, chunk, sealed.entries())?;
+        self.digests.insert(digest)?;
+
+        Ok(digest)
+    }
+
+    /// Digests of every chunk of the latest snapshot, oldest first. Sealed chunks
+    /// reuse the digest computed when they were sealed, the active chunk is digested
+    /// on every call.
+    fn digest_chunks(&self) -> Result<Vec<ChunkDigestEntry>, BlobProviderError> {
+        self.with_snapshot(|snapshot| {
+            let mut digests = Vec::new();
+            for (chunk, sealed) in snapshot.sealed_chunks() {
+                digests.push(self.sealed_digest(snapshot, *chunk, sealed)?);
+            }
+
+            if let Some((chunk, records)) = snapshot.active_records() {
+                digests.push(self.digest_chunk(snapshot, chunk, &records)?);
+            }
+
+            Ok(digests)
+        })
+    }
+}
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Digest of every chunk, oldest first, to compare with another device's using
+    /// `diff_against`
+    pub fn chunk_digests(&self) -> Result<Vec<ChunkDigest>, BlobProviderError> {
+        Ok(self
+            .digest_chunks()?
+            .iter()
+            .map(ChunkDigest::from)
+            .collect())
+    }
+
+    /// Root of a Merkle tree over the digests of every chunk, oldest first. Two
+    /// providers holding the same records in the same chunks have the same root.
+    pub fn merkle_root(&self) -> Result<Vec<u8>, BlobProviderError> {
+        let leaves = self
+            .digest_chunks()?
+            .iter()
+            .map(|digest| digest.digest)
+            .collect();
+
+        Ok(merkle_root_of(leaves).to_vec())
+    }
+
+    /// Chunks that differ from `remote_digests`, the `chunk_digests` of another
+    /// device. Chunks are matched by digest, whatever their number, and the key range
+    /// of every unmatched chunk tells which keys need to be synced.
+    pub fn diff_against(
+        &self,
+        remote_digests: Vec<ChunkDigest>,
+    ) -> Result<ChunkDiff, BlobProviderError> {
+        let local_digests = self.chunk_digests()?;
+
+        let local = local_digests
+            .iter()
+            .map(|digest| digest.digest.as_slice())
+            .collect::<HashSet<_>>();
+        let remote = remote_digests
+            .iter()
+            .map(|digest| digest.digest.as_slice())
+            .collect::<HashSet<_>>();
+
+        Ok(ChunkDiff {
+            local_only: local_digests
+                .iter()
+                .filter(|digest| !remote.contains(digest.digest.as_slice()))
+                .cloned()
+                .collect(),
+            remote_only: remote_digests
+                .iter()
+                .filter(|digest| !local.contains(digest.digest.as_slice()))
+                .cloned()
+                .collect(),
+        })
+    }
+}
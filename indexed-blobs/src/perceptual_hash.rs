@@ -0,0 +1,125 @@
+use std::f32::consts::PI;
+
+use crate::{err_type::BlobProviderError, yuv::YuvThumbnail};
+
+/// Side of the luma grid the DCT of `perceptual_hash` runs on
+const DCT_SIZE: usize = 32;
+/// Side of the block of lowest DCT frequencies that make up the hash
+const HASH_SIZE: usize = 8;
+
+/// Averages the thumbnail's luma over a grid of `cols` by `rows` cells, each covering
+/// at least one pixel. Only luma is used, and since the hashes only compare samples
+/// with each other, the pixel range doesn't matter either.
+fn luma_grid(
+    thumbnail: &YuvThumbnail,
+    cols: usize,
+    rows: usize,
+) -> Result<Vec<f32>, BlobProviderError> {
+    // Validates the planes against the dimensions
+    thumbnail.plane_lens()?;
+
+    let (width, height) = (thumbnail.width as usize, thumbnail.height as usize);
+    let stride = thumbnail.y_stride as usize;
+    let span = |cell: usize, cells: usize, len: usize| {
+        let start = cell * len / cells;
+        start..(((cell + 1) * len / cells).max(start + 1)).min(len)
+    };
+
+    let mut grid = Vec::with_capacity(cols * rows);
+    for row in 0..rows {
+        let ys = span(row, rows, height);
+        for col in 0..cols {
+            let xs = span(col, cols, width);
+
+            let sum = ys
+                .clone()
+                .flat_map(|y| &thumbnail.planes[y * stride..][xs.clone()])
+                .map(|luma| *luma as u32)
+                .sum::<u32>();
+            grid.push(sum as f32 / (ys.len() * xs.len()) as f32);
+        }
+    }
+
+    Ok(grid)
+}
+
+/// DCT-based perceptual hash: the signs of the 8x8 lowest frequencies of the luma,
+/// relative to their median. Robust to rescaling, recompression and small edits,
+/// near-duplicates differ in a few bits.
+pub fn perceptual_hash(thumbnail: &YuvThumbnail) -> Result<u64, BlobProviderError> {
+    let grid = luma_grid(thumbnail, DCT_SIZE, DCT_SIZE)?;
+
+    // cos((2x + 1) * u * PI / 2N) of the frequencies that are kept
+    let cosines = (0..HASH_SIZE)
+        .map(|u| {
+            (0..DCT_SIZE)
+                .map(|x| ((2 * x + 1) as f32 * u as f32 * PI / (2 * DCT_SIZE) as f32).cos())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    // Rows first, then columns of the row transforms
+    let rows = grid
+        .chunks_exact(DCT_SIZE)
+        .map(|row| {
+            cosines
+                .iter()
+                .map(|cos| row.iter().zip(cos).map(|(luma, cos)| luma * cos).sum())
+                .collect::<Vec<f32>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut coefficients = Vec::with_capacity(HASH_SIZE * HASH_SIZE);
+    for cos in &cosines {
+        for u in 0..HASH_SIZE {
+            coefficients.push(
+                rows.iter()
+                    .zip(cos)
+                    .map(|(row, cos)| row[u] * cos)
+                    .sum::<f32>(),
+            );
+        }
+    }
+
+    // The DC coefficient is the average brightness, it would skew the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f32::total_cmp);
+    let median = sorted[sorted.len() / 2];
+
+    Ok(coefficients
+        .iter()
+        .enumerate()
+        .filter(|(_, coefficient)| **coefficient > median)
+        .fold(0, |hash, (bit, _)| hash | 1 << bit))
+}
+
+/// Gradient hash: whether the luma gets brighter between neighbouring cells of a 9x8
+/// grid. Cheaper than `perceptual_hash` but less robust to edits.
+pub fn difference_hash(thumbnail: &YuvThumbnail) -> Result<u64, BlobProviderError> {
+    let grid = luma_grid(thumbnail, HASH_SIZE + 1, HASH_SIZE)?;
+
+    Ok(grid
+        .chunks_exact(HASH_SIZE + 1)
+        .flat_map(|row| row.windows(2).map(|pair| pair[1] > pair[0]))
+        .enumerate()
+        .filter(|(_, brighter)| *brighter)
+        .fold(0, |hash, (bit, _)| hash | 1 << bit))
+}
+
+/// Number of bits two hashes differ in
+#[uniffi::export]
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// See `perceptual_hash`
+#[uniffi::export]
+pub fn perceptual_hash_of_thumbnail(thumbnail: YuvThumbnail) -> Result<u64, BlobProviderError> {
+    perceptual_hash(&thumbnail)
+}
+
+/// See `difference_hash`
+#[uniffi::export]
+pub fn difference_hash_of_thumbnail(thumbnail: YuvThumbnail) -> Result<u64, BlobProviderError> {
+    difference_hash(&thumbnail)
+}
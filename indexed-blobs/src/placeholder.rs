@@ -0,0 +1,62 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use crate::{
+    blob_provider::BlobProvider, blurhash::thumbnail_placeholder,
+    data_structures::chunk_idx::BlobKey, err_type::BlobProviderError, key::parse_key,
+};
+
+#[uniffi::export]
+impl BlobProvider {
+    /// BlurHash of the thumbnail stored under `key`, which the grid can render before
+    /// the thumbnail itself is read. `None` for blobs stored without one until
+    /// `backfill_placeholders` computes it.
+    pub fn get_placeholder(&self, key: String) -> Result<Option<String>, BlobProviderError> {
+        let key = parse_key(&key)?;
+        if self.snapshot.load().find_latest(&key).is_none() {
+            return Ok(None);
+        }
+
+        Ok(self
+            .placeholders
+            .get(&key)?
+            .map(|placeholder| placeholder.to_string()))
+    }
+
+    /// Computes the placeholder of every thumbnail that doesn't have one yet, e.g.
+    /// those stored before placeholders existed, returning how many were computed.
+    /// Blobs that aren't YUV thumbnails are skipped. Thumbnails are read and hashed
+    /// without holding the index lock, so writers are only blocked briefly.
+    pub fn backfill_placeholders(&self) -> Result<u64, BlobProviderError> {
+        let existing = self.placeholders.keys()?;
+        let keys = self
+            .snapshot
+            .load()
+            .live_entries()
+            .iter()
+            .map(|entry| entry.key)
+            .filter(|key| !existing.contains(key))
+            .collect::<BTreeSet<BlobKey>>();
+
+        let mut computed = 0;
+        for key in keys {
+            let Some((entry, thumbnail)) = self.read_thumbnail(&key)? else {
+                continue;
+            };
+            let placeholder = thumbnail_placeholder(&thumbnail)?;
+
+            // Skipped if the blob was replaced or moved by compaction meanwhile, the
+            // next backfill picks it up again
+            let index = self.index.lock()?;
+            if index.snapshot().find_latest(&key) == Some(entry)
+                && index.placeholders.get(&key)?.is_none()
+            {
+                index.placeholders.set(key, Arc::from(placeholder))?;
+                computed += 1;
+            }
+        }
+
+        self.index.lock()?.placeholders.sync()?;
+
+        Ok(computed)
+    }
+}
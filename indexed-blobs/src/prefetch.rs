@@ -0,0 +1,108 @@
+use std::sync::atomic::Ordering;
+
+use crate::{
+    blob_provider::BlobProvider, consts::BLOB_EXTENSION, data_structures::chunk_idx::BlobLocation,
+    err_type::BlobProviderError, key::parse_key,
+};
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Warms up the blobs of `keys`, in order, so the next reads don't wait on disk.
+    /// With the read cache enabled they are loaded into it, otherwise the kernel is
+    /// asked to read their ranges ahead.
+    ///
+    /// Every call supersedes the previous ones, which stop at the next key. Returns
+    /// the number of blobs warmed before finishing or being superseded.
+    pub fn prefetch(&self, keys: Vec<String>) -> Result<u64, BlobProviderError> {
+        let generation = self.prefetch_generation.fetch_add(1, Ordering::AcqRel) + 1;
+        let keys = keys
+            .iter()
+            .map(|key| parse_key(key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let is_current = || self.prefetch_generation.load(Ordering::Acquire) == generation;
+        let mut warmed = 0;
+
+        if let Some(cache) = &self.cache {
+            for key in keys {
+                if !is_current() {
+                    break;
+                }
+
+                if cache.contains(&(self.namespace, key))? {
+                    continue;
+                }
+
+                // Inline blobs are served from the index and never cached
+                let was_warmed = self.with_snapshot(|snapshot| {
+                    let Some(entry) = snapshot.find_latest(&key).filter(|e| !e.is_inline()) else {
+                        return Ok(false);
+                    };
+
+                    let data = self.read_entry_bytes(snapshot, &entry, 0, entry.len as u64)?;
+                    self.cache_read(snapshot, key, &data)?;
+                    Ok(true)
+                })?;
+                if was_warmed {
+                    warmed += 1;
+                }
+            }
+
+            return Ok(warmed);
+        }
+
+        let mut locations = {
+            let snapshot = self.snapshot.load();
+            keys.iter()
+                .filter_map(|key| snapshot.find_latest(key))
+                .filter(|entry| !entry.is_inline())
+                .map(|entry| entry.location())
+                .collect::<Vec<_>>()
+        };
+        locations.sort_unstable_by_key(|location| (location.chunk, location.offset));
+
+        for (chunk, offset, len, count) in coalesce(&locations) {
+            if !is_current() {
+                break;
+            }
+
+            self.dat_fd_pool.advise_will_need(
+                (self.namespace, chunk as usize),
+                &self.chunk_file_path(chunk as usize, BLOB_EXTENSION),
+                offset,
+                len,
+            )?;
+            warmed += count;
+        }
+
+        Ok(warmed)
+    }
+
+    /// Stops any prefetch in progress, e.g. when the user reverses scroll direction
+    pub fn cancel_prefetch(&self) {
+        self.prefetch_generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+/// Merges sorted locations that touch or overlap into `(chunk, offset, len, blobs)`
+/// ranges so each one is a single advise call
+fn coalesce(locations: &[BlobLocation]) -> Vec<(u32, u64, u64, u64)> {
+    let mut ranges: Vec<(u32, u64, u64, u64)> = Vec::new();
+
+    for location in locations {
+        let start = location.offset as u64;
+        let end = start + location.len as u64;
+
+        match ranges.last_mut() {
+            Some((chunk, range_start, range_len, count))
+                if *chunk == location.chunk && start <= *range_start + *range_len =>
+            {
+                *range_len = (*range_len).max(end - *range_start);
+                *count += 1;
+            }
+            _ => ranges.push((location.chunk, start, end - start, 1)),
+        }
+    }
+
+    ranges
+}
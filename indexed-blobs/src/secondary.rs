@@ -0,0 +1,37 @@
+use crate::{
+    blob_provider::BlobProvider,
+    err_type::BlobProviderError,
+    key::{format_key, parse_key},
+};
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Like `put`, and also gives `key` a sortable secondary key, e.g. the capture
+    /// timestamp of its asset. The secondary key stays with the key until it is
+    /// deleted or evicted, later plain puts keep it.
+    pub fn put_with_secondary(
+        &self,
+        key: String,
+        data: Vec<u8>,
+        secondary: i64,
+    ) -> Result<(), BlobProviderError> {
+        self.put_latest(parse_key(&key)?, &data, Some(secondary), None)
+    }
+
+    /// Keys holding a blob whose secondary key is at least `start` and below `end`,
+    /// ordered by secondary key and then key, at most `limit` of them
+    pub fn range_by_secondary(
+        &self,
+        start: i64,
+        end: i64,
+        limit: u32,
+    ) -> Result<Vec<String>, BlobProviderError> {
+        let snapshot = self.snapshot.load();
+
+        let keys = self.secondary.range(start, end, limit as usize, |key| {
+            snapshot.find_latest(key).is_some()
+        })?;
+
+        Ok(keys.iter().map(format_key).collect())
+    }
+}
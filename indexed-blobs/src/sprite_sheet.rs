@@ -0,0 +1,260 @@
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use crate::{
+    blob_provider::BlobProvider, consts::MAX_BLOB_SIZE, err_type::BlobProviderError, key::parse_key,
+};
+
+const SPRITE_SHEET_MAGIC: [u8; 4] = *b"IBSS";
+const SPRITE_SHEET_VERSION: u16 = 1;
+
+/// Starts every sprite sheet blob, followed by `tile_count` tile ranges and the tiles
+#[repr(C)]
+#[derive(Debug, Copy, Clone, FromBytes, IntoBytes, KnownLayout, Immutable)]
+struct SpriteSheetHeader {
+    magic: [u8; 4],
+    version: u16,
+    tile_count: u16,
+    tile_width: u32,
+    tile_height: u32,
+}
+
+const _: () = assert!(size_of::<SpriteSheetHeader>() == 16);
+
+/// Where a tile's bytes are, relative to the start of the blob
+#[repr(C)]
+#[derive(Debug, Copy, Clone, FromBytes, IntoBytes, KnownLayout, Immutable)]
+struct TileRange {
+    offset: u32,
+    len: u32,
+}
+
+const _: () = assert!(size_of::<TileRange>() == 8);
+
+/// Thumbnails of the same dimensions packed into a single blob, e.g. one grid row at
+/// the smallest zoom levels, so they are stored and read as one record
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct SpriteSheet {
+    pub tile_width: u32,
+    pub tile_height: u32,
+    /// YUV 4:2:0 planes of every tile, packed without padding
+    pub tiles: Vec<Vec<u8>>,
+}
+
+impl SpriteSheet {
+    fn encode(&self) -> Result<Vec<u8>, BlobProviderError> {
+        let invalid = |reason: &str| Err(BlobProviderError::InvalidSpriteSheet(reason.to_owned()));
+
+        if self.tiles.is_empty() {
+            return invalid("no tiles");
+        }
+        let tile_len = tile_len(self.tile_width, self.tile_height)?;
+        if let Some(tile) = self.tiles.iter().find(|tile| tile.len() != tile_len) {
+            return Err(wrong_tile_len(tile.len(), tile_len));
+        }
+        let Ok(tile_count) = u16::try_from(self.tiles.len()) else {
+            return invalid("too many tiles");
+        };
+
+        let data_start = tiles_start(tile_count);
+        let total_len = data_start + tile_len * self.tiles.len();
+        if total_len > MAX_BLOB_SIZE {
+            return Err(BlobProviderError::BlobTooLarge(total_len as u64));
+        }
+
+        let header = SpriteSheetHeader {
+            magic: SPRITE_SHEET_MAGIC,
+            version: SPRITE_SHEET_VERSION,
+            tile_count,
+            tile_width: self.tile_width,
+            tile_height: self.tile_height,
+        };
+
+        let mut buffer = Vec::with_capacity(total_len);
+        buffer.extend_from_slice(header.as_bytes());
+        for i in 0..self.tiles.len() {
+            let range = TileRange {
+                offset: (data_start + i * tile_len) as u32,
+                len: tile_len as u32,
+            };
+            buffer.extend_from_slice(range.as_bytes());
+        }
+        for tile in &self.tiles {
+            buffer.extend_from_slice(tile);
+        }
+
+        Ok(buffer)
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, BlobProviderError> {
+        let header = parse_header(data)?;
+        let ranges = parse_ranges(
+            &header,
+            data.get(size_of::<SpriteSheetHeader>()..),
+            header.tile_count as usize,
+        )?;
+
+        let tiles = ranges
+            .iter()
+            .map(|range| tile_bytes(data, range).map(<[u8]>::to_vec))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            tile_width: header.tile_width,
+            tile_height: header.tile_height,
+            tiles,
+        })
+    }
+}
+
+/// Offset of the first tile in a sheet of `tile_count` tiles
+fn tiles_start(tile_count: u16) -> usize {
+    size_of::<SpriteSheetHeader>() + tile_count as usize * size_of::<TileRange>()
+}
+
+/// Length of a tile of `width` by `height` pixels: a Y plane and two chroma planes
+/// subsampled 2x2, without padding
+fn tile_len(width: u32, height: u32) -> Result<usize, BlobProviderError> {
+    if width == 0 || height == 0 {
+        return Err(BlobProviderError::InvalidSpriteSheet(
+            "empty tiles".to_owned(),
+        ));
+    }
+
+    let luma_len = width as u64 * height as u64;
+    let chroma_len = width.div_ceil(2) as u64 * height.div_ceil(2) as u64;
+    let len = luma_len + 2 * chroma_len;
+    if len > MAX_BLOB_SIZE as u64 {
+        return Err(BlobProviderError::BlobTooLarge(len));
+    }
+
+    Ok(len as usize)
+}
+
+fn wrong_tile_len(len: usize, expected: usize) -> BlobProviderError {
+    BlobProviderError::InvalidSpriteSheet(format!(
+        "tile of {len} bytes, tiles of its size take {expected}"
+    ))
+}
+
+fn parse_header(data: &[u8]) -> Result<SpriteSheetHeader, BlobProviderError> {
+    let header = data
+        .get(..size_of::<SpriteSheetHeader>())
+        .and_then(|bytes| SpriteSheetHeader::read_from_bytes(bytes).ok())
+        .filter(|header| header.magic == SPRITE_SHEET_MAGIC)
+        .ok_or_else(|| BlobProviderError::InvalidSpriteSheet("not a sprite sheet".to_owned()))?;
+
+    if header.version != SPRITE_SHEET_VERSION {
+        return Err(BlobProviderError::InvalidSpriteSheet(format!(
+            "unsupported version {}",
+            header.version
+        )));
+    }
+
+    Ok(header)
+}
+
+/// Parses the first `count` tile ranges following `header` out of `bytes`, each of
+/// which has to be as long as a tile of the header's size
+fn parse_ranges(
+    header: &SpriteSheetHeader,
+    bytes: Option<&[u8]>,
+    count: usize,
+) -> Result<Vec<TileRange>, BlobProviderError> {
+    let table_len = count * size_of::<TileRange>();
+    let ranges = bytes
+        .and_then(|bytes| bytes.get(..table_len))
+        .and_then(|table| <[TileRange]>::ref_from_bytes(table).ok())
+        .map(<[TileRange]>::to_vec)
+        .ok_or_else(|| BlobProviderError::InvalidSpriteSheet("truncated tile table".to_owned()))?;
+
+    let tile_len = tile_len(header.tile_width, header.tile_height)?;
+    if let Some(range) = ranges.iter().find(|range| range.len as usize != tile_len) {
+        return Err(wrong_tile_len(range.len as usize, tile_len));
+    }
+
+    Ok(ranges)
+}
+
+/// The bytes of the tile at `range` out of the whole sheet
+fn tile_bytes<'a>(data: &'a [u8], range: &TileRange) -> Result<&'a [u8], BlobProviderError> {
+    let start = range.offset as usize;
+
+    data.get(start..start + range.len as usize)
+        .ok_or_else(|| BlobProviderError::InvalidSpriteSheet("tile out of bounds".to_owned()))
+}
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Stores `sheet` under `key` as a single blob, replacing the latest generation
+    pub fn put_sprite_sheet(
+        &self,
+        key: String,
+        sheet: SpriteSheet,
+    ) -> Result<(), BlobProviderError> {
+        self.put_latest(parse_key(&key)?, &sheet.encode()?, None, None)
+    }
+
+    /// Reads every tile of the sprite sheet stored under `key`
+    pub fn get_sprite_sheet(&self, key: String) -> Result<Option<SpriteSheet>, BlobProviderError> {
+        self.get(key)?
+            .map(|data| SpriteSheet::decode(&data))
+            .transpose()
+    }
+
+    /// Reads the tile at `index` of the sprite sheet stored under `key`, with one
+    /// read of the header and tile table and one of the tile instead of the whole
+    /// sheet, unless the sheet is cached
+    pub fn get_sprite_tile(
+        &self,
+        key: String,
+        index: u32,
+    ) -> Result<Option<Vec<u8>>, BlobProviderError> {
+        let key = parse_key(&key)?;
+        self.touch_for_quota(&key)?;
+
+        let tile_range = |header: &SpriteSheetHeader, table: Option<&[u8]>| {
+            if index >= header.tile_count as u32 {
+                return Err(BlobProviderError::TileOutOfBounds(
+                    index,
+                    header.tile_count as u32,
+                ));
+            }
+            let ranges = parse_ranges(header, table, index as usize + 1)?;
+            Ok(ranges[index as usize])
+        };
+
+        if let Some(cache) = &self.cache
+            && let Some(data) = cache.get(&(self.namespace, key))?
+        {
+            let header = parse_header(&data)?;
+            let range = tile_range(&header, data.get(size_of::<SpriteSheetHeader>()..))?;
+            return Ok(Some(tile_bytes(&data, &range)?.to_vec()));
+        }
+
+        // Both reads go through the same snapshot, so a sheet replaced in between
+        // can't mix the table of one with the tiles of the other
+        self.with_snapshot(|snapshot| {
+            let Some(entry) = snapshot.find_latest(&key) else {
+                return Ok(None);
+            };
+            let blob_len = entry.len as u64;
+            let read = |offset: u64, len: u64| match offset.checked_add(len) {
+                Some(end) if end <= blob_len => {
+                    self.read_entry_bytes(snapshot, &entry, offset, len)
+                }
+                _ => Err(BlobProviderError::InvalidSpriteSheet(
+                    "truncated".to_owned(),
+                )),
+            };
+
+            // The table only has to reach the range of the tile at `index`
+            let prefix_len = size_of::<SpriteSheetHeader>() as u64
+                + (index as u64 + 1) * size_of::<TileRange>() as u64;
+            let prefix = read(0, prefix_len.min(blob_len))?;
+            let header = parse_header(&prefix)?;
+            let range = tile_range(&header, prefix.get(size_of::<SpriteSheetHeader>()..))?;
+
+            Ok(Some(read(range.offset as u64, range.len as u64)?))
+        })
+    }
+}
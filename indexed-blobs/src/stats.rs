@@ -0,0 +1,109 @@
+use std::{collections::HashSet, path::Path};
+
+use crate::{
+    blob_provider::BlobProvider,
+    consts::BLOB_EXTENSION,
+    data_structures::blob_index::BlobIndex,
+    err_type::BlobProviderError,
+    fs::chunk_file_path,
+    storage::{OpenMode, Storage},
+};
+
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct BlobProviderStats {
+    pub num_chunks: u64,
+    /// Live blobs, counting every kept generation of a key separately
+    pub num_blobs: u64,
+    /// Sum of the lengths of every live blob, as seen by readers
+    pub logical_bytes: u64,
+    /// Bytes actually taken by live blobs in the `.dat` files
+    pub stored_bytes: u64,
+    /// Bytes of live blobs stored inline in the `.idx` files
+    pub inline_bytes: u64,
+    /// Total size of the `.dat` files, including bytes compaction can reclaim
+    pub dat_bytes: u64,
+    /// Bytes saved by storing identical blobs only once
+    pub deduplicated_bytes: u64,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct BlobCacheStats {
+    pub budget_bytes: u64,
+    pub cached_bytes: u64,
+    pub cached_blobs: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl BlobProviderStats {
+    pub(crate) fn from_index(
+        provider: &BlobProvider,
+        index: &BlobIndex,
+    ) -> Result<Self, BlobProviderError> {
+        let live_entries = index.snapshot().live_entries();
+        let mut locations = HashSet::new();
+
+        let mut logical_bytes = 0;
+        let mut stored_bytes = 0;
+        let mut inline_bytes = 0;
+        for entry in &live_entries {
+            logical_bytes += entry.len as u64;
+            if entry.is_inline() {
+                inline_bytes += entry.len as u64;
+            } else if locations.insert(entry.location()) {
+                stored_bytes += entry.len as u64;
+            }
+        }
+
+        let dat_bytes = dat_bytes(
+            provider.storage.as_ref(),
+            &provider.root_blob_dir,
+            &provider.blob_file_prefix,
+            index,
+        )?;
+
+        Ok(Self {
+            num_chunks: (index.sealed.len() + index.active.iter().count()) as u64,
+            num_blobs: live_entries.len() as u64,
+            logical_bytes,
+            stored_bytes,
+            inline_bytes,
+            dat_bytes,
+            deduplicated_bytes: logical_bytes - stored_bytes - inline_bytes,
+        })
+    }
+}
+
+/// Total size of the `.dat` files of the chunks `index` holds
+pub(crate) fn dat_bytes(
+    storage: &dyn Storage,
+    root_blob_dir: &Path,
+    blob_file_prefix: &str,
+    index: &BlobIndex,
+) -> Result<u64, BlobProviderError> {
+    let mut dat_bytes = index.active.as_ref().map_or(0, |active| active.dat_len);
+    for chunk in index.sealed.keys() {
+        dat_bytes += storage
+            .open(
+                &chunk_file_path(root_blob_dir, blob_file_prefix, *chunk, BLOB_EXTENSION),
+                OpenMode::Read,
+            )?
+            .len()?;
+    }
+
+    Ok(dat_bytes)
+}
+
+#[uniffi::export]
+impl BlobProvider {
+    pub fn stats(&self) -> Result<BlobProviderStats, BlobProviderError> {
+        let index = self.index.lock()?;
+
+        BlobProviderStats::from_index(self, &index)
+    }
+
+    /// Hit and miss counters of the read cache, `None` when caching is disabled
+    pub fn cache_stats(&self) -> Result<Option<BlobCacheStats>, BlobProviderError> {
+        self.cache.as_ref().map(|cache| cache.stats()).transpose()
+    }
+}
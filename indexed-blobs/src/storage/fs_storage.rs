@@ -0,0 +1,146 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use memmap2::Mmap;
+
+use crate::storage::{FileContext, MappedBytes, OpenMode, Storage, StorageFile};
+
+/// Storage backed by a directory on the local filesystem
+#[derive(Debug, Default)]
+pub struct FsStorage;
+
+impl Storage for FsStorage {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+
+        match mode {
+            OpenMode::Read => &mut options,
+            OpenMode::ReadWrite => options.write(true).create(true).truncate(false),
+            OpenMode::CreateNew => options.write(true).create_new(true),
+            OpenMode::Truncate => options.write(true).create(true).truncate(true),
+        };
+
+        Ok(Box::new(FsFile {
+            file: options.open(path).file_context(path, None)?,
+            path: path.to_path_buf(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to).file_context(from, None)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path).file_context(path, None)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let mut file_names = Vec::new();
+
+        for file in std::fs::read_dir(dir).file_context(dir, None)? {
+            let file = file.file_context(dir, None)?;
+            if file.file_type().file_context(&file.path(), None)?.is_file() {
+                file_names.push(file.file_name().to_string_lossy().into_owned());
+            }
+        }
+
+        Ok(file_names)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)
+            .and_then(|dir_file| dir_file.sync_all())
+            .file_context(dir, None)
+    }
+}
+
+struct FsFile {
+    file: File,
+    /// Path the file was opened at, to report errors with
+    path: PathBuf,
+}
+
+impl StorageFile for FsFile {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata().file_context(&self.path, None)?.len())
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file
+            .read_exact_at(buffer, offset)
+            .file_context(&self.path, Some(offset))
+    }
+
+    fn write_at(&self, buffer: &[u8], offset: u64) -> io::Result<()> {
+        self.file
+            .write_all_at(buffer, offset)
+            .file_context(&self.path, Some(offset))
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len).file_context(&self.path, None)
+    }
+
+    fn fsync(&self) -> io::Result<()> {
+        self.file.sync_data().file_context(&self.path, None)
+    }
+
+    fn map(&self) -> io::Result<MappedBytes> {
+        // Zero length maps are not allowed
+        if self.is_empty()? {
+            return Ok(Box::new(Vec::new()));
+        }
+
+        Ok(Box::new(
+            unsafe { Mmap::map(&self.file) }.file_context(&self.path, None)?,
+        ))
+    }
+
+    fn advise_will_need(&self, offset: u64, len: u64) -> io::Result<()> {
+        advise_will_need(&self.file, offset, len).file_context(&self.path, Some(offset))
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn advise_will_need(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let result = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_WILLNEED,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(target_vendor = "apple")]
+fn advise_will_need(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let advisory = libc::radvisory {
+        ra_offset: offset as libc::off_t,
+        ra_count: len.min(libc::c_int::MAX as u64) as libc::c_int,
+    };
+
+    match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_RDADVISE, &advisory) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+fn advise_will_need(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
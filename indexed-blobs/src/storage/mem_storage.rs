@@ -0,0 +1,380 @@
+use std::{
+    collections::HashMap,
+    io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use zerocopy::IntoBytes;
+
+use crate::storage::{FileContext, MappedBytes, OpenMode, Storage, StorageFile};
+
+/// Failure injected into a mutating operation of a [`MemStorage`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Fails with `StorageFull` without changing anything
+    NoSpace,
+    /// Writes half of the buffer, then fails
+    ShortWrite,
+    /// Writes half of the buffer, then fails every operation until `restart`
+    Crash,
+}
+
+/// What survives a crash of a [`MemStorage`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CrashMode {
+    /// Only bytes that were fsynced survive
+    DropUnsynced,
+    /// Every write survives, as if the kernel flushed its caches right before the crash
+    KeepUnsynced,
+}
+
+#[derive(Default)]
+struct MemFileData {
+    data: Vec<u8>,
+    /// Contents as of the last fsync
+    durable: Vec<u8>,
+}
+
+#[derive(Default)]
+struct MemState {
+    files: HashMap<PathBuf, Arc<Mutex<MemFileData>>>,
+    mutations: u64,
+    fault: Option<(u64, Fault)>,
+    crashed: bool,
+    /// Bumped on every restart so handles opened before a crash stay dead
+    epoch: u64,
+}
+
+/// In-memory storage for tests that simulates faults and crashes.
+///
+/// File contents are only durable once fsynced, while creating, renaming and removing
+/// files takes effect immediately. Clones share the same files.
+#[derive(Clone, Default)]
+pub struct MemStorage {
+    state: Arc<Mutex<MemState>>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of mutating operations performed so far
+    pub fn mutations(&self) -> u64 {
+        self.lock().mutations
+    }
+
+    /// Makes the mutating operation `after` operations from now fail with `fault`,
+    /// 0 being the next one
+    pub fn inject_fault(&self, after: u64, fault: Fault) {
+        let mut state = self.lock();
+        state.fault = Some((state.mutations + after, fault));
+    }
+
+    /// Fails every operation until `restart`
+    pub fn crash(&self) {
+        self.lock().crashed = true;
+    }
+
+    pub fn has_crashed(&self) -> bool {
+        self.lock().crashed
+    }
+
+    /// Brings the storage back after a crash, or simulates one if it hadn't crashed.
+    /// Every file handle opened before is invalidated.
+    pub fn restart(&self, mode: CrashMode) {
+        let mut state = self.lock();
+        state.crashed = false;
+        state.fault = None;
+        state.epoch += 1;
+
+        for file in state.files.values() {
+            let mut file = lock_file(file);
+            match mode {
+                CrashMode::DropUnsynced => file.data = file.durable.clone(),
+                CrashMode::KeepUnsynced => file.durable = file.data.clone(),
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn check_alive(&self, epoch: u64) -> io::Result<()> {
+        let state = self.lock();
+
+        match state.crashed || state.epoch != epoch {
+            true => Err(crashed_error()),
+            false => Ok(()),
+        }
+    }
+
+    /// Counts a mutating operation and returns the fault it has to fail with
+    fn mutation(&self, epoch: u64) -> io::Result<Option<Fault>> {
+        let mut state = self.lock();
+        if state.crashed || state.epoch != epoch {
+            return Err(crashed_error());
+        }
+
+        let mutation = state.mutations;
+        state.mutations += 1;
+
+        let fault = state
+            .fault
+            .filter(|(at, _)| *at == mutation)
+            .map(|(_, fault)| fault);
+        if fault.is_some() {
+            state.fault = None;
+        }
+        if fault == Some(Fault::Crash) {
+            state.crashed = true;
+        }
+
+        Ok(fault)
+    }
+
+    /// Mutation that either fully happens or fails without side effects
+    fn atomic_mutation(&self, epoch: u64) -> io::Result<()> {
+        match self.mutation(epoch)? {
+            Some(fault) => Err(fault_error(fault)),
+            None => Ok(()),
+        }
+    }
+
+    fn open_file(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let epoch = self.lock().epoch;
+        self.check_alive(epoch)?;
+
+        let existing = self.lock().files.get(path).cloned();
+        let data = match (mode, existing) {
+            (OpenMode::CreateNew, Some(_)) => {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+            }
+            (OpenMode::Read | OpenMode::ReadWrite, Some(data)) => data,
+            (OpenMode::Read, None) => return Err(io::Error::from(io::ErrorKind::NotFound)),
+            (OpenMode::Truncate, Some(data)) => {
+                self.atomic_mutation(epoch)?;
+                lock_file(&data).data.clear();
+                data
+            }
+            (OpenMode::ReadWrite | OpenMode::CreateNew | OpenMode::Truncate, None) => {
+                self.atomic_mutation(epoch)?;
+                let data = Arc::new(Mutex::new(MemFileData::default()));
+                self.lock().files.insert(path.to_path_buf(), data.clone());
+                data
+            }
+        };
+
+        Ok(Box::new(MemFile {
+            storage: self.clone(),
+            path: path.to_path_buf(),
+            data,
+            epoch,
+        }))
+    }
+
+    fn rename_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let epoch = self.lock().epoch;
+        if !self.lock().files.contains_key(from) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+
+        self.atomic_mutation(epoch)?;
+
+        let mut state = self.lock();
+        let data = state.files.remove(from).ok_or(io::ErrorKind::NotFound)?;
+        state.files.insert(to.to_path_buf(), data);
+
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let epoch = self.lock().epoch;
+        if !self.lock().files.contains_key(path) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+
+        self.atomic_mutation(epoch)?;
+        self.lock().files.remove(path);
+
+        Ok(())
+    }
+}
+
+impl Storage for MemStorage {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        self.open_file(path, mode).file_context(path, None)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.rename_file(from, to).file_context(from, None)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.remove_file(path).file_context(path, None)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let state = self.lock();
+        if state.crashed {
+            return Err(crashed_error()).file_context(dir, None);
+        }
+
+        Ok(state
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| path.file_name()?.to_str().map(str::to_owned))
+            .collect())
+    }
+
+    /// Creating, renaming and removing files is durable right away
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        match self.lock().crashed {
+            true => Err(crashed_error()).file_context(dir, None),
+            false => Ok(()),
+        }
+    }
+}
+
+struct MemFile {
+    storage: MemStorage,
+    /// Path the file was opened at, to report errors with
+    path: PathBuf,
+    data: Arc<Mutex<MemFileData>>,
+    epoch: u64,
+}
+
+impl MemFile {
+    fn lock(&self) -> MutexGuard<'_, MemFileData> {
+        lock_file(&self.data)
+    }
+
+    fn read_file_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        self.storage.check_alive(self.epoch)?;
+
+        let file = self.lock();
+        let source = usize::try_from(offset)
+            .ok()
+            .and_then(|start| file.data.get(start..start.checked_add(buffer.len())?))
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buffer.copy_from_slice(source);
+
+        Ok(())
+    }
+
+    fn write_file_at(&self, buffer: &[u8], offset: u64) -> io::Result<()> {
+        let fault = self.storage.mutation(self.epoch)?;
+        let written = match fault {
+            None => buffer.len(),
+            Some(Fault::NoSpace) => 0,
+            Some(Fault::ShortWrite | Fault::Crash) => buffer.len() / 2,
+        };
+
+        let mut file = self.lock();
+        let start = offset as usize;
+        let end = start + written;
+        if file.data.len() < end {
+            file.data.resize(end, 0);
+        }
+        file.data[start..end].copy_from_slice(&buffer[..written]);
+
+        match fault {
+            Some(fault) => Err(fault_error(fault)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StorageFile for MemFile {
+    fn len(&self) -> io::Result<u64> {
+        self.storage
+            .check_alive(self.epoch)
+            .file_context(&self.path, None)?;
+
+        Ok(self.lock().data.len() as u64)
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read_file_at(buffer, offset)
+            .file_context(&self.path, Some(offset))
+    }
+
+    fn write_at(&self, buffer: &[u8], offset: u64) -> io::Result<()> {
+        self.write_file_at(buffer, offset)
+            .file_context(&self.path, Some(offset))
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.storage
+            .atomic_mutation(self.epoch)
+            .file_context(&self.path, None)?;
+        self.lock().data.resize(len as usize, 0);
+
+        Ok(())
+    }
+
+    fn fsync(&self) -> io::Result<()> {
+        self.storage
+            .atomic_mutation(self.epoch)
+            .file_context(&self.path, None)?;
+
+        let mut file = self.lock();
+        file.durable = file.data.clone();
+
+        Ok(())
+    }
+
+    fn map(&self) -> io::Result<MappedBytes> {
+        self.storage
+            .check_alive(self.epoch)
+            .file_context(&self.path, None)?;
+
+        Ok(Box::new(AlignedBytes::new(&self.lock().data)))
+    }
+}
+
+/// Copy of a file's contents with the alignment of an mmap
+struct AlignedBytes {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl AlignedBytes {
+    fn new(data: &[u8]) -> Self {
+        let mut words = vec![0u64; data.len().div_ceil(size_of::<u64>())];
+        words.as_mut_bytes()[..data.len()].copy_from_slice(data);
+
+        Self {
+            words,
+            len: data.len(),
+        }
+    }
+}
+
+impl Deref for AlignedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.words.as_bytes()[..self.len]
+    }
+}
+
+fn lock_file(file: &Mutex<MemFileData>) -> MutexGuard<'_, MemFileData> {
+    file.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn crashed_error() -> io::Error {
+    io::Error::other("storage crashed")
+}
+
+fn fault_error(fault: Fault) -> io::Error {
+    match fault {
+        Fault::NoSpace => io::Error::new(io::ErrorKind::StorageFull, "injected: no space left"),
+        Fault::ShortWrite => io::Error::new(io::ErrorKind::WriteZero, "injected: short write"),
+        Fault::Crash => crashed_error(),
+    }
+}
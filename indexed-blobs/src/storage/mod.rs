@@ -0,0 +1,149 @@
+use std::{
+    fmt, io,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use crate::consts::TMP_EXTENSION;
+
+pub mod fs_storage;
+#[cfg(feature = "test-support")]
+pub mod mem_storage;
+
+/// Read-only view of a whole file, an mmap for the filesystem backend
+pub type MappedBytes = Box<dyn Deref<Target = [u8]> + Send + Sync>;
+
+/// Source of an I/O error of a storage backend, naming the file it failed on and, for
+/// reads and writes, where. Backends return it inside an `io::Error` of the same kind.
+#[derive(Debug)]
+pub struct FileError {
+    pub path: PathBuf,
+    pub offset: Option<u64>,
+    pub source: io::Error,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {offset}")?;
+        }
+
+        write!(f, ": {}", self.source)
+    }
+}
+
+impl std::error::Error for FileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Wraps the error of an operation on the file at `path` in a `FileError`
+pub trait FileContext<T> {
+    fn file_context(self, path: &Path, offset: Option<u64>) -> io::Result<T>;
+}
+
+impl<T> FileContext<T> for io::Result<T> {
+    fn file_context(self, path: &Path, offset: Option<u64>) -> io::Result<T> {
+        self.map_err(|source| {
+            io::Error::new(
+                source.kind(),
+                FileError {
+                    path: path.to_path_buf(),
+                    offset,
+                    source,
+                },
+            )
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Existing file, read only
+    Read,
+    /// Read and write, creating the file if it's missing
+    ReadWrite,
+    /// Read and write a file that must not exist yet
+    CreateNew,
+    /// Read and write, creating the file or emptying an existing one
+    Truncate,
+}
+
+/// Everything the provider needs from the directory holding its files. Paths are
+/// always `root_blob_dir` joined with a single file name.
+///
+/// Errors should carry a `FileError`, see `FileContext`, so the provider's errors
+/// can tell which file failed.
+pub trait Storage: Send + Sync {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Atomically replaces `to` with `from`
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Names of the files directly inside `dir`
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>>;
+
+    /// Makes every file created, renamed or removed in `dir` so far durable
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+}
+
+pub trait StorageFile: Send + Sync {
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Fills `buffer` from `offset`, failing if the file ends before that
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn write_at(&self, buffer: &[u8], offset: u64) -> io::Result<()>;
+
+    fn append(&self, buffer: &[u8]) -> io::Result<()> {
+        self.write_at(buffer, self.len()?)
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()>;
+
+    /// Makes every write so far durable
+    fn fsync(&self) -> io::Result<()>;
+
+    /// Maps the current contents. The mapping is at least 8-byte aligned and doesn't
+    /// see later writes.
+    fn map(&self) -> io::Result<MappedBytes>;
+
+    /// Hints that the range will be read soon
+    fn advise_will_need(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Replaces the file at `path` with `bytes`, so after a crash it holds either its old
+/// or its new contents. The bytes go to a `.tmp` file next to it first, which is
+/// renamed over `path` once synced.
+pub(crate) fn write_atomically(storage: &dyn Storage, path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}", TMP_EXTENSION));
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let tmp_file = storage.open(&tmp_path, OpenMode::Truncate)?;
+    tmp_file.write_at(bytes, 0)?;
+    tmp_file.fsync()?;
+    storage.rename(&tmp_path, path)?;
+
+    match path.parent() {
+        Some(dir) => storage.sync_dir(dir),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn read_all(file: &dyn StorageFile) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0; file.len()? as usize];
+    file.read_at(&mut buffer, 0)?;
+
+    Ok(buffer)
+}
@@ -0,0 +1,124 @@
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, imageops::FilterType};
+
+use crate::{
+    blob_provider::BlobProvider,
+    blurhash::thumbnail_placeholder,
+    err_type::BlobProviderError,
+    key::parse_key,
+    yuv::{PixelLayout, YuvPixelRange, YuvThumbnail},
+};
+
+/// Longest side of a thumbnail, the app's `MAX_COMPRESSED_DIMENSION`
+pub const MAX_THUMBNAIL_DIMENSION: u32 = 350;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Record)]
+pub struct ThumbnailSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Size of the thumbnail of an image of `width` by `height` pixels: the longest side
+/// becomes `MAX_THUMBNAIL_DIMENSION` and the other one keeps the aspect ratio, rounded
+/// to an even number of pixels so the chroma planes cover whole 2x2 blocks. Matches
+/// the app's `compressedPixelSize`.
+#[uniffi::export]
+pub fn compressed_pixel_size(width: u32, height: u32) -> Result<ThumbnailSize, BlobProviderError> {
+    if width == 0 || height == 0 {
+        return Err(BlobProviderError::InvalidImage("empty image".to_owned()));
+    }
+
+    let short_side = |short: u32, long: u32| {
+        let scaled = (MAX_THUMBNAIL_DIMENSION as u64 * short as u64 / long as u64) as u32;
+        (scaled.div_ceil(2) * 2).max(2)
+    };
+
+    Ok(match width > height {
+        true => ThumbnailSize {
+            width: MAX_THUMBNAIL_DIMENSION,
+            height: short_side(height, width),
+        },
+        false => ThumbnailSize {
+            width: short_side(width, height),
+            height: MAX_THUMBNAIL_DIMENSION,
+        },
+    })
+}
+
+/// Decodes a JPEG, PNG or WebP image and applies its EXIF orientation
+fn decode_oriented(image: &[u8]) -> Result<DynamicImage, BlobProviderError> {
+    let reader = ImageReader::new(Cursor::new(image)).with_guessed_format()?;
+    let format = reader.format();
+    if !matches!(
+        format,
+        Some(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)
+    ) {
+        return Err(BlobProviderError::InvalidImage(format!(
+            "unsupported format {:?}",
+            format
+        )));
+    }
+
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    Ok(image)
+}
+
+/// Decodes `image`, applies its EXIF orientation and scales it to its
+/// `compressed_pixel_size` as a video range YUV thumbnail.
+///
+/// Large images are first shrunk with a box filter to twice the target size, so the
+/// Lanczos filter that produces the final size only looks at a few source pixels each.
+pub fn generate_thumbnail(image: &[u8]) -> Result<YuvThumbnail, BlobProviderError> {
+    let image = decode_oriented(image)?;
+    let size = compressed_pixel_size(image.width(), image.height())?;
+
+    let image = match image.width() > 4 * size.width && image.height() > 4 * size.height {
+        true => image.thumbnail_exact(2 * size.width, 2 * size.height),
+        false => image,
+    };
+    let pixels = image
+        .resize_exact(size.width, size.height, FilterType::Lanczos3)
+        .into_rgba8();
+
+    YuvThumbnail::from_pixels(
+        &pixels,
+        size.width,
+        size.height,
+        size.width * 4,
+        PixelLayout::Rgba,
+        YuvPixelRange::Video,
+    )
+}
+
+/// Generates the thumbnail of a JPEG, PNG or WebP image, see `generate_thumbnail`
+#[uniffi::export]
+pub fn generate_yuv_thumbnail(image: Vec<u8>) -> Result<YuvThumbnail, BlobProviderError> {
+    generate_thumbnail(&image)
+}
+
+#[uniffi::export]
+impl BlobProvider {
+    /// Generates the thumbnail of a JPEG, PNG or WebP image and stores its record
+    /// under `key` along with its placeholder, returning the thumbnail's size
+    pub fn put_thumbnail(
+        &self,
+        key: String,
+        image: Vec<u8>,
+    ) -> Result<ThumbnailSize, BlobProviderError> {
+        let key = parse_key(&key)?;
+        let thumbnail = generate_thumbnail(&image)?;
+        let placeholder = thumbnail_placeholder(&thumbnail)?;
+
+        self.put_latest(key, &thumbnail.encode()?, None, Some(&placeholder))?;
+
+        Ok(ThumbnailSize {
+            width: thumbnail.width,
+            height: thumbnail.height,
+        })
+    }
+}
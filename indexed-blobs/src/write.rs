@@ -0,0 +1,299 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{
+    blob_provider::BlobProvider,
+    consts::{BLOB_EXTENSION, IDX_EXTENSION, MAX_BLOB_SIZE, MAX_INLINE_BLOB_SIZE},
+    data_structures::{
+        blob_index::{BlobIndex, BlobMetadata, no_active_chunk},
+        chunk_idx::{
+            ActiveChunk, BlobKey, BlobLocation, Generation, IdxEntry, SealedIdx, write_sorted_idx,
+        },
+        content_idx::{ContentHash, hash_content},
+        key_filter::KeyFilter,
+        mmap_midx::{MIDX_FLAG_SEALED, MIdxEntry},
+    },
+    err_type::BlobProviderError,
+    fs::key_filter_file_path,
+};
+
+impl BlobProvider {
+    /// Stores `data` under `key`, replacing the blob of its latest generation, and
+    /// gives the key `secondary` as its secondary key if one is passed. Metadata
+    /// computed from the previous blob is dropped, and `placeholder` becomes the
+    /// key's placeholder if one is passed.
+    pub(crate) fn put_latest(
+        &self,
+        key: BlobKey,
+        data: &[u8],
+        secondary: Option<i64>,
+        placeholder: Option<&str>,
+    ) -> Result<(), BlobProviderError> {
+        if data.len() > MAX_BLOB_SIZE {
+            return Err(BlobProviderError::BlobTooLarge(data.len() as u64));
+        }
+
+        let inline = self.stores_inline(data.len() as u64);
+        let hash = (self.options.deduplicate && !inline).then(|| hash_content(data));
+        let mut index = self.index.lock()?;
+        let generation = index.snapshot().put_generation(&key);
+
+        let metadata = BlobMetadata {
+            secondary,
+            placeholder: placeholder.map(Arc::from),
+        };
+
+        if inline {
+            return self.commit_inline_blob(&mut index, key, generation, metadata, data);
+        }
+
+        self.commit_blob(
+            &mut index,
+            key,
+            generation,
+            metadata,
+            data.len() as u64,
+            hash,
+            |active| active.append_data(data),
+        )
+    }
+
+    /// Publishes a blob of `len` bytes under `key` at `generation`, replacing whatever
+    /// that generation held, along with its `metadata`. When deduplication is enabled
+    /// and `hash` is already stored, the existing bytes are referenced and `append` is
+    /// never called.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn commit_blob(
+        &self,
+        index: &mut BlobIndex,
+        key: BlobKey,
+        generation: Generation,
+        metadata: BlobMetadata,
+        len: u64,
+        hash: Option<ContentHash>,
+        append: impl FnOnce(&mut ActiveChunk) -> Result<BlobLocation, BlobProviderError>,
+    ) -> Result<(), BlobProviderError> {
+        let existing = hash
+            .as_ref()
+            .zip(index.content.as_ref())
+            .and_then(|(hash, content)| content.find(hash));
+
+        if let Some(location) = existing {
+            self.ensure_active_chunk(index, 0)?;
+            index.commit_with_metadata(
+                &[IdxEntry::new(key, generation, location)],
+                &HashMap::from([(key, metadata)]),
+            )?;
+            self.finish_write(index, &[key], []);
+
+            return Ok(());
+        }
+
+        let location = append(self.ensure_active_chunk(index, len)?)?;
+        index.commit_with_metadata(
+            &[IdxEntry::new(key, generation, location)],
+            &HashMap::from([(key, metadata)]),
+        )?;
+        self.finish_write(index, &[key], hash.map(|hash| (hash, location)));
+
+        Ok(())
+    }
+
+    /// Bookkeeping once `written` is committed: drops their cached blobs, remembers
+    /// the hashes of freshly `appended` bytes and enforces the quota. The write is
+    /// durable by then, so a failure here isn't reported as a failed write. It only
+    /// costs a missed deduplication, or an eviction the next write retries.
+    pub(crate) fn finish_write(
+        &self,
+        index: &mut BlobIndex,
+        written: &[BlobKey],
+        appended: impl IntoIterator<Item = (ContentHash, BlobLocation)>,
+    ) {
+        for key in written {
+            let _ = self.invalidate_cached(key);
+        }
+
+        if let Some(content) = index.content.as_mut() {
+            for (hash, location) in appended {
+                let _ = content.insert(hash, location);
+            }
+        }
+
+        let _ = self.enforce_quota_after_write(index, written);
+    }
+
+    /// Bookkeeping once the tombstones of `deleted` are committed, best effort like
+    /// `finish_write`
+    pub(crate) fn finish_delete(&self, deleted: &[BlobKey]) {
+        for key in deleted {
+            let _ = self.invalidate_cached(key);
+
+            if let Some(quota) = &self.quota {
+                let _ = quota.record_delete(key);
+            }
+        }
+    }
+
+    /// Whether a blob of `len` bytes goes into the index instead of a `.dat` file
+    pub(crate) fn stores_inline(&self, len: u64) -> bool {
+        self.options.inline_max_bytes > 0
+            && len <= self.options.inline_max_bytes.min(MAX_INLINE_BLOB_SIZE)
+    }
+
+    /// Publishes `data` under `key` at `generation` along with its `metadata`, stored
+    /// inline in the index
+    pub(crate) fn commit_inline_blob(
+        &self,
+        index: &mut BlobIndex,
+        key: BlobKey,
+        generation: Generation,
+        metadata: BlobMetadata,
+        data: &[u8],
+    ) -> Result<(), BlobProviderError> {
+        let location = self.ensure_active_chunk(index, 0)?.append_inline(data)?;
+        index.commit_with_metadata(
+            &[IdxEntry::new(key, generation, location)],
+            &HashMap::from([(key, metadata)]),
+        )?;
+        self.finish_write(index, &[key], []);
+
+        Ok(())
+    }
+
+    /// Must be called with the index lock held, after the commit published the new
+    /// snapshot, see `cache_read`
+    pub(crate) fn invalidate_cached(&self, key: &BlobKey) -> Result<(), BlobProviderError> {
+        match &self.cache {
+            Some(cache) => cache.invalidate(&(self.namespace, *key)),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes sure the active chunk can take `incoming` more bytes, sealing it and
+    /// starting a new chunk otherwise
+    pub(crate) fn ensure_active_chunk<'a>(
+        &self,
+        index: &'a mut BlobIndex,
+        incoming: u64,
+    ) -> Result<&'a mut ActiveChunk, BlobProviderError> {
+        let has_room = index
+            .active
+            .as_ref()
+            .is_some_and(|active| active.has_room(incoming));
+
+        if !has_room {
+            self.seal_active_chunk(index)?;
+
+            // Files first, so a failure never leaves an entry behind without them
+            let chunk = index.midx.entry_count();
+            let active = ActiveChunk::open(
+                self.storage.as_ref(),
+                chunk,
+                &self.chunk_file_path(chunk, IDX_EXTENSION),
+                &self.chunk_file_path(chunk, BLOB_EXTENSION),
+                0,
+            )?;
+            index.midx.add_entry(MIdxEntry::new())?;
+            index.active = Some(active);
+        }
+
+        index.active.as_mut().ok_or_else(no_active_chunk)
+    }
+
+    /// Rewrites the active chunk's `.idx` sorted by key and generation with only the
+    /// newest record of each, then flags it as sealed in the midx. The chunk stays
+    /// active until the seal is durable, so writes carry on into it if sealing fails.
+    pub(crate) fn seal_active_chunk(&self, index: &mut BlobIndex) -> Result<(), BlobProviderError> {
+        if index.active.is_none() {
+            return Ok(());
+        }
+
+        // The sorted `.idx` only reads as the same chunk if the midx committed every
+        // record in it, should the seal not make it into the midx
+        index.commit_staged()?;
+
+        let active = index.active.as_ref().ok_or_else(no_active_chunk)?;
+        // Batches append bytes before sealing a full chunk and commit the records
+        // pointing at them afterwards, from the next chunk
+        active.dat_file.fsync()?;
+
+        let chunk = active.chunk;
+        let idx_path = self.chunk_file_path(chunk, IDX_EXTENSION);
+        let mut entries = active.entries.merged().into_values().collect::<Vec<_>>();
+
+        let sealed = write_sorted_idx(
+            self.storage.as_ref(),
+            &idx_path,
+            chunk,
+            &mut entries,
+            &active.inline,
+        )
+        .and_then(|()| self.flag_sealed(index, chunk, &idx_path, &entries));
+        let sealed = match sealed {
+            Ok(sealed) => sealed,
+            Err(err) => {
+                // The sorted `.idx` may have replaced the file the chunk appends to
+                if let Some(active) = index.active.as_mut() {
+                    active.reopen_idx(self.storage.as_ref(), &idx_path)?;
+                }
+                index.publish();
+                return Err(err);
+            }
+        };
+
+        index.active = None;
+        index.mark_durable();
+        index.sealed.insert(chunk, Arc::new(sealed));
+        index.publish();
+
+        // The chunk's records never change again, so its digest is only computed once
+        let digest = self.digest_chunk(&index.snapshot(), chunk, &entries)?;
+        index.digests.insert(digest)?;
+
+        Ok(())
+    }
+
+    /// Persists everything sealing `chunk` changes besides its `.idx`, returning the
+    /// sorted `.idx` mapped. The midx is left as it was if its update fails.
+    fn flag_sealed(
+        &self,
+        index: &mut BlobIndex,
+        chunk: usize,
+        idx_path: &Path,
+        entries: &[IdxEntry],
+    ) -> Result<SealedIdx, BlobProviderError> {
+        // Once sealed, the chunk's keys are only known to the persisted filter. One that
+        // outgrew its size is built again from the keys of every chunk.
+        if index.filter.is_overfull() {
+            let keys = index
+                .sealed
+                .values()
+                .flat_map(|sealed| sealed.entries())
+                .chain(entries)
+                .map(|entry| entry.key)
+                .collect::<HashSet<_>>();
+            index.filter = Arc::new(KeyFilter::build(
+                self.storage.clone(),
+                &key_filter_file_path(&self.root_blob_dir, &self.blob_file_prefix),
+                &keys.into_iter().collect::<Vec<_>>(),
+            )?);
+        } else {
+            index.filter.persist()?;
+        }
+
+        let sealed = SealedIdx::open(self.storage.as_ref(), idx_path, entries.len(), chunk)?;
+
+        let unsealed = index.midx[chunk];
+        index.midx[chunk].num_entries = entries.len() as u32;
+        index.midx[chunk].flags |= MIDX_FLAG_SEALED;
+        if let Err(err) = index.midx.flush() {
+            index.midx[chunk] = unsealed;
+            return Err(err);
+        }
+
+        Ok(sealed)
+    }
+}
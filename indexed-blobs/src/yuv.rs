@@ -0,0 +1,408 @@
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, byteorder::little_endian::U32};
+
+use crate::{consts::MAX_BLOB_SIZE, err_type::BlobProviderError};
+
+const YUV_MAGIC: [u8; 4] = *b"IBYV";
+const YUV_VERSION: u8 = 1;
+/// ITU-R BT.601, the only matrix so far
+const YUV_MATRIX_BT601: u8 = 0;
+
+/// Starts every thumbnail record, followed by the Y, U and V planes. Little-endian so
+/// tooling and other clients can read records regardless of the platform they were
+/// written on.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, FromBytes, IntoBytes, KnownLayout, Immutable)]
+struct YuvHeader {
+    magic: [u8; 4],
+    version: u8,
+    pixel_range: u8,
+    matrix: u8,
+    reserved: u8,
+    width: U32,
+    height: U32,
+    y_stride: U32,
+    uv_stride: U32,
+}
+
+const _: () = assert!(size_of::<YuvHeader>() == 24);
+
+/// Range the samples of a thumbnail are encoded in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum YuvPixelRange {
+    /// Y in 16..=235 and U, V in 16..=240, which the app has always written
+    Video,
+    /// Every sample in 0..=255
+    Full,
+}
+
+/// Byte order of 8-bit four channel pixels, alpha always last
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum PixelLayout {
+    Rgba,
+    Bgra,
+}
+
+impl PixelLayout {
+    /// Offsets of red, green and blue within a pixel
+    pub(crate) fn rgb_offsets(self) -> [usize; 3] {
+        match self {
+            PixelLayout::Rgba => [0, 1, 2],
+            PixelLayout::Bgra => [2, 1, 0],
+        }
+    }
+}
+
+/// Planar YUV 4:2:0 thumbnail that carries everything needed to display it, instead
+/// of relying on its dimensions being known out of band.
+///
+/// `planes` holds `height` rows of `y_stride` bytes of Y, followed by the U and then
+/// the V plane, each `(height + 1) / 2` rows of `uv_stride` bytes. Every row holds
+/// `width` or `(width + 1) / 2` samples, the rest of the stride is padding.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct YuvThumbnail {
+    pub width: u32,
+    pub height: u32,
+    pub y_stride: u32,
+    pub uv_stride: u32,
+    pub pixel_range: YuvPixelRange,
+    pub planes: Vec<u8>,
+}
+
+/// RGB to YUV conversion in 8-bit fixed point
+struct ToYuv {
+    y_offset: i32,
+    y: [i32; 3],
+    u: [i32; 3],
+    v: [i32; 3],
+}
+
+/// YUV to RGB conversion in 8-bit fixed point
+struct ToRgb {
+    y_offset: i32,
+    y_scale: i32,
+    r_v: i32,
+    g_u: i32,
+    g_v: i32,
+    b_u: i32,
+}
+
+const VIDEO_TO_YUV: ToYuv = ToYuv {
+    y_offset: 16,
+    y: [66, 129, 25],
+    u: [-38, -74, 112],
+    v: [112, -94, -18],
+};
+
+const FULL_TO_YUV: ToYuv = ToYuv {
+    y_offset: 0,
+    y: [77, 150, 29],
+    u: [-43, -85, 128],
+    v: [128, -107, -21],
+};
+
+const VIDEO_TO_RGB: ToRgb = ToRgb {
+    y_offset: 16,
+    y_scale: 298,
+    r_v: 409,
+    g_u: -100,
+    g_v: -208,
+    b_u: 516,
+};
+
+const FULL_TO_RGB: ToRgb = ToRgb {
+    y_offset: 0,
+    y_scale: 256,
+    r_v: 359,
+    g_u: -88,
+    g_v: -183,
+    b_u: 454,
+};
+
+fn clamp_sample(value: i32) -> u8 {
+    value.clamp(0, 255) as u8
+}
+
+impl ToYuv {
+    fn luma(&self, [r, g, b]: [i32; 3]) -> u8 {
+        clamp_sample(((self.y[0] * r + self.y[1] * g + self.y[2] * b + 128) >> 8) + self.y_offset)
+    }
+
+    fn chroma(&self, [r, g, b]: [i32; 3]) -> (u8, u8) {
+        let u = (self.u[0] * r + self.u[1] * g + self.u[2] * b + 128) >> 8;
+        let v = (self.v[0] * r + self.v[1] * g + self.v[2] * b + 128) >> 8;
+        (clamp_sample(u + 128), clamp_sample(v + 128))
+    }
+}
+
+impl ToRgb {
+    fn rgb(&self, y: u8, u: u8, v: u8) -> [u8; 3] {
+        let c = (y as i32 - self.y_offset) * self.y_scale + 128;
+        let d = u as i32 - 128;
+        let e = v as i32 - 128;
+
+        [
+            clamp_sample((c + self.r_v * e) >> 8),
+            clamp_sample((c + self.g_u * d + self.g_v * e) >> 8),
+            clamp_sample((c + self.b_u * d) >> 8),
+        ]
+    }
+}
+
+impl YuvPixelRange {
+    fn to_yuv(self) -> &'static ToYuv {
+        match self {
+            YuvPixelRange::Video => &VIDEO_TO_YUV,
+            YuvPixelRange::Full => &FULL_TO_YUV,
+        }
+    }
+
+    fn to_rgb(self) -> &'static ToRgb {
+        match self {
+            YuvPixelRange::Video => &VIDEO_TO_RGB,
+            YuvPixelRange::Full => &FULL_TO_RGB,
+        }
+    }
+}
+
+fn invalid(reason: &str) -> BlobProviderError {
+    BlobProviderError::InvalidThumbnail(reason.to_owned())
+}
+
+/// Bytes a plane of `rows` rows of `stride` bytes takes, if it is a sane size
+fn plane_len(stride: u32, rows: u32) -> Result<usize, BlobProviderError> {
+    let len = stride as u64 * rows as u64;
+    if len > MAX_BLOB_SIZE as u64 {
+        return Err(BlobProviderError::BlobTooLarge(len));
+    }
+
+    Ok(len as usize)
+}
+
+impl YuvThumbnail {
+    fn chroma_width(&self) -> u32 {
+        self.width.div_ceil(2)
+    }
+
+    fn chroma_height(&self) -> u32 {
+        self.height.div_ceil(2)
+    }
+
+    /// Checks the dimensions against the strides and `planes`, returning the length of
+    /// the Y and of each chroma plane
+    pub(crate) fn plane_lens(&self) -> Result<(usize, usize), BlobProviderError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(invalid("empty thumbnail"));
+        }
+        if self.y_stride < self.width || self.uv_stride < self.chroma_width() {
+            return Err(invalid("stride shorter than a row"));
+        }
+
+        let y_len = plane_len(self.y_stride, self.height)?;
+        let uv_len = plane_len(self.uv_stride, self.chroma_height())?;
+        if self.planes.len() != y_len + 2 * uv_len {
+            return Err(invalid("planes don't match the dimensions"));
+        }
+
+        Ok((y_len, uv_len))
+    }
+
+    /// Converts `height` rows of `row_bytes` bytes of four channel pixels, subsampling
+    /// the chroma of every 2x2 block
+    pub fn from_pixels(
+        pixels: &[u8],
+        width: u32,
+        height: u32,
+        row_bytes: u32,
+        layout: PixelLayout,
+        pixel_range: YuvPixelRange,
+    ) -> Result<Self, BlobProviderError> {
+        let (w, h, row_bytes) = (width as usize, height as usize, row_bytes as usize);
+        if w == 0 || h == 0 {
+            return Err(invalid("empty thumbnail"));
+        }
+        if row_bytes < w * 4 || pixels.len() < row_bytes * (h - 1) + w * 4 {
+            return Err(invalid("pixels don't match the dimensions"));
+        }
+
+        let mut thumbnail = Self {
+            width,
+            height,
+            y_stride: width,
+            uv_stride: width.div_ceil(2),
+            pixel_range,
+            planes: Vec::new(),
+        };
+        let (y_len, uv_len) = (
+            plane_len(thumbnail.y_stride, height)?,
+            plane_len(thumbnail.uv_stride, thumbnail.chroma_height())?,
+        );
+        thumbnail.planes = vec![0; y_len + 2 * uv_len];
+
+        let to_yuv = pixel_range.to_yuv();
+        let [ri, gi, bi] = layout.rgb_offsets();
+        let rgb = |pixel: &[u8]| [pixel[ri] as i32, pixel[gi] as i32, pixel[bi] as i32];
+        let row = |y: usize| &pixels[y * row_bytes..y * row_bytes + w * 4];
+
+        let (y_plane, uv_planes) = thumbnail.planes.split_at_mut(y_len);
+        for (y, y_row) in y_plane.chunks_exact_mut(w).enumerate() {
+            for (luma, pixel) in y_row.iter_mut().zip(row(y).chunks_exact(4)) {
+                *luma = to_yuv.luma(rgb(pixel));
+            }
+        }
+
+        let (u_plane, v_plane) = uv_planes.split_at_mut(uv_len);
+        let chroma_width = w.div_ceil(2);
+        let rows = u_plane
+            .chunks_exact_mut(chroma_width)
+            .zip(v_plane.chunks_exact_mut(chroma_width));
+        for (cy, (u_row, v_row)) in rows.enumerate() {
+            // Edges of odd dimensions repeat their last row or column
+            let (top, bottom) = (row(2 * cy), row((2 * cy + 1).min(h - 1)));
+
+            for (cx, (u, v)) in u_row.iter_mut().zip(v_row.iter_mut()).enumerate() {
+                let (left, right) = (2 * cx * 4, (2 * cx + 1).min(w - 1) * 4);
+
+                let mut sum = [2; 3];
+                for pixel in [
+                    &top[left..],
+                    &top[right..],
+                    &bottom[left..],
+                    &bottom[right..],
+                ] {
+                    for (sum, channel) in sum.iter_mut().zip(rgb(pixel)) {
+                        *sum += channel;
+                    }
+                }
+
+                (*u, *v) = to_yuv.chroma(sum.map(|sum| sum >> 2));
+            }
+        }
+
+        Ok(thumbnail)
+    }
+
+    /// Converts the thumbnail to four channel pixels with opaque alpha, `width * 4`
+    /// bytes per row
+    pub fn to_pixels(&self, layout: PixelLayout) -> Result<Vec<u8>, BlobProviderError> {
+        let (y_len, uv_len) = self.plane_lens()?;
+        let (w, y_stride, uv_stride) = (
+            self.width as usize,
+            self.y_stride as usize,
+            self.uv_stride as usize,
+        );
+
+        let to_rgb = self.pixel_range.to_rgb();
+        let [ri, gi, bi] = layout.rgb_offsets();
+        let (y_plane, uv_planes) = self.planes.split_at(y_len);
+        let (u_plane, v_plane) = uv_planes.split_at(uv_len);
+
+        let mut pixels = vec![0; w * self.height as usize * 4];
+        for (y, out) in pixels.chunks_exact_mut(w * 4).enumerate() {
+            let y_row = &y_plane[y * y_stride..][..w];
+            let u_row = &u_plane[y / 2 * uv_stride..][..w.div_ceil(2)];
+            let v_row = &v_plane[y / 2 * uv_stride..][..w.div_ceil(2)];
+
+            // Two pixels share a chroma sample, the loop stays free of bounds checks so
+            // it can be vectorized
+            let lumas = y_row.chunks(2);
+            for ((out, lumas), (u, v)) in out.chunks_mut(8).zip(lumas).zip(u_row.iter().zip(v_row))
+            {
+                for (pixel, luma) in out.chunks_exact_mut(4).zip(lumas) {
+                    let [r, g, b] = to_rgb.rgb(*luma, *u, *v);
+                    pixel[ri] = r;
+                    pixel[gi] = g;
+                    pixel[bi] = b;
+                    pixel[3] = 255;
+                }
+            }
+        }
+
+        Ok(pixels)
+    }
+
+    /// Serializes the thumbnail into a record that can be stored as a blob
+    pub fn encode(&self) -> Result<Vec<u8>, BlobProviderError> {
+        self.plane_lens()?;
+
+        let header = YuvHeader {
+            magic: YUV_MAGIC,
+            version: YUV_VERSION,
+            pixel_range: match self.pixel_range {
+                YuvPixelRange::Video => 0,
+                YuvPixelRange::Full => 1,
+            },
+            matrix: YUV_MATRIX_BT601,
+            reserved: 0,
+            width: self.width.into(),
+            height: self.height.into(),
+            y_stride: self.y_stride.into(),
+            uv_stride: self.uv_stride.into(),
+        };
+
+        let mut buffer = Vec::with_capacity(size_of::<YuvHeader>() + self.planes.len());
+        buffer.extend_from_slice(header.as_bytes());
+        buffer.extend_from_slice(&self.planes);
+
+        Ok(buffer)
+    }
+
+    /// Parses a record written by `encode`
+    pub fn decode(data: &[u8]) -> Result<Self, BlobProviderError> {
+        let (header, planes) = YuvHeader::read_from_prefix(data)
+            .ok()
+            .filter(|(header, _)| header.magic == YUV_MAGIC)
+            .ok_or_else(|| invalid("not a YUV thumbnail"))?;
+
+        if header.version != YUV_VERSION || header.matrix != YUV_MATRIX_BT601 {
+            return Err(invalid("unsupported version"));
+        }
+
+        let thumbnail = Self {
+            width: header.width.get(),
+            height: header.height.get(),
+            y_stride: header.y_stride.get(),
+            uv_stride: header.uv_stride.get(),
+            pixel_range: match header.pixel_range {
+                0 => YuvPixelRange::Video,
+                1 => YuvPixelRange::Full,
+                _ => return Err(invalid("unknown pixel range")),
+            },
+            planes: planes.to_vec(),
+        };
+        thumbnail.plane_lens()?;
+
+        Ok(thumbnail)
+    }
+}
+
+/// Converts four channel pixels into a YUV 4:2:0 thumbnail, see `YuvThumbnail::from_pixels`
+#[uniffi::export]
+pub fn yuv_thumbnail_from_pixels(
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+    row_bytes: u32,
+    layout: PixelLayout,
+    pixel_range: YuvPixelRange,
+) -> Result<YuvThumbnail, BlobProviderError> {
+    YuvThumbnail::from_pixels(&pixels, width, height, row_bytes, layout, pixel_range)
+}
+
+/// Converts a thumbnail into `width * 4` bytes per row of opaque pixels
+#[uniffi::export]
+pub fn yuv_thumbnail_to_pixels(
+    thumbnail: YuvThumbnail,
+    layout: PixelLayout,
+) -> Result<Vec<u8>, BlobProviderError> {
+    thumbnail.to_pixels(layout)
+}
+
+#[uniffi::export]
+pub fn encode_yuv_thumbnail(thumbnail: YuvThumbnail) -> Result<Vec<u8>, BlobProviderError> {
+    thumbnail.encode()
+}
+
+#[uniffi::export]
+pub fn decode_yuv_thumbnail(data: Vec<u8>) -> Result<YuvThumbnail, BlobProviderError> {
+    YuvThumbnail::decode(&data)
+}
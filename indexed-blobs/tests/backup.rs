@@ -0,0 +1,188 @@
+use std::{path::Path, sync::Arc};
+
+use indexed_blobs::{
+    backup::{BackupFile, backup_midx_file, plan_restore_with_storage},
+    blob_provider::{BlobProvider, BlobProviderOptions, new_blob_provider_with_storage},
+    err_type::BlobProviderError,
+    storage::{OpenMode, Storage, mem_storage::MemStorage},
+};
+
+fn key(id: u8) -> String {
+    format!("00000000-0000-0000-0000-{:012X}", id)
+}
+
+fn open(storage: &MemStorage, options: &BlobProviderOptions) -> BlobProvider {
+    new_blob_provider_with_storage(
+        Arc::new(storage.clone()),
+        Path::new("/blobs"),
+        "thumbs".to_owned(),
+        options.clone(),
+    )
+    .unwrap()
+}
+
+/// Stands in for uploading or downloading a file, copying it between devices
+fn transfer(from: &MemStorage, from_path: &str, to: &MemStorage, to_path: &str) {
+    let source = from.open(Path::new(from_path), OpenMode::Read).unwrap();
+    let mut data = vec![0; source.len().unwrap() as usize];
+    source.read_at(&mut data, 0).unwrap();
+
+    let target = to.open(Path::new(to_path), OpenMode::Truncate).unwrap();
+    target.write_at(&data, 0).unwrap();
+}
+
+fn remote_path(file: &BackupFile) -> String {
+    format!("/remote/{}", file.name)
+}
+
+#[test]
+fn sealed_chunks_are_restored() {
+    let options = BlobProviderOptions {
+        deduplicate: true,
+        inline_max_bytes: 64,
+        ..Default::default()
+    };
+    let device = MemStorage::new();
+    let provider = open(&device, &options);
+    for id in 1..=6 {
+        provider.put(key(id), vec![id; 40 * id as usize]).unwrap();
+    }
+    provider.put(key(6), vec![1; 40]).unwrap();
+    provider.delete(key(2)).unwrap();
+    provider.compact().unwrap();
+    // Only in the active chunk, which the backup seals
+    provider
+        .put_with_secondary(key(7), vec![7; 500], 70)
+        .unwrap();
+    provider.delete(key(3)).unwrap();
+
+    // Upload
+    let remote = MemStorage::new();
+    let backup = provider.prepare_backup("/staging".to_owned()).unwrap();
+    assert_eq!(backup.chunks.len(), 2);
+    assert_eq!(backup.midx.name, "thumbs.midx");
+    assert_eq!(backup.digests.name, "thumbs.sum");
+    let files = backup
+        .chunks
+        .iter()
+        .flat_map(|chunk| &chunk.files)
+        .chain([&backup.midx, &backup.digests])
+        .chain(&backup.sidecars);
+    for file in files {
+        transfer(&device, &file.path, &remote, &remote_path(file));
+    }
+
+    // Download on a fresh install
+    let fresh = MemStorage::new();
+    transfer(
+        &remote,
+        &remote_path(&backup.digests),
+        &fresh,
+        "/staging/thumbs.sum",
+    );
+    let plan = |storage: &MemStorage| {
+        plan_restore_with_storage(
+            storage,
+            Path::new("/blobs"),
+            "thumbs",
+            Path::new("/staging/thumbs.sum"),
+        )
+        .unwrap()
+    };
+    let restore = plan(&fresh);
+    assert_eq!(restore.chunks, backup.chunks);
+    assert_eq!(
+        restore.midx,
+        backup_midx_file("/blobs".to_owned(), "thumbs".to_owned()).unwrap()
+    );
+    for file in restore.chunks.iter().flat_map(|chunk| &chunk.files) {
+        transfer(&remote, &remote_path(file), &fresh, &file.path);
+    }
+    assert!(plan(&fresh).chunks.is_empty());
+
+    // A torn download is downloaded again
+    let dat = &restore.chunks[0].files[1];
+    fresh
+        .open(Path::new(&dat.path), OpenMode::ReadWrite)
+        .unwrap()
+        .truncate(10)
+        .unwrap();
+    assert_eq!(plan(&fresh).chunks, restore.chunks[..1]);
+    transfer(&remote, &remote_path(dat), &fresh, &dat.path);
+    assert!(plan(&fresh).chunks.is_empty());
+
+    for file in restore
+        .sidecars
+        .iter()
+        .chain([&restore.midx, &restore.digests])
+    {
+        transfer(&remote, &remote_path(file), &fresh, &file.path);
+    }
+    let restored = open(&fresh, &options);
+    for id in 1..=7 {
+        assert_eq!(
+            restored.get(key(id)).unwrap(),
+            provider.get(key(id)).unwrap()
+        );
+    }
+    assert_eq!(restored.get(key(3)).unwrap(), None);
+    assert_eq!(restored.range_by_secondary(0, 100, 10).unwrap(), [key(7)]);
+    assert_eq!(
+        restored.chunk_digests().unwrap(),
+        provider.chunk_digests().unwrap()
+    );
+
+    restored.put(key(8), vec![8; 100]).unwrap();
+    restored.compact().unwrap();
+    assert_eq!(restored.get(key(8)).unwrap(), Some(vec![8; 100]));
+    assert_eq!(restored.get(key(6)).unwrap(), Some(vec![1; 40]));
+}
+
+#[test]
+fn backups_seal_the_active_chunk_and_keep_its_metadata() {
+    let options = BlobProviderOptions {
+        inline_max_bytes: 64,
+        ..Default::default()
+    };
+    let device = MemStorage::new();
+    let provider = open(&device, &options);
+    for id in 1..=3 {
+        provider
+            .put_with_secondary(key(id), vec![id; 40], 10 * id as i64)
+            .unwrap();
+    }
+    provider.delete(key(2)).unwrap();
+
+    let backup = provider.prepare_backup("/staging".to_owned()).unwrap();
+    assert_eq!(backup.chunks.len(), 1);
+    let names = backup
+        .sidecars
+        .iter()
+        .map(|file| file.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        ["thumbs.gen", "thumbs.sidx", "thumbs.plh", "thumbs.phash"]
+    );
+
+    // The seal didn't cost the provider the secondary keys written with its records
+    drop(provider);
+    let provider = open(&device, &options);
+    assert_eq!(
+        provider.range_by_secondary(0, 100, 10).unwrap(),
+        [key(1), key(3)]
+    );
+}
+
+#[test]
+fn backups_cant_be_staged_in_the_blob_directory() {
+    let device = MemStorage::new();
+    let provider = open(&device, &BlobProviderOptions::default());
+    provider.put(key(1), vec![1; 100]).unwrap();
+
+    assert!(matches!(
+        provider.prepare_backup("/blobs".to_owned()),
+        Err(BlobProviderError::InvalidPath)
+    ));
+    assert_eq!(provider.get(key(1)).unwrap(), Some(vec![1; 100]));
+}
@@ -0,0 +1,196 @@
+use std::{path::Path, sync::Arc};
+
+use indexed_blobs::{
+    blob_provider::{BlobProvider, BlobProviderOptions, new_blob_provider_with_storage},
+    perceptual_hash::{difference_hash, hamming_distance, perceptual_hash},
+    storage::mem_storage::MemStorage,
+    yuv::{PixelLayout, YuvPixelRange, YuvThumbnail},
+};
+
+fn key(id: u8) -> String {
+    format!("00000000-0000-0000-0000-{:012X}", id)
+}
+
+fn open(storage: &MemStorage) -> BlobProvider {
+    new_blob_provider_with_storage(
+        Arc::new(storage.clone()),
+        Path::new("/blobs"),
+        "thumbs".to_owned(),
+        BlobProviderOptions::default(),
+    )
+    .unwrap()
+}
+
+/// Gray thumbnail of `width` by `height` pixels whose brightness at (x, y), both
+/// scaled to 0..1, is `luma`
+fn thumbnail(width: u32, height: u32, luma: impl Fn(f32, f32) -> f32) -> YuvThumbnail {
+    let pixels = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| luma(x as f32 / width as f32, y as f32 / height as f32))
+        .flat_map(|luma| {
+            let luma = (luma * 255.0).clamp(0.0, 255.0) as u8;
+            [luma, luma, luma, 255]
+        })
+        .collect::<Vec<_>>();
+
+    YuvThumbnail::from_pixels(
+        &pixels,
+        width,
+        height,
+        width * 4,
+        PixelLayout::Rgba,
+        YuvPixelRange::Video,
+    )
+    .unwrap()
+}
+
+/// Smooth random texture: values at the corners of a 6x6 grid of cells picked from
+/// `seed`, blended across each cell
+fn texture(seed: u64) -> impl Fn(f32, f32) -> f32 {
+    let corner = move |x: u64, y: u64| {
+        let mut state = (seed * 7 + x) * 31 + y;
+        for _ in 0..2 {
+            state = (state ^ state >> 31).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+        (state >> 40) as f32 / (1 << 24) as f32
+    };
+
+    move |x, y| {
+        let (x, y) = (x * 6.0, y * 6.0);
+        let (cell_x, cell_y) = (x.floor(), y.floor());
+        let (dx, dy) = (x - cell_x, y - cell_y);
+        let (cell_x, cell_y) = (cell_x as u64, cell_y as u64);
+
+        let top = corner(cell_x, cell_y) * (1.0 - dx) + corner(cell_x + 1, cell_y) * dx;
+        let bottom = corner(cell_x, cell_y + 1) * (1.0 - dx) + corner(cell_x + 1, cell_y + 1) * dx;
+        top * (1.0 - dy) + bottom * dy
+    }
+}
+
+#[test]
+fn hashes_tolerate_small_changes() {
+    let original = thumbnail(96, 64, texture(1));
+    let rescaled = thumbnail(48, 32, texture(1));
+    let original_texture = texture(1);
+    let brightened = thumbnail(96, 64, |x, y| original_texture(x, y) * 0.9 + 0.08);
+    let different = thumbnail(96, 64, texture(2));
+
+    for hash in [perceptual_hash, difference_hash] {
+        let original = hash(&original).unwrap();
+        assert!(hamming_distance(original, hash(&rescaled).unwrap()) <= 10);
+        assert!(hamming_distance(original, hash(&brightened).unwrap()) <= 10);
+        assert!(hamming_distance(original, hash(&different).unwrap()) > 16);
+    }
+}
+
+#[test]
+fn near_duplicates_are_grouped() {
+    let storage = MemStorage::new();
+    let provider = open(&storage);
+
+    let record = |thumbnail: YuvThumbnail| thumbnail.encode().unwrap();
+    provider
+        .put(key(1), record(thumbnail(96, 64, texture(1))))
+        .unwrap();
+    provider
+        .put(key(2), record(thumbnail(48, 32, texture(1))))
+        .unwrap();
+    provider
+        .put(key(3), record(thumbnail(96, 64, texture(2))))
+        .unwrap();
+    let darker_texture = texture(2);
+    provider
+        .put(
+            key(4),
+            record(thumbnail(96, 64, |x, y| darker_texture(x, y) * 0.9 + 0.05)),
+        )
+        .unwrap();
+    provider
+        .put(key(5), record(thumbnail(96, 64, texture(3))))
+        .unwrap();
+    provider.put(key(6), vec![6; 100]).unwrap();
+
+    let groups = provider.find_near_duplicates(10).unwrap();
+    assert_eq!(groups, vec![vec![key(1), key(2)], vec![key(3), key(4)]]);
+    assert_eq!(provider.find_near_duplicates(u32::MAX).unwrap().len(), 1);
+    assert_eq!(
+        provider.find_near_duplicates(u32::MAX).unwrap()[0],
+        vec![key(1), key(2), key(3), key(4), key(5)]
+    );
+
+    // Replacing or deleting a blob drops it from its group
+    provider
+        .put(key(2), record(thumbnail(96, 64, texture(3))))
+        .unwrap();
+    provider.delete(key(4)).unwrap();
+    assert_eq!(
+        provider.find_near_duplicates(10).unwrap(),
+        vec![vec![key(2), key(5)]]
+    );
+
+    // Hashes survive compaction and reopening
+    provider.compact().unwrap();
+    drop(provider);
+    let provider = open(&storage);
+    assert_eq!(
+        provider.find_near_duplicates(10).unwrap(),
+        vec![vec![key(2), key(5)]]
+    );
+}
+
+#[test]
+fn grouping_matches_pairwise_comparison() {
+    let storage = MemStorage::new();
+    let provider = open(&storage);
+
+    let thumbnails = (0..40)
+        .map(|seed| thumbnail(32, 24, texture(seed)))
+        .collect::<Vec<_>>();
+    let hashes = thumbnails
+        .iter()
+        .map(|thumbnail| perceptual_hash(thumbnail).unwrap())
+        .collect::<Vec<_>>();
+    for (id, thumbnail) in thumbnails.iter().enumerate() {
+        provider
+            .put(key(id as u8), thumbnail.encode().unwrap())
+            .unwrap();
+    }
+
+    for max_hamming in [0, 16, 20, 22, 24, 64] {
+        // Connected components of every pair within `max_hamming`, by flooding
+        let mut group_of = (0..hashes.len()).collect::<Vec<_>>();
+        loop {
+            let mut changed = false;
+            for a in 0..hashes.len() {
+                for b in 0..hashes.len() {
+                    if hamming_distance(hashes[a], hashes[b]) <= max_hamming
+                        && group_of[b] < group_of[a]
+                    {
+                        group_of[a] = group_of[b];
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut expected = (0..hashes.len())
+            .map(|group| {
+                (0..hashes.len())
+                    .filter(|id| group_of[*id] == group)
+                    .map(|id| key(id as u8))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|keys| keys.len() > 1)
+            .collect::<Vec<_>>();
+        expected.sort();
+
+        assert_eq!(
+            provider.find_near_duplicates(max_hamming).unwrap(),
+            expected,
+            "{max_hamming}"
+        );
+    }
+}
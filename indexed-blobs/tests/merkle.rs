@@ -0,0 +1,132 @@
+use std::{path::Path, sync::Arc};
+
+use indexed_blobs::{
+    blob_provider::{BlobProvider, BlobProviderOptions, new_blob_provider_with_storage},
+    storage::{Storage, mem_storage::MemStorage},
+};
+
+fn key(id: u8) -> String {
+    format!("00000000-0000-0000-0000-{:012X}", id)
+}
+
+fn open(storage: &MemStorage, options: &BlobProviderOptions) -> BlobProvider {
+    new_blob_provider_with_storage(
+        Arc::new(storage.clone()),
+        Path::new("/blobs"),
+        "thumbs".to_owned(),
+        options.clone(),
+    )
+    .unwrap()
+}
+
+/// Writes keys 1 to 5, seals them by compacting and writes keys 6 to 8 into the
+/// active chunk, key 8 holding `last`
+fn fill(provider: &BlobProvider, last: u8) {
+    for id in 1..=5 {
+        provider.put(key(id), vec![id; 100 * id as usize]).unwrap();
+    }
+    provider.put(key(4), vec![0; 10]).unwrap();
+    provider.delete(key(5)).unwrap();
+    provider.compact().unwrap();
+
+    for id in 6..=7 {
+        provider.put(key(id), vec![id; 100]).unwrap();
+    }
+    provider.put(key(8), vec![last; 100]).unwrap();
+}
+
+#[test]
+fn equal_records_digest_equally() {
+    let plain = open(&MemStorage::new(), &BlobProviderOptions::default());
+    assert!(plain.chunk_digests().unwrap().is_empty());
+    assert_eq!(
+        plain.merkle_root().unwrap(),
+        blake3::hash(&[]).as_bytes().to_vec()
+    );
+
+    // Same records, stored differently
+    let packed = open(
+        &MemStorage::new(),
+        &BlobProviderOptions {
+            deduplicate: true,
+            inline_max_bytes: 150,
+            ..Default::default()
+        },
+    );
+    fill(&plain, 8);
+    fill(&packed, 8);
+    // Rewriting a blob with its own bytes changes nothing
+    packed.put(key(7), vec![7; 100]).unwrap();
+
+    let digests = plain.chunk_digests().unwrap();
+    assert_eq!(digests.len(), 2);
+    assert_eq!(digests[0].num_records, 4);
+    assert_eq!(digests[0].first_key, Some(key(1)));
+    assert_eq!(digests[0].last_key, Some(key(4)));
+    assert_eq!(digests[1].num_records, 3);
+    assert_eq!(digests[0].digest.len(), 32);
+    assert_eq!(packed.chunk_digests().unwrap(), digests);
+    assert_eq!(packed.merkle_root().unwrap(), plain.merkle_root().unwrap());
+
+    let diff = plain.diff_against(digests.clone()).unwrap();
+    assert!(diff.local_only.is_empty() && diff.remote_only.is_empty());
+
+    // The root follows every write
+    let root = plain.merkle_root().unwrap();
+    plain.put(key(9), vec![9; 10]).unwrap();
+    assert_ne!(plain.merkle_root().unwrap(), root);
+    plain.delete(key(9)).unwrap();
+    assert_ne!(plain.merkle_root().unwrap(), root);
+}
+
+#[test]
+fn diff_lists_differing_chunks() {
+    let local = open(&MemStorage::new(), &BlobProviderOptions::default());
+    let remote = open(&MemStorage::new(), &BlobProviderOptions::default());
+    fill(&local, 8);
+    fill(&remote, 80);
+    assert_ne!(local.merkle_root().unwrap(), remote.merkle_root().unwrap());
+
+    let remote_digests = remote.chunk_digests().unwrap();
+    let diff = local.diff_against(remote_digests.clone()).unwrap();
+    assert_eq!(
+        diff.local_only,
+        vec![local.chunk_digests().unwrap()[1].clone()]
+    );
+    assert_eq!(diff.remote_only, vec![remote_digests[1].clone()]);
+    assert_eq!(diff.local_only[0].first_key, Some(key(6)));
+    assert_eq!(diff.local_only[0].last_key, Some(key(8)));
+
+    // Sealed into a chunk of another number, the records still match
+    remote.compact().unwrap();
+    local.put(key(8), vec![80; 100]).unwrap();
+    local.compact().unwrap();
+    local.compact().unwrap();
+    let diff = local.diff_against(remote.chunk_digests().unwrap()).unwrap();
+    assert!(diff.local_only.is_empty() && diff.remote_only.is_empty());
+    assert_ne!(
+        local.chunk_digests().unwrap()[0].chunk,
+        remote.chunk_digests().unwrap()[0].chunk
+    );
+}
+
+#[test]
+fn sealed_digests_survive_reopening() {
+    let storage = MemStorage::new();
+    let options = BlobProviderOptions::default();
+    let provider = open(&storage, &options);
+    fill(&provider, 8);
+    let digests = provider.chunk_digests().unwrap();
+    let root = provider.merkle_root().unwrap();
+    drop(provider);
+
+    let provider = open(&storage, &options);
+    assert_eq!(provider.chunk_digests().unwrap(), digests);
+    drop(provider);
+
+    // Stores from before digests existed compute them on first use
+    storage.remove(Path::new("/blobs/thumbs.sum")).unwrap();
+    let provider = open(&storage, &options);
+    assert_eq!(provider.chunk_digests().unwrap(), digests);
+    assert_eq!(provider.merkle_root().unwrap(), root);
+}
@@ -0,0 +1,1233 @@
+use std::{collections::HashMap, io, path::Path, sync::Arc};
+
+use indexed_blobs::{
+    batch::BlobBatchEntry,
+    blob_provider::{BlobProvider, BlobProviderOptions, new_blob_provider_with_storage},
+    err_type::{BlobProviderError, IoErrorKind},
+    storage::{
+        OpenMode, Storage,
+        mem_storage::{CrashMode, Fault, MemStorage},
+    },
+    yuv::{PixelLayout, YuvPixelRange, YuvThumbnail},
+};
+
+const ROOT: &str = "/blobs";
+const PREFIX: &str = "thumbs";
+
+#[derive(Debug, Clone)]
+enum Op {
+    Put(u8, Vec<u8>),
+    Stream(u8, Vec<u8>),
+    Delete(u8),
+    Compact,
+}
+
+fn key(id: u8) -> String {
+    format!("00000000-0000-0000-0000-{:012X}", id)
+}
+
+fn thumbnail_record() -> Vec<u8> {
+    let pixels = [[200, 30, 30, 255], [30, 30, 200, 255]]
+        .repeat(32 * 24)
+        .concat();
+    YuvThumbnail::from_pixels(
+        &pixels,
+        64,
+        24,
+        64 * 4,
+        PixelLayout::Rgba,
+        YuvPixelRange::Video,
+    )
+    .unwrap()
+    .encode()
+    .unwrap()
+}
+
+fn open(storage: &MemStorage, options: &BlobProviderOptions) -> Arc<BlobProvider> {
+    Arc::new(
+        new_blob_provider_with_storage(
+            Arc::new(storage.clone()),
+            Path::new(ROOT),
+            PREFIX.to_owned(),
+            options.clone(),
+        )
+        .expect("provider should reopen after any fault"),
+    )
+}
+
+fn workload() -> Vec<Op> {
+    vec![
+        Op::Put(1, vec![1; 100]),
+        Op::Put(2, vec![2; 50]),
+        Op::Stream(3, vec![3; 300]),
+        Op::Put(1, vec![4; 80]),
+        Op::Delete(2),
+        Op::Compact,
+        Op::Put(4, vec![3; 300]),
+        Op::Stream(5, vec![5; 20]),
+        Op::Delete(1),
+        Op::Put(2, vec![6; 40]),
+        Op::Compact,
+        Op::Put(6, vec![7; 10]),
+    ]
+}
+
+fn apply(provider: &Arc<BlobProvider>, op: &Op) -> Result<(), BlobProviderError> {
+    match op {
+        Op::Put(id, data) => provider.put(key(*id), data.clone()),
+        Op::Stream(id, data) => {
+            let writer = provider
+                .clone()
+                .begin_write(key(*id), Some(data.len() as u64))?;
+            for part in data.chunks(64) {
+                writer.write(part.to_vec())?;
+            }
+            writer.commit()
+        }
+        Op::Delete(id) => provider.delete(key(*id)).map(|_| ()),
+        Op::Compact => provider.compact().map(|_| ()),
+    }
+}
+
+/// Runs the workload until an operation fails, returning the contents every
+/// acknowledged operation produced and the operation that failed
+fn run(provider: &Arc<BlobProvider>) -> (HashMap<u8, Vec<u8>>, Option<Op>) {
+    let mut model = HashMap::new();
+
+    for op in workload() {
+        if apply(provider, &op).is_err() {
+            return (model, Some(op));
+        }
+
+        match op {
+            Op::Put(id, data) | Op::Stream(id, data) => {
+                model.insert(id, data);
+            }
+            Op::Delete(id) => {
+                model.remove(&id);
+            }
+            Op::Compact => {}
+        }
+    }
+
+    (model, None)
+}
+
+/// Checks that every acknowledged write survived, and that the failed operation either
+/// fully happened or not at all
+fn verify(provider: &BlobProvider, model: &HashMap<u8, Vec<u8>>, failed: Option<&Op>) {
+    for id in 1..=7 {
+        let stored = provider.get(key(id)).unwrap();
+        let acknowledged = model.get(&id).cloned();
+
+        let attempted = match failed {
+            Some(Op::Put(failed_id, data) | Op::Stream(failed_id, data)) if *failed_id == id => {
+                Some(Some(data.clone()))
+            }
+            Some(Op::Delete(failed_id)) if *failed_id == id => Some(None),
+            _ => None,
+        };
+
+        assert!(
+            stored == acknowledged || attempted.is_some_and(|attempted| stored == attempted),
+            "key {id} holds {:?} bytes after failing {failed:?}",
+            stored.map(|data| data.len()),
+        );
+    }
+}
+
+/// Runs the workload again and again, failing the `n`th mutation of every run with
+/// `fault`, then reopens and checks the surviving contents
+fn fail_at_every_mutation(fault: Fault, mode: CrashMode, options: BlobProviderOptions) {
+    let clean = MemStorage::new();
+    let provider = open(&clean, &options);
+    let before = clean.mutations();
+    assert!(run(&provider).1.is_none());
+    let mutations = clean.mutations() - before;
+
+    for n in 0..mutations {
+        let storage = MemStorage::new();
+        let provider = open(&storage, &options);
+
+        storage.inject_fault(n, fault);
+        let (model, failed) = run(&provider);
+        // Bookkeeping after a commit, like remembering a content hash, can fail
+        // without failing the acknowledged write
+        assert!(
+            failed.is_some() || storage.has_crashed(),
+            "mutation {n} was never reached"
+        );
+
+        drop(provider);
+        storage.restart(mode);
+
+        let provider = open(&storage, &options);
+        verify(&provider, &model, failed.as_ref());
+
+        // The recovered provider must keep accepting durable writes
+        provider.put(key(7), vec![9; 30]).unwrap();
+        drop(provider);
+        storage.restart(CrashMode::DropUnsynced);
+        assert_eq!(
+            open(&storage, &options).get(key(7)).unwrap(),
+            Some(vec![9; 30])
+        );
+    }
+}
+
+#[test]
+fn crash_dropping_unsynced_writes() {
+    fail_at_every_mutation(
+        Fault::Crash,
+        CrashMode::DropUnsynced,
+        BlobProviderOptions::default(),
+    );
+}
+
+#[test]
+fn crash_keeping_torn_writes() {
+    fail_at_every_mutation(
+        Fault::Crash,
+        CrashMode::KeepUnsynced,
+        BlobProviderOptions::default(),
+    );
+}
+
+#[test]
+fn crash_with_deduplication() {
+    let options = BlobProviderOptions {
+        deduplicate: true,
+        ..Default::default()
+    };
+
+    fail_at_every_mutation(Fault::Crash, CrashMode::DropUnsynced, options.clone());
+    fail_at_every_mutation(Fault::Crash, CrashMode::KeepUnsynced, options);
+}
+
+#[test]
+fn crash_with_inline_blobs() {
+    let options = BlobProviderOptions {
+        deduplicate: true,
+        inline_max_bytes: 64,
+        ..Default::default()
+    };
+
+    fail_at_every_mutation(Fault::Crash, CrashMode::DropUnsynced, options.clone());
+    fail_at_every_mutation(Fault::Crash, CrashMode::KeepUnsynced, options);
+}
+
+#[test]
+fn short_write_then_reopen() {
+    fail_at_every_mutation(
+        Fault::ShortWrite,
+        CrashMode::KeepUnsynced,
+        BlobProviderOptions::default(),
+    );
+}
+
+#[test]
+fn no_space_fails_the_write() {
+    let storage = MemStorage::new();
+    let provider = open(&storage, &BlobProviderOptions::default());
+    provider.put(key(1), vec![1; 10]).unwrap();
+
+    storage.inject_fault(0, Fault::NoSpace);
+    match provider.put(key(2), vec![2; 10]) {
+        Err(BlobProviderError::IoError {
+            kind,
+            path,
+            offset,
+            source,
+        }) => {
+            assert_eq!(kind, IoErrorKind::StorageFull);
+            assert_eq!(path.as_deref(), Some("/blobs/thumbs_0.dat"));
+            assert_eq!(offset, Some(10));
+            assert_eq!(source.0.kind(), io::ErrorKind::StorageFull);
+            assert!(
+                source
+                    .to_string()
+                    .starts_with("/blobs/thumbs_0.dat at offset 10: "),
+                "{source}"
+            );
+        }
+        result => panic!("expected a storage full error, got {result:?}"),
+    }
+
+    drop(provider);
+    storage.restart(CrashMode::KeepUnsynced);
+
+    let provider = open(&storage, &BlobProviderOptions::default());
+    assert_eq!(provider.get(key(1)).unwrap(), Some(vec![1; 10]));
+    assert_eq!(provider.get(key(2)).unwrap(), None);
+}
+
+/// Runs the workload again and again, failing its `n`th mutation with `NoSpace`, then
+/// keeps writing and reopens the provider without a restart, as an app does once
+/// space was freed up
+#[test]
+fn no_space_at_every_mutation_keeps_the_provider_usable() {
+    let options = BlobProviderOptions::default();
+    let clean = MemStorage::new();
+    let provider = open(&clean, &options);
+    let before = clean.mutations();
+    assert!(run(&provider).1.is_none());
+    let mutations = clean.mutations() - before;
+
+    for n in 0..mutations {
+        let storage = MemStorage::new();
+        let provider = open(&storage, &options);
+
+        storage.inject_fault(n, Fault::NoSpace);
+        let (model, failed) = run(&provider);
+        verify(&provider, &model, failed.as_ref());
+
+        provider.put(key(8), vec![9; 30]).unwrap();
+        drop(provider);
+
+        let provider = open(&storage, &options);
+        verify(&provider, &model, failed.as_ref());
+        assert_eq!(provider.get(key(8)).unwrap(), Some(vec![9; 30]), "{n}");
+
+        // A seal that failed is done again by the next one
+        provider.compact().unwrap();
+        verify(&provider, &model, failed.as_ref());
+        assert_eq!(provider.get(key(8)).unwrap(), Some(vec![9; 30]), "{n}");
+    }
+}
+
+#[test]
+fn uncommitted_tail_is_discarded_on_open() {
+    let storage = MemStorage::new();
+    let provider = open(&storage, &BlobProviderOptions::default());
+    provider.put(key(1), vec![1; 10]).unwrap();
+
+    // Dies after writing the blob and its record, before they are synced and published
+    storage.inject_fault(2, Fault::Crash);
+    assert!(provider.put(key(2), vec![2; 10]).is_err());
+
+    drop(provider);
+    storage.restart(CrashMode::KeepUnsynced);
+
+    let provider = open(&storage, &BlobProviderOptions::default());
+    assert_eq!(provider.get(key(2)).unwrap(), None);
+    assert_eq!(provider.stats().unwrap().dat_bytes, 10);
+
+    provider.put(key(3), vec![3; 10]).unwrap();
+    assert_eq!(provider.get(key(1)).unwrap(), Some(vec![1; 10]));
+    assert_eq!(provider.get(key(3)).unwrap(), Some(vec![3; 10]));
+}
+
+#[test]
+fn unknown_midx_contents_are_rejected() {
+    let midx_path = Path::new(ROOT).join(format!("{PREFIX}.midx"));
+
+    for (offset, bytes, reason) in [
+        (6, [2u8, 0], "unknown version 2"),
+        (6, [0, 0], "unknown version 0"),
+        (4, [4, 0], "unknown flags 0x0004"),
+    ] {
+        let storage = MemStorage::new();
+        open(&storage, &BlobProviderOptions::default())
+            .put(key(1), vec![1; 10])
+            .unwrap();
+
+        let midx = storage.open(&midx_path, OpenMode::ReadWrite).unwrap();
+        midx.write_at(&bytes, offset).unwrap();
+
+        let reopened = new_blob_provider_with_storage(
+            Arc::new(storage.clone()),
+            Path::new(ROOT),
+            PREFIX.to_owned(),
+            BlobProviderOptions::default(),
+        );
+        match reopened {
+            Err(BlobProviderError::InvalidMIdx {
+                chunk: Some(0),
+                reason: actual,
+            }) => assert_eq!(actual, reason),
+            Err(err) => panic!("expected an invalid midx error, got {err:?}"),
+            Ok(_) => panic!("expected an invalid midx error"),
+        }
+    }
+}
+
+#[test]
+fn batches_are_all_or_nothing() {
+    let batch = || {
+        (1..=4)
+            .map(|id| BlobBatchEntry {
+                key: key(id),
+                data: vec![id; 40 * id as usize],
+                secondary: Some(100 + id as i64),
+            })
+            .collect::<Vec<_>>()
+    };
+    // Key 1 starts out as a thumbnail with a secondary key and a placeholder
+    let prepare = |provider: &BlobProvider| {
+        provider
+            .put_with_secondary(key(1), thumbnail_record(), 7)
+            .unwrap();
+        assert_eq!(provider.backfill_placeholders().unwrap(), 1);
+        provider.get_placeholder(key(1)).unwrap().unwrap()
+    };
+
+    let clean = MemStorage::new();
+    let provider = open(&clean, &BlobProviderOptions::default());
+    prepare(&provider);
+    let before = clean.mutations();
+    provider.write_batch(batch()).unwrap();
+    let mutations = clean.mutations() - before;
+
+    for n in 0..mutations {
+        let storage = MemStorage::new();
+        let provider = open(&storage, &BlobProviderOptions::default());
+        let placeholder = prepare(&provider);
+
+        storage.inject_fault(n, Fault::Crash);
+        assert!(provider.write_batch(batch()).is_err());
+        assert_eq!(
+            provider.range_by_secondary(0, 1000, 10).unwrap(),
+            [key(1)],
+            "mutation {n}"
+        );
+        assert_eq!(
+            provider.get_placeholder(key(1)).unwrap(),
+            Some(placeholder.clone()),
+            "mutation {n}"
+        );
+
+        drop(provider);
+        storage.restart(CrashMode::KeepUnsynced);
+
+        let provider = open(&storage, &BlobProviderOptions::default());
+        let applied = provider.contains(key(4)).unwrap();
+        for id in 1..=4 {
+            let expected = match (applied, id) {
+                (true, _) => Some(vec![id; 40 * id as usize]),
+                (false, 1) => Some(thumbnail_record()),
+                (false, _) => None,
+            };
+            assert_eq!(provider.get(key(id)).unwrap(), expected, "mutation {n}");
+        }
+
+        let (secondary_keys, placeholder) = match applied {
+            true => ((1..=4).map(key).collect(), None),
+            false => (vec![key(1)], Some(placeholder)),
+        };
+        assert_eq!(
+            provider.range_by_secondary(0, 1000, 10).unwrap(),
+            secondary_keys,
+            "mutation {n}"
+        );
+        assert_eq!(
+            provider.get_placeholder(key(1)).unwrap(),
+            placeholder,
+            "mutation {n}"
+        );
+    }
+}
+
+#[test]
+fn inline_blobs_never_touch_dat_files() {
+    let options = BlobProviderOptions {
+        inline_max_bytes: 32,
+        ..Default::default()
+    };
+    let storage = MemStorage::new();
+    let provider = open(&storage, &options);
+
+    for id in 1..=4 {
+        provider.put(key(id), vec![id; 8 * id as usize]).unwrap();
+    }
+    provider.delete(key(2)).unwrap();
+    let stats = provider.compact().unwrap();
+    assert_eq!((stats.inline_bytes, stats.dat_bytes), (64, 0));
+    drop(provider);
+
+    for file_name in storage.list(Path::new(ROOT)).unwrap() {
+        if file_name.ends_with(".dat") {
+            storage.remove(&Path::new(ROOT).join(file_name)).unwrap();
+        }
+    }
+
+    let provider = open(&storage, &options);
+    assert_eq!(provider.get(key(1)).unwrap(), Some(vec![1; 8]));
+    assert_eq!(provider.get(key(2)).unwrap(), None);
+    assert_eq!(
+        provider.get_range(key(3), 5, 15).unwrap(),
+        Some(vec![3; 15])
+    );
+}
+
+#[test]
+fn key_filter_is_rebuilt_when_unusable() {
+    let filter_path = Path::new(ROOT).join(format!("{PREFIX}.bloom"));
+
+    for corrupt in [None, Some(vec![]), Some(vec![7; 40])] {
+        let storage = MemStorage::new();
+        let provider = open(&storage, &BlobProviderOptions::default());
+        provider.put(key(1), vec![1; 10]).unwrap();
+        provider.compact().unwrap();
+        // Only in the active chunk, never persisted in the filter
+        provider.put(key(2), vec![2; 10]).unwrap();
+        drop(provider);
+
+        match &corrupt {
+            None => storage.remove(&filter_path).unwrap(),
+            Some(bytes) => {
+                let filter = storage.open(&filter_path, OpenMode::Truncate).unwrap();
+                filter.write_at(bytes, 0).unwrap();
+            }
+        }
+
+        let provider = open(&storage, &BlobProviderOptions::default());
+        assert_eq!(provider.get(key(1)).unwrap(), Some(vec![1; 10]));
+        assert_eq!(provider.get(key(2)).unwrap(), Some(vec![2; 10]));
+        assert!(!provider.contains(key(3)).unwrap());
+    }
+}
+
+#[test]
+fn key_filter_grows_when_a_chunk_is_sealed() {
+    let filter_path = Path::new(ROOT).join(format!("{PREFIX}.bloom"));
+    let storage = MemStorage::new();
+    let provider = open(&storage, &BlobProviderOptions::default());
+    // More keys than an empty provider's filter is sized for
+    let keys = (0..3000)
+        .map(|id| format!("00000000-0000-0000-0000-{:012X}", id))
+        .collect::<Vec<_>>();
+    for key in &keys {
+        provider.put(key.clone(), vec![1; 10]).unwrap();
+    }
+
+    // Backups seal the active chunk
+    provider.prepare_backup("/staging".to_owned()).unwrap();
+    let filter = storage.open(&filter_path, OpenMode::Read).unwrap();
+    let mut header = [0; 16];
+    filter.read_at(&mut header, 0).unwrap();
+    let capacity = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let num_keys = u64::from_le_bytes(header[8..].try_into().unwrap());
+    assert!(
+        capacity >= 3000 && num_keys <= capacity,
+        "{num_keys} keys in a filter for {capacity}"
+    );
+
+    drop(provider);
+    let provider = open(&storage, &BlobProviderOptions::default());
+    for key in &keys {
+        assert!(provider.contains(key.clone()).unwrap());
+    }
+}
+
+#[test]
+fn reads_see_whole_blobs_while_compaction_deletes_chunks() {
+    let storage = MemStorage::new();
+    let options = BlobProviderOptions {
+        cache_budget_bytes: 1 << 20,
+        ..Default::default()
+    };
+    let provider = open(&storage, &options);
+    for id in 0..8 {
+        provider.put(key(id), vec![0; 64]).unwrap();
+    }
+
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let readers = (0..4)
+        .map(|_| {
+            let provider = provider.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    for id in 0..8 {
+                        let data = provider.get(key(id)).unwrap().unwrap();
+                        assert_eq!(data.len(), 64);
+                        assert!(data.iter().all(|byte| *byte == data[0]));
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for round in 1..=20 {
+        for id in 0..8 {
+            provider.put(key(id), vec![round; 64]).unwrap();
+        }
+        provider.compact().unwrap();
+    }
+    done.store(true, std::sync::atomic::Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    // Nothing stale was left in the cache by a read racing a write
+    for id in 0..8 {
+        assert_eq!(provider.get(key(id)).unwrap(), Some(vec![20; 64]));
+    }
+}
+
+#[test]
+fn group_commit_only_loses_unflushed_writes() {
+    let storage = MemStorage::new();
+    let options = BlobProviderOptions {
+        group_commit_max_writes: 3,
+        ..Default::default()
+    };
+    let provider = open(&storage, &options);
+
+    // The third write commits the group
+    for id in 1..=3 {
+        provider.put(key(id), vec![id; 20]).unwrap();
+    }
+    provider.put(key(4), vec![4; 20]).unwrap();
+    assert_eq!(provider.durable_sequence().unwrap(), 3);
+    assert_eq!(provider.flush().unwrap(), 4);
+
+    // Staged writes are readable before they are durable
+    provider.put(key(5), vec![5; 20]).unwrap();
+    assert_eq!(provider.get(key(5)).unwrap(), Some(vec![5; 20]));
+
+    storage.restart(CrashMode::DropUnsynced);
+    drop(provider);
+
+    let provider = open(&storage, &options);
+    for id in 1..=4 {
+        assert_eq!(provider.get(key(id)).unwrap(), Some(vec![id; 20]));
+    }
+    assert_eq!(provider.get(key(5)).unwrap(), None);
+    assert!(provider.durable_sequence().unwrap() >= 4);
+
+    // Dropping the provider commits what is still staged
+    provider.put(key(5), vec![5; 20]).unwrap();
+    drop(provider);
+    storage.restart(CrashMode::DropUnsynced);
+    assert_eq!(
+        open(&storage, &options).get(key(5)).unwrap(),
+        Some(vec![5; 20])
+    );
+}
+
+#[test]
+fn sequence_never_goes_backwards_across_restarts() {
+    let storage = MemStorage::new();
+    let options = BlobProviderOptions {
+        group_commit_max_writes: 3,
+        ..Default::default()
+    };
+
+    // Commits staged before a crash took sequence numbers too, without a flush
+    let mut handed_out = 0;
+    for round in 0..3u8 {
+        let provider = open(&storage, &options);
+        let durable = provider.durable_sequence().unwrap();
+        assert!(durable >= handed_out, "round {round}");
+
+        for id in 1..=4 {
+            provider.put(key(id), vec![round; 20]).unwrap();
+        }
+        let flushed = provider.flush().unwrap();
+        assert!(flushed > durable, "round {round}");
+        provider.put(key(5), vec![round; 20]).unwrap();
+        handed_out = flushed + 1;
+
+        storage.restart(CrashMode::DropUnsynced);
+        drop(provider);
+    }
+
+    // A clean shutdown counts on too
+    let provider = open(&storage, &options);
+    provider.put(key(1), vec![9; 20]).unwrap();
+    let flushed = provider.flush().unwrap();
+    assert!(flushed > handed_out);
+    drop(provider);
+    assert!(open(&storage, &options).durable_sequence().unwrap() >= flushed);
+}
+
+#[test]
+fn seals_keep_flushed_writes_with_staged_ones_behind() {
+    let options = BlobProviderOptions {
+        group_commit_max_writes: 100,
+        ..Default::default()
+    };
+    // Staged keys sort before the flushed ones, so the sorted `.idx` doesn't start
+    // with the records the midx committed
+    let write = |provider: &BlobProvider| {
+        for id in 5..=9 {
+            provider.put(key(id), vec![id; 20]).unwrap();
+        }
+        provider.flush().unwrap();
+        for id in 1..=4 {
+            provider.put(key(id), vec![id; 20]).unwrap();
+        }
+    };
+
+    let clean = MemStorage::new();
+    let provider = open(&clean, &options);
+    write(&provider);
+    let before = clean.mutations();
+    provider.compact().unwrap();
+    let mutations = clean.mutations() - before;
+
+    for n in 0..mutations {
+        let storage = MemStorage::new();
+        let provider = open(&storage, &options);
+        write(&provider);
+
+        storage.inject_fault(n, Fault::Crash);
+        assert!(provider.compact().is_err());
+        drop(provider);
+        storage.restart(CrashMode::DropUnsynced);
+
+        let provider = open(&storage, &options);
+        for id in 1..=9 {
+            let stored = provider.get(key(id)).unwrap();
+            match id {
+                5..=9 => assert_eq!(stored, Some(vec![id; 20]), "key {id} at {n}"),
+                _ => assert!(stored.is_none_or(|data| data == vec![id; 20])),
+            }
+        }
+    }
+}
+
+#[test]
+fn group_commit_after_delay() {
+    let storage = MemStorage::new();
+    let options = BlobProviderOptions {
+        group_commit_max_writes: 1000,
+        group_commit_max_delay_ms: 10,
+        ..Default::default()
+    };
+    let provider = open(&storage, &options);
+
+    provider.put(key(1), vec![1; 20]).unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while provider.durable_sequence().unwrap() < 1 {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "nothing committed the group"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+
+    storage.restart(CrashMode::DropUnsynced);
+    drop(provider);
+    assert_eq!(
+        open(&storage, &options).get(key(1)).unwrap(),
+        Some(vec![1; 20])
+    );
+}
+
+#[test]
+fn range_reads_are_bounds_checked() {
+    let cached = BlobProviderOptions {
+        cache_budget_bytes: 1 << 20,
+        ..Default::default()
+    };
+    let inline = BlobProviderOptions {
+        inline_max_bytes: 64,
+        ..Default::default()
+    };
+
+    for options in [BlobProviderOptions::default(), cached, inline] {
+        let provider = open(&MemStorage::new(), &options);
+        let data = (0..40).collect::<Vec<u8>>();
+        provider.put(key(1), data.clone()).unwrap();
+        // Served from the cache when there is one
+        provider.get(key(1)).unwrap();
+
+        assert_eq!(
+            provider.get_range(key(1), 10, 5).unwrap(),
+            Some(data[10..15].to_vec())
+        );
+        assert_eq!(provider.get_range(key(1), 0, 40).unwrap(), Some(data));
+        assert_eq!(provider.get_range(key(1), 40, 0).unwrap(), Some(vec![]));
+        assert_eq!(provider.get_range(key(2), 0, 1).unwrap(), None);
+
+        for (offset, len) in [(40, 1), (39, 2), (0, 41), (u64::MAX, 2), (1, u64::MAX)] {
+            assert!(
+                matches!(
+                    provider.get_range(key(1), offset, len),
+                    Err(BlobProviderError::RangeOutOfBounds(o, l, 40)) if (o, l) == (offset, len)
+                ),
+                "range {offset}+{len}"
+            );
+        }
+    }
+}
+
+#[test]
+fn stray_files_of_other_prefixes_are_kept() {
+    let storage = MemStorage::new();
+    let open_prefix = |prefix: &str| {
+        Arc::new(
+            new_blob_provider_with_storage(
+                Arc::new(storage.clone()),
+                Path::new(ROOT),
+                prefix.to_owned(),
+                BlobProviderOptions::default(),
+            )
+            .unwrap(),
+        )
+    };
+    let exists = |file_name: &str| {
+        storage
+            .list(Path::new(ROOT))
+            .unwrap()
+            .iter()
+            .any(|name| name == file_name)
+    };
+    let create = |file_name: &str| {
+        storage
+            .open(&Path::new(ROOT).join(file_name), OpenMode::Truncate)
+            .unwrap();
+    };
+
+    let other = open_prefix("a_b");
+    let writer = other.clone().begin_write(key(1), None).unwrap();
+    writer.write(vec![1; 10]).unwrap();
+    let others = [
+        "a_b.sidx.tmp",
+        "a_b_0.idx.tmp",
+        "a_5.midx",
+        "a.sidx.tmp.old",
+    ];
+    for file_name in others {
+        create(file_name);
+    }
+
+    let strays = ["a_staging3.tmp", "a.sidx.tmp", "a_0.idx.tmp", "a_9.dat"];
+    for file_name in strays {
+        create(file_name);
+    }
+
+    open_prefix("a").put(key(2), vec![2; 10]).unwrap();
+    for file_name in strays {
+        assert!(!exists(file_name), "{file_name} should be removed");
+    }
+    for file_name in others.iter().chain(&["a_b_staging0.tmp"]) {
+        assert!(exists(file_name), "{file_name} should be kept");
+    }
+
+    writer.commit().unwrap();
+    assert_eq!(other.get(key(1)).unwrap(), Some(vec![1; 10]));
+}
+
+#[test]
+fn deduplicated_bytes_outlive_every_key_but_the_last() {
+    let options = BlobProviderOptions {
+        deduplicate: true,
+        ..Default::default()
+    };
+    let storage = MemStorage::new();
+    let provider = open(&storage, &options);
+
+    for id in 1..=3 {
+        provider.put(key(id), vec![7; 100]).unwrap();
+    }
+    provider.put(key(4), vec![4; 50]).unwrap();
+    let stats = provider.stats().unwrap();
+    assert_eq!(stats.num_blobs, 4);
+    assert_eq!(stats.logical_bytes, 350);
+    assert_eq!(stats.stored_bytes, 150);
+    assert_eq!(stats.deduplicated_bytes, 200);
+    assert_eq!(stats.dat_bytes, 150);
+
+    // The bytes stay while any key references them, across compaction and reopening
+    provider.delete(key(1)).unwrap();
+    provider.put(key(2), vec![2; 30]).unwrap();
+    let stats = provider.compact().unwrap();
+    assert_eq!(stats.deduplicated_bytes, 0);
+    assert_eq!(stats.dat_bytes, 180);
+    drop(provider);
+
+    let provider = open(&storage, &options);
+    assert_eq!(provider.get(key(3)).unwrap(), Some(vec![7; 100]));
+    provider.put(key(5), vec![7; 100]).unwrap();
+    let stats = provider.stats().unwrap();
+    assert_eq!(stats.deduplicated_bytes, 100);
+    assert_eq!(stats.dat_bytes, 180);
+
+    provider.delete(key(3)).unwrap();
+    provider.delete(key(5)).unwrap();
+    let stats = provider.compact().unwrap();
+    assert_eq!((stats.num_blobs, stats.dat_bytes), (2, 80));
+    assert_eq!(provider.get(key(3)).unwrap(), None);
+
+    // Nothing references the old bytes anymore, so they are stored again
+    provider.put(key(6), vec![7; 100]).unwrap();
+    assert_eq!(provider.get(key(6)).unwrap(), Some(vec![7; 100]));
+    assert_eq!(provider.stats().unwrap().dat_bytes, 180);
+}
+
+#[test]
+fn failed_compactions_keep_deduplicating() {
+    let options = BlobProviderOptions {
+        deduplicate: true,
+        ..Default::default()
+    };
+    let write = |provider: &BlobProvider| {
+        for id in 1..=3 {
+            provider.put(key(id), vec![7; 100]).unwrap();
+        }
+        provider.put(key(4), vec![4; 50]).unwrap();
+        provider.delete(key(1)).unwrap();
+        provider.put(key(2), vec![2; 30]).unwrap();
+    };
+    let check = |provider: &BlobProvider, n: u64| {
+        assert_eq!(provider.get(key(1)).unwrap(), None, "{n}");
+        assert_eq!(provider.get(key(2)).unwrap(), Some(vec![2; 30]), "{n}");
+        assert_eq!(provider.get(key(3)).unwrap(), Some(vec![7; 100]), "{n}");
+        assert_eq!(provider.get(key(4)).unwrap(), Some(vec![4; 50]), "{n}");
+    };
+
+    let clean = MemStorage::new();
+    let provider = open(&clean, &options);
+    write(&provider);
+    let before = clean.mutations();
+    provider.compact().unwrap();
+    let mutations = clean.mutations() - before;
+
+    for n in 0..mutations {
+        let storage = MemStorage::new();
+        let provider = open(&storage, &options);
+        write(&provider);
+
+        storage.inject_fault(n, Fault::NoSpace);
+        let _ = provider.compact();
+        check(&provider, n);
+
+        provider.put(key(5), vec![5; 60]).unwrap();
+        let dat_bytes = provider.stats().unwrap().dat_bytes;
+        provider.put(key(6), vec![5; 60]).unwrap();
+        assert_eq!(provider.stats().unwrap().dat_bytes, dat_bytes, "{n}");
+        drop(provider);
+
+        let provider = open(&storage, &options);
+        check(&provider, n);
+        let stats = provider.compact().unwrap();
+        check(&provider, n);
+        assert_eq!(provider.get(key(6)).unwrap(), Some(vec![5; 60]), "{n}");
+        assert_eq!(stats.deduplicated_bytes, 60, "{n}");
+        assert_eq!(stats.dat_bytes, 240, "{n}");
+    }
+}
+
+#[test]
+fn cache_follows_writes_and_its_budget() {
+    let options = BlobProviderOptions {
+        cache_budget_bytes: 250,
+        ..Default::default()
+    };
+    let provider = open(&MemStorage::new(), &options);
+    let cache_stats = || provider.cache_stats().unwrap().unwrap();
+    for id in 1..=3 {
+        provider.put(key(id), vec![id; 100]).unwrap();
+    }
+
+    provider.get(key(1)).unwrap();
+    provider.get(key(1)).unwrap();
+    let stats = cache_stats();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert_eq!((stats.cached_blobs, stats.cached_bytes), (1, 100));
+
+    // Over budget, the least recently read blob goes first
+    provider.get(key(2)).unwrap();
+    provider.get(key(1)).unwrap();
+    provider.get(key(3)).unwrap();
+    assert_eq!(cache_stats().cached_bytes, 200);
+    let misses = cache_stats().misses;
+    provider.get(key(1)).unwrap();
+    assert_eq!(cache_stats().misses, misses);
+    provider.get(key(2)).unwrap();
+    assert_eq!(cache_stats().misses, misses + 1);
+
+    // Writes never leave a stale blob behind
+    provider.put(key(2), vec![20; 50]).unwrap();
+    assert_eq!(provider.get(key(2)).unwrap(), Some(vec![20; 50]));
+    assert_eq!(provider.get(key(2)).unwrap(), Some(vec![20; 50]));
+    provider.delete(key(2)).unwrap();
+    assert_eq!(provider.get(key(2)).unwrap(), None);
+    assert_eq!(provider.get_range(key(2), 0, 1).unwrap(), None);
+
+    provider.trim_cache(60).unwrap();
+    assert!(cache_stats().cached_bytes <= 60);
+    provider.trim_cache(0).unwrap();
+    let stats = cache_stats();
+    assert_eq!((stats.cached_blobs, stats.cached_bytes), (0, 0));
+    assert_eq!(stats.budget_bytes, 250);
+    assert_eq!(provider.get(key(3)).unwrap(), Some(vec![3; 100]));
+
+    // Blobs larger than the budget are never cached
+    provider.put(key(4), vec![4; 300]).unwrap();
+    provider.get(key(4)).unwrap();
+    assert_eq!(cache_stats().cached_blobs, 1);
+}
+
+#[test]
+fn prefetch_warms_the_cache() {
+    let options = BlobProviderOptions {
+        cache_budget_bytes: 1 << 20,
+        inline_max_bytes: 16,
+        ..Default::default()
+    };
+    let provider = open(&MemStorage::new(), &options);
+    for id in 1..=4 {
+        provider.put(key(id), vec![id; 100]).unwrap();
+    }
+    provider.put(key(5), vec![5; 10]).unwrap();
+
+    // Inline blobs are served from the index and aren't cached
+    let keys = (1..=5).map(key).collect::<Vec<_>>();
+    assert_eq!(provider.prefetch(keys.clone()).unwrap(), 4);
+    assert_eq!(provider.prefetch(keys).unwrap(), 0);
+
+    for id in 1..=4 {
+        assert_eq!(provider.get(key(id)).unwrap(), Some(vec![id; 100]));
+    }
+    let stats = provider.cache_stats().unwrap().unwrap();
+    assert_eq!((stats.hits, stats.misses), (4, 0));
+    assert_eq!(stats.cached_blobs, 4);
+
+    // Without a cache the kernel is asked to read ahead instead
+    let uncached = open(&MemStorage::new(), &BlobProviderOptions::default());
+    for id in 1..=4 {
+        uncached.put(key(id), vec![id; 100]).unwrap();
+    }
+    assert_eq!(uncached.prefetch((1..=4).map(key).collect()).unwrap(), 4);
+}
+
+#[test]
+fn prefetching_missing_keys_does_nothing() {
+    let cached = BlobProviderOptions {
+        cache_budget_bytes: 1 << 20,
+        ..Default::default()
+    };
+
+    for options in [BlobProviderOptions::default(), cached] {
+        let provider = open(&MemStorage::new(), &options);
+        assert_eq!(provider.prefetch(vec![]).unwrap(), 0);
+        assert_eq!(provider.prefetch(vec![key(1), key(2)]).unwrap(), 0);
+
+        provider.put(key(1), vec![1; 100]).unwrap();
+        provider.delete(key(1)).unwrap();
+        assert_eq!(provider.prefetch(vec![key(1)]).unwrap(), 0);
+        assert!(matches!(
+            provider.prefetch(vec!["not a key".to_owned()]),
+            Err(BlobProviderError::InvalidKey(_))
+        ));
+
+        if let Some(stats) = provider.cache_stats().unwrap() {
+            assert_eq!((stats.cached_blobs, stats.hits, stats.misses), (0, 0, 0));
+        }
+    }
+}
+
+#[test]
+fn cancelled_prefetch_stops_partway() {
+    let options = BlobProviderOptions {
+        cache_budget_bytes: 1 << 30,
+        ..Default::default()
+    };
+    let provider = open(&MemStorage::new(), &options);
+    let keys = (0..20_000u32)
+        .map(|id| format!("00000000-0000-0000-0000-{:012X}", id))
+        .collect::<Vec<_>>();
+    provider
+        .write_batch(
+            keys.iter()
+                .map(|key| BlobBatchEntry {
+                    key: key.clone(),
+                    data: vec![1; 64],
+                    secondary: None,
+                })
+                .collect(),
+        )
+        .unwrap();
+
+    // Cancelling before the prefetch starts doesn't stop it, so keep cancelling
+    let prefetch = {
+        let provider = provider.clone();
+        let keys = keys.clone();
+        std::thread::spawn(move || provider.prefetch(keys).unwrap())
+    };
+    while !prefetch.is_finished() {
+        provider.cancel_prefetch();
+    }
+
+    let warmed = prefetch.join().unwrap();
+    assert!(warmed < keys.len() as u64, "nothing cancelled the prefetch");
+    assert_eq!(
+        provider.cache_stats().unwrap().unwrap().cached_blobs,
+        warmed
+    );
+
+    // A later prefetch picks up where the cancelled one stopped
+    assert_eq!(
+        provider.prefetch(keys.clone()).unwrap(),
+        keys.len() as u64 - warmed
+    );
+}
+
+fn quota_options(quota_bytes: u64) -> BlobProviderOptions {
+    BlobProviderOptions {
+        quota_bytes,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn quota_evicts_the_least_recently_read_key_first() {
+    let storage = MemStorage::new();
+    let provider = open(&storage, &quota_options(300));
+    for id in 1..=3 {
+        provider.put(key(id), vec![id; 100]).unwrap();
+    }
+    assert_eq!(provider.take_evicted_keys().unwrap(), Vec::<String>::new());
+
+    provider.get(key(1)).unwrap();
+    provider.put(key(4), vec![4; 100]).unwrap();
+    assert_eq!(provider.get(key(2)).unwrap(), None);
+    for id in [1, 3, 4] {
+        assert_eq!(provider.get(key(id)).unwrap(), Some(vec![id; 100]));
+    }
+
+    // The log is only cleared by taking it, across reopening
+    drop(provider);
+    storage.restart(CrashMode::DropUnsynced);
+    let provider = open(&storage, &quota_options(300));
+    assert_eq!(provider.take_evicted_keys().unwrap(), vec![key(2)]);
+    assert_eq!(provider.take_evicted_keys().unwrap(), Vec::<String>::new());
+
+    // Keys put again after their eviction aren't reported
+    provider.put(key(5), vec![5; 100]).unwrap();
+    provider.put(key(1), vec![1; 100]).unwrap();
+    let evicted = provider.take_evicted_keys().unwrap();
+    assert!(!evicted.contains(&key(1)), "{evicted:?}");
+    assert!(evicted.contains(&key(3)), "{evicted:?}");
+}
+
+#[test]
+fn pinned_and_just_written_keys_survive_the_quota() {
+    let provider = open(&MemStorage::new(), &quota_options(300));
+    provider.pin(vec![key(1)]).unwrap();
+    for id in 1..=3 {
+        provider.put(key(id), vec![id; 100]).unwrap();
+    }
+
+    provider.put(key(4), vec![4; 100]).unwrap();
+    assert_eq!(provider.get(key(1)).unwrap(), Some(vec![1; 100]));
+    assert_eq!(provider.get(key(2)).unwrap(), None);
+
+    // A blob larger than the quota evicts everything else, but not itself
+    provider.put(key(5), vec![5; 400]).unwrap();
+    assert_eq!(provider.get(key(5)).unwrap(), Some(vec![5; 400]));
+    assert_eq!(provider.get(key(1)).unwrap(), Some(vec![1; 100]));
+    for id in [3, 4] {
+        assert_eq!(provider.get(key(id)).unwrap(), None);
+    }
+
+    provider.unpin(vec![key(1)]).unwrap();
+    provider.put(key(6), vec![6; 250]).unwrap();
+    for id in [1, 5] {
+        assert_eq!(provider.get(key(id)).unwrap(), None);
+    }
+
+    let unlimited = open(&MemStorage::new(), &BlobProviderOptions::default());
+    assert!(matches!(
+        unlimited.pin(vec![key(1)]),
+        Err(BlobProviderError::QuotaDisabled)
+    ));
+}
+
+#[test]
+fn quota_counts_deduplicated_bytes_once() {
+    let options = BlobProviderOptions {
+        deduplicate: true,
+        ..quota_options(250)
+    };
+    let provider = open(&MemStorage::new(), &options);
+    for id in 1..=3 {
+        provider.put(key(id), vec![7; 100]).unwrap();
+    }
+    provider.put(key(4), vec![4; 100]).unwrap();
+    assert_eq!(provider.take_evicted_keys().unwrap(), Vec::<String>::new());
+
+    // The shared bytes are only freed once every key holding them is evicted
+    provider.put(key(5), vec![5; 100]).unwrap();
+    assert_eq!(
+        provider.take_evicted_keys().unwrap(),
+        vec![key(1), key(2), key(3)]
+    );
+    for id in [4, 5] {
+        assert_eq!(provider.get(key(id)).unwrap(), Some(vec![id; 100]));
+    }
+}
+
+#[test]
+fn quota_keeps_the_dat_files_within_twice_the_quota() {
+    let storage = MemStorage::new();
+    let options = quota_options(300);
+    let provider = open(&storage, &options);
+    for round in 0..20 {
+        for id in 1..=5 {
+            provider.put(key(id), vec![round; 100]).unwrap();
+            let stats = provider.stats().unwrap();
+            assert!(stats.dat_bytes <= 600, "{stats:?} in round {round}");
+        }
+    }
+    for id in 3..=5 {
+        assert_eq!(provider.get(key(id)).unwrap(), Some(vec![19; 100]));
+    }
+
+    // Bytes replaced before the provider was opened count as well
+    drop(provider);
+    let unlimited = open(&storage, &BlobProviderOptions::default());
+    for round in 0..10 {
+        unlimited.put(key(1), vec![round; 100]).unwrap();
+    }
+    drop(unlimited);
+    let provider = open(&storage, &options);
+    provider.put(key(2), vec![2; 100]).unwrap();
+    let stats = provider.stats().unwrap();
+    assert!(stats.dat_bytes <= 600, "{stats:?}");
+    assert_eq!(provider.get(key(1)).unwrap(), Some(vec![9; 100]));
+}
+
+#[test]
+fn evictions_are_logged_across_crashes() {
+    let options = quota_options(300);
+    let fill = |provider: &BlobProvider| {
+        for id in 1..=3 {
+            provider.put(key(id), vec![id; 100]).unwrap();
+        }
+    };
+
+    let clean = MemStorage::new();
+    let provider = open(&clean, &options);
+    fill(&provider);
+    let before = clean.mutations();
+    provider.put(key(4), vec![4; 100]).unwrap();
+    let mutations = clean.mutations() - before;
+
+    for n in 0..mutations {
+        let storage = MemStorage::new();
+        let provider = open(&storage, &options);
+        fill(&provider);
+
+        storage.inject_fault(n, Fault::Crash);
+        let _ = provider.put(key(4), vec![4; 100]);
+        drop(provider);
+        storage.restart(CrashMode::DropUnsynced);
+
+        // Exactly the keys that are gone are reported as evicted
+        let provider = open(&storage, &options);
+        let evicted = provider.take_evicted_keys().unwrap();
+        for id in 1..=3 {
+            let live = provider.get(key(id)).unwrap().is_some();
+            assert_ne!(
+                live,
+                evicted.contains(&key(id)),
+                "key {id} after crashing at mutation {n}, evicted {evicted:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Arc,
+};
+
+use indexed_blobs::{
+    batch::BlobBatchEntry,
+    blob_provider::{BlobProvider, BlobProviderOptions, new_blob_provider_with_storage},
+    err_type::BlobProviderError,
+    storage::mem_storage::{CrashMode, MemStorage},
+};
+use proptest::prelude::*;
+
+const NUM_KEYS: u8 = 8;
+const NUM_GENERATIONS: u16 = 4;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Put(u8, Vec<u8>),
+    PutVersion(u8, u16, Vec<u8>),
+    Stream(u8, Vec<u8>),
+    Get(u8),
+    GetVersion(u8, u16),
+    GetRange(u8, u64, u64),
+    Delete(u8),
+    WriteBatch(Vec<(u8, Vec<u8>)>),
+    DeleteBatch(Vec<u8>),
+    Prune(u16),
+    Compact,
+    Reopen,
+    Crash,
+}
+
+fn key(id: u8) -> String {
+    format!("00000000-0000-0000-0000-{:012x}", id)
+}
+
+fn open(storage: &MemStorage, options: &BlobProviderOptions) -> Arc<BlobProvider> {
+    Arc::new(
+        new_blob_provider_with_storage(
+            Arc::new(storage.clone()),
+            Path::new("/blobs"),
+            "model".to_owned(),
+            options.clone(),
+        )
+        .unwrap(),
+    )
+}
+
+fn data_strategy() -> impl Strategy<Value = Vec<u8>> {
+    // A tiny alphabet makes identical blobs likely, which exercises deduplication
+    prop::collection::vec(0u8..3, 0..64)
+}
+
+fn op_strategy() -> impl Strategy<Value = Op> {
+    let id = 0..NUM_KEYS;
+
+    prop_oneof![
+        4 => (id.clone(), data_strategy()).prop_map(|(id, data)| Op::Put(id, data)),
+        2 => (id.clone(), 0..NUM_GENERATIONS, data_strategy())
+            .prop_map(|(id, generation, data)| Op::PutVersion(id, generation, data)),
+        2 => (id.clone(), data_strategy()).prop_map(|(id, data)| Op::Stream(id, data)),
+        3 => id.clone().prop_map(Op::Get),
+        1 => (id.clone(), 0..NUM_GENERATIONS)
+            .prop_map(|(id, generation)| Op::GetVersion(id, generation)),
+        2 => (id.clone(), 0u64..80, 0u64..80)
+            .prop_map(|(id, offset, len)| Op::GetRange(id, offset, len)),
+        2 => id.clone().prop_map(Op::Delete),
+        1 => prop::collection::vec((id.clone(), data_strategy()), 0..6).prop_map(Op::WriteBatch),
+        1 => prop::collection::vec(id, 0..6).prop_map(Op::DeleteBatch),
+        1 => (0..=NUM_GENERATIONS).prop_map(Op::Prune),
+        1 => Just(Op::Compact),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Crash),
+    ]
+}
+
+fn options_strategy() -> impl Strategy<Value = BlobProviderOptions> {
+    (
+        any::<bool>(),
+        prop_oneof![Just(0u64), Just(128u64)],
+        prop_oneof![Just(0u64), Just(24u64)],
+    )
+        .prop_map(
+            |(deduplicate, cache_budget_bytes, inline_max_bytes)| BlobProviderOptions {
+                deduplicate,
+                cache_budget_bytes,
+                inline_max_bytes,
+                ..Default::default()
+            },
+        )
+}
+
+fn stream(provider: &Arc<BlobProvider>, id: u8, data: &[u8]) -> Result<(), BlobProviderError> {
+    let writer = provider.clone().begin_write(key(id), None)?;
+    for part in data.chunks(16) {
+        writer.write(part.to_vec())?;
+    }
+
+    writer.commit()
+}
+
+/// Every kept generation of every key, plus the oldest generation still accepted
+#[derive(Default)]
+struct Model {
+    blobs: HashMap<u8, BTreeMap<u16, Vec<u8>>>,
+    floor: u16,
+}
+
+impl Model {
+    fn latest(&self, id: u8) -> Option<Vec<u8>> {
+        self.blobs
+            .get(&id)
+            .and_then(|generations| generations.values().next_back().cloned())
+    }
+
+    /// A plain put replaces the latest generation
+    fn put(&mut self, id: u8, data: Vec<u8>) {
+        let generations = self.blobs.entry(id).or_default();
+        let generation = generations
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or(self.floor);
+        generations.insert(generation, data);
+    }
+
+    fn prune(&mut self, generation: u16) -> u64 {
+        if generation <= self.floor {
+            return 0;
+        }
+        self.floor = generation;
+
+        let mut pruned = 0;
+        for generations in self.blobs.values_mut() {
+            let kept = generations.split_off(&generation);
+            pruned += generations.len() as u64;
+            *generations = kept;
+        }
+        self.blobs.retain(|_, generations| !generations.is_empty());
+
+        pruned
+    }
+
+    fn num_blobs(&self) -> u64 {
+        self.blobs
+            .values()
+            .map(|generations| generations.len() as u64)
+            .sum()
+    }
+}
+
+fn check_against_model(provider: &BlobProvider, model: &Model) -> Result<(), TestCaseError> {
+    for id in 0..NUM_KEYS {
+        prop_assert_eq!(provider.get(key(id)).unwrap(), model.latest(id));
+        prop_assert_eq!(
+            provider.contains(key(id)).unwrap(),
+            model.blobs.contains_key(&id)
+        );
+
+        for generation in 0..NUM_GENERATIONS {
+            prop_assert_eq!(
+                provider.get_version(key(id), generation).unwrap(),
+                model
+                    .blobs
+                    .get(&id)
+                    .and_then(|generations| generations.get(&generation).cloned())
+            );
+        }
+    }
+
+    let stats = provider.stats().unwrap();
+    prop_assert_eq!(stats.num_blobs, model.num_blobs());
+    prop_assert_eq!(
+        stats.logical_bytes,
+        model
+            .blobs
+            .values()
+            .flat_map(|generations| generations.values())
+            .map(|data| data.len() as u64)
+            .sum::<u64>()
+    );
+
+    Ok(())
+}
+
+fn run(options: BlobProviderOptions, ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let storage = MemStorage::new();
+    let mut provider = open(&storage, &options);
+    let mut model = Model::default();
+
+    for op in ops {
+        match op {
+            Op::Put(id, data) => {
+                provider.put(key(id), data.clone()).unwrap();
+                model.put(id, data);
+            }
+            Op::PutVersion(id, generation, data) => {
+                let result = provider.put_version(key(id), generation, data.clone());
+
+                if generation < model.floor {
+                    prop_assert!(matches!(
+                        result,
+                        Err(BlobProviderError::GenerationPruned(..))
+                    ));
+                } else {
+                    result.unwrap();
+                    model.blobs.entry(id).or_default().insert(generation, data);
+                }
+            }
+            Op::Stream(id, data) => {
+                stream(&provider, id, &data).unwrap();
+                model.put(id, data);
+            }
+            Op::Get(id) => {
+                prop_assert_eq!(provider.get(key(id)).unwrap(), model.latest(id));
+            }
+            Op::GetVersion(id, generation) => {
+                prop_assert_eq!(
+                    provider.get_version(key(id), generation).unwrap(),
+                    model
+                        .blobs
+                        .get(&id)
+                        .and_then(|generations| generations.get(&generation).cloned())
+                );
+            }
+            Op::GetRange(id, offset, len) => {
+                let range = provider.get_range(key(id), offset, len);
+
+                match model.latest(id) {
+                    None => prop_assert!(matches!(range, Ok(None))),
+                    Some(data) if offset + len <= data.len() as u64 => prop_assert_eq!(
+                        range.unwrap(),
+                        Some(data[offset as usize..(offset + len) as usize].to_vec())
+                    ),
+                    Some(_) => prop_assert!(matches!(
+                        range,
+                        Err(BlobProviderError::RangeOutOfBounds(..))
+                    )),
+                }
+            }
+            Op::Delete(id) => {
+                prop_assert_eq!(
+                    provider.delete(key(id)).unwrap(),
+                    model.blobs.remove(&id).is_some()
+                );
+            }
+            Op::WriteBatch(entries) => {
+                provider
+                    .write_batch(
+                        entries
+                            .iter()
+                            .map(|(id, data)| BlobBatchEntry {
+                                key: key(*id),
+                                data: data.clone(),
+                                secondary: None,
+                            })
+                            .collect(),
+                    )
+                    .unwrap();
+                for (id, data) in entries {
+                    model.put(id, data);
+                }
+            }
+            Op::DeleteBatch(ids) => {
+                let removed = provider
+                    .delete_batch(ids.iter().map(|id| key(*id)).collect())
+                    .unwrap();
+                let expected = ids
+                    .iter()
+                    .filter(|id| model.blobs.remove(id).is_some())
+                    .count();
+                prop_assert_eq!(removed, expected as u64);
+            }
+            Op::Prune(generation) => {
+                prop_assert_eq!(
+                    provider.prune_older_than(generation).unwrap(),
+                    model.prune(generation)
+                );
+            }
+            Op::Compact => {
+                let stats = provider.compact().unwrap();
+                prop_assert_eq!(stats.num_blobs, model.num_blobs());
+                prop_assert_eq!(stats.dat_bytes, stats.stored_bytes);
+            }
+            Op::Reopen => {
+                drop(provider);
+                provider = open(&storage, &options);
+            }
+            Op::Crash => {
+                // Every acknowledged write was synced, so nothing may be lost
+                drop(provider);
+                storage.restart(CrashMode::DropUnsynced);
+                provider = open(&storage, &options);
+            }
+        }
+    }
+
+    check_against_model(&provider, &model)?;
+
+    drop(provider);
+    check_against_model(&open(&storage, &options), &model)
+}
+
+proptest! {
+    #[test]
+    fn provider_matches_hash_map_model(
+        options in options_strategy(),
+        ops in prop::collection::vec(op_strategy(), 1..60),
+    ) {
+        run(options, ops)?;
+    }
+}
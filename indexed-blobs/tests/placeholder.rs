@@ -0,0 +1,179 @@
+use std::{path::Path, sync::Arc};
+
+use indexed_blobs::{
+    blob_provider::{BlobProvider, BlobProviderOptions, new_blob_provider_with_storage},
+    blurhash::{PLACEHOLDER_COMPONENTS, decode_blurhash, encode_blurhash},
+    err_type::BlobProviderError,
+    storage::mem_storage::MemStorage,
+    yuv::{PixelLayout, YuvPixelRange, YuvThumbnail},
+};
+
+fn key(id: u8) -> String {
+    format!("00000000-0000-0000-0000-{:012X}", id)
+}
+
+fn open(storage: &MemStorage) -> BlobProvider {
+    new_blob_provider_with_storage(
+        Arc::new(storage.clone()),
+        Path::new("/blobs"),
+        "thumbs".to_owned(),
+        BlobProviderOptions::default(),
+    )
+    .unwrap()
+}
+
+/// Left half `left`, right half `right`, as RGBA pixels
+fn halves(width: u32, height: u32, left: [u8; 3], right: [u8; 3]) -> Vec<u8> {
+    (0..height)
+        .flat_map(|_| (0..width).map(|x| if x < width / 2 { left } else { right }))
+        .flat_map(|[r, g, b]| [r, g, b, 255])
+        .collect()
+}
+
+fn thumbnail_record(left: [u8; 3], right: [u8; 3]) -> Vec<u8> {
+    YuvThumbnail::from_pixels(
+        &halves(64, 48, left, right),
+        64,
+        48,
+        64 * 4,
+        PixelLayout::Rgba,
+        YuvPixelRange::Video,
+    )
+    .unwrap()
+    .encode()
+    .unwrap()
+}
+
+#[test]
+fn blurhash_round_trips_colors() {
+    let pixels = halves(32, 32, [40, 120, 200], [40, 120, 200]);
+    let solid = encode_blurhash(&pixels, 32, 32, 32 * 4, PixelLayout::Rgba, (1, 1)).unwrap();
+    assert_eq!(solid.len(), 6);
+    for pixel in decode_blurhash(&solid, 4, 4).unwrap().chunks(4) {
+        assert_eq!(pixel, [40, 120, 200, 255]);
+    }
+
+    let solid = encode_blurhash(
+        &pixels,
+        32,
+        32,
+        32 * 4,
+        PixelLayout::Rgba,
+        PLACEHOLDER_COMPONENTS,
+    )
+    .unwrap();
+    assert_eq!(solid.len(), 28);
+
+    // Same hash whatever the byte order of the pixels
+    let bgra = halves(32, 32, [200, 120, 40], [200, 120, 40]);
+    assert_eq!(
+        encode_blurhash(
+            &bgra,
+            32,
+            32,
+            32 * 4,
+            PixelLayout::Bgra,
+            PLACEHOLDER_COMPONENTS
+        )
+        .unwrap(),
+        solid
+    );
+
+    let split = encode_blurhash(
+        &halves(32, 32, [230, 20, 20], [20, 20, 230]),
+        32,
+        32,
+        32 * 4,
+        PixelLayout::Rgba,
+        (9, 1),
+    )
+    .unwrap();
+    let pixels = decode_blurhash(&split, 4, 1).unwrap();
+    assert!(pixels[0] > 150 && pixels[2] < 100, "{pixels:?}");
+    assert!(pixels[14] > 150 && pixels[12] < 100, "{pixels:?}");
+
+    for invalid in [
+        "",
+        "L",
+        "LEHV6nWB2yk8pyo0adR*.7kCMdn",
+        "LEHV6nWB2yk8pyo0adR*.7kCMdnj\u{e9}",
+    ] {
+        assert!(matches!(
+            decode_blurhash(invalid, 4, 4),
+            Err(BlobProviderError::InvalidThumbnail(_))
+        ));
+    }
+}
+
+#[test]
+fn placeholders_follow_their_blobs() {
+    let storage = MemStorage::new();
+    let provider = open(&storage);
+
+    // Thumbnails stored before placeholders existed, and a blob that isn't one
+    provider
+        .put(key(1), thumbnail_record([200, 30, 30], [30, 30, 200]))
+        .unwrap();
+    provider
+        .put(key(2), thumbnail_record([30, 200, 30], [30, 200, 30]))
+        .unwrap();
+    provider.put(key(3), vec![3; 100]).unwrap();
+    assert_eq!(provider.get_placeholder(key(1)).unwrap(), None);
+
+    assert_eq!(provider.backfill_placeholders().unwrap(), 2);
+    assert_eq!(provider.backfill_placeholders().unwrap(), 0);
+    let placeholder = provider.get_placeholder(key(1)).unwrap().unwrap();
+    assert_eq!(placeholder.len(), 28);
+    assert_ne!(
+        provider.get_placeholder(key(2)).unwrap(),
+        Some(placeholder.clone())
+    );
+    assert_eq!(provider.get_placeholder(key(3)).unwrap(), None);
+
+    // Replacing or deleting a blob drops its placeholder
+    provider
+        .put(key(2), thumbnail_record([0, 0, 0], [0, 0, 0]))
+        .unwrap();
+    assert_eq!(provider.get_placeholder(key(2)).unwrap(), None);
+    provider.delete(key(1)).unwrap();
+    assert_eq!(provider.get_placeholder(key(1)).unwrap(), None);
+
+    provider
+        .put(key(1), thumbnail_record([200, 30, 30], [30, 30, 200]))
+        .unwrap();
+    assert_eq!(provider.backfill_placeholders().unwrap(), 2);
+
+    provider.compact().unwrap();
+    drop(provider);
+    let provider = open(&storage);
+
+    assert_eq!(provider.get_placeholder(key(1)).unwrap(), Some(placeholder));
+    assert!(provider.get_placeholder(key(2)).unwrap().is_some());
+    assert_eq!(provider.backfill_placeholders().unwrap(), 0);
+}
+
+#[test]
+fn older_generations_keep_the_latest_placeholder() {
+    let storage = MemStorage::new();
+    let provider = open(&storage);
+    provider
+        .put_version(key(1), 5, thumbnail_record([200, 30, 30], [30, 30, 200]))
+        .unwrap();
+    assert_eq!(provider.backfill_placeholders().unwrap(), 1);
+    let placeholder = provider.get_placeholder(key(1)).unwrap();
+    assert!(placeholder.is_some());
+
+    // Still describes generation 5, which `get` returns
+    provider
+        .put_version(key(1), 2, thumbnail_record([0, 0, 0], [0, 0, 0]))
+        .unwrap();
+    assert_eq!(provider.get_placeholder(key(1)).unwrap(), placeholder);
+    drop(provider);
+    let provider = open(&storage);
+    assert_eq!(provider.get_placeholder(key(1)).unwrap(), placeholder);
+
+    provider
+        .put_version(key(1), 6, thumbnail_record([0, 0, 0], [0, 0, 0]))
+        .unwrap();
+    assert_eq!(provider.get_placeholder(key(1)).unwrap(), None);
+}
@@ -0,0 +1,176 @@
+use std::{path::Path, sync::Arc};
+
+use indexed_blobs::{
+    batch::BlobBatchEntry,
+    blob_provider::{BlobProvider, BlobProviderOptions, new_blob_provider_with_storage},
+    storage::{
+        OpenMode, Storage,
+        mem_storage::{Fault, MemStorage},
+    },
+};
+
+fn key(id: u8) -> String {
+    format!("00000000-0000-0000-0000-{:012X}", id)
+}
+
+fn open(storage: &MemStorage, options: &BlobProviderOptions) -> BlobProvider {
+    new_blob_provider_with_storage(
+        Arc::new(storage.clone()),
+        Path::new("/blobs"),
+        "thumbs".to_owned(),
+        options.clone(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn range_lists_live_keys_in_secondary_order() {
+    let storage = MemStorage::new();
+    let options = BlobProviderOptions::default();
+    let provider = open(&storage, &options);
+
+    for (id, taken_at) in [(1, 300), (2, -50), (3, 100), (4, 100), (5, 900)] {
+        provider
+            .put_with_secondary(key(id), vec![id; 10], taken_at)
+            .unwrap();
+    }
+    provider.put(key(6), vec![6; 10]).unwrap();
+    provider
+        .write_batch(vec![BlobBatchEntry {
+            key: key(7),
+            data: vec![7; 10],
+            secondary: Some(200),
+        }])
+        .unwrap();
+
+    assert_eq!(
+        provider.range_by_secondary(-100, 900, 100).unwrap(),
+        [key(2), key(3), key(4), key(7), key(1)]
+    );
+    assert_eq!(
+        provider.range_by_secondary(100, 1000, 2).unwrap(),
+        [key(3), key(4)]
+    );
+    assert!(
+        provider
+            .range_by_secondary(900, 900, 10)
+            .unwrap()
+            .is_empty()
+    );
+
+    // A plain put keeps the secondary key, deleting the blob drops it
+    provider.put(key(3), vec![0; 10]).unwrap();
+    provider
+        .put_with_secondary(key(1), vec![1; 10], 50)
+        .unwrap();
+    assert!(provider.delete(key(4)).unwrap());
+    provider.put(key(4), vec![4; 10]).unwrap();
+
+    let expected = [key(2), key(1), key(3), key(7), key(5)];
+    assert_eq!(
+        provider
+            .range_by_secondary(i64::MIN, i64::MAX, 100)
+            .unwrap(),
+        expected
+    );
+    drop(provider);
+
+    let provider = open(&storage, &options);
+    assert_eq!(
+        provider
+            .range_by_secondary(i64::MIN, i64::MAX, 100)
+            .unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn compaction_lays_out_blobs_in_secondary_order() {
+    let storage = MemStorage::new();
+    let options = BlobProviderOptions {
+        compact_by_secondary: true,
+        ..Default::default()
+    };
+    let provider = open(&storage, &options);
+
+    provider.put(key(9), vec![9; 10]).unwrap();
+    for (id, taken_at) in [(1, 30), (2, 10), (3, 20)] {
+        provider
+            .put_with_secondary(key(id), vec![id; 10], taken_at)
+            .unwrap();
+    }
+    provider.compact().unwrap();
+
+    let dat_path = storage
+        .list(Path::new("/blobs"))
+        .unwrap()
+        .into_iter()
+        .find(|name| name.ends_with(".dat"))
+        .unwrap();
+    let dat = storage
+        .open(&Path::new("/blobs").join(dat_path), OpenMode::Read)
+        .unwrap();
+    let mut bytes = vec![0; 40];
+    dat.read_at(&mut bytes, 0).unwrap();
+
+    let layout = bytes.chunks(10).map(|blob| blob[0]).collect::<Vec<_>>();
+    assert_eq!(layout, [2, 3, 1, 9]);
+}
+
+#[test]
+fn failed_puts_keep_the_previous_secondary_key() {
+    let options = BlobProviderOptions::default();
+
+    let clean = MemStorage::new();
+    let provider = open(&clean, &options);
+    provider.put_with_secondary(key(1), vec![1; 10], 5).unwrap();
+    let before = clean.mutations();
+    provider
+        .put_with_secondary(key(1), vec![2; 10], 50)
+        .unwrap();
+    let mutations = clean.mutations() - before;
+
+    for n in 0..mutations {
+        let storage = MemStorage::new();
+        let provider = open(&storage, &options);
+        provider.put_with_secondary(key(1), vec![1; 10], 5).unwrap();
+
+        storage.inject_fault(n, Fault::NoSpace);
+        let (expected, secondary) = match provider.put_with_secondary(key(1), vec![2; 10], 50) {
+            Ok(()) => (vec![2; 10], 50),
+            Err(_) => (vec![1; 10], 5),
+        };
+        assert_eq!(
+            provider.get(key(1)).unwrap(),
+            Some(expected),
+            "mutation {n}"
+        );
+        assert_eq!(
+            provider.range_by_secondary(0, 100, 10).unwrap(),
+            [key(1)],
+            "mutation {n}"
+        );
+        assert_eq!(
+            provider
+                .range_by_secondary(secondary, secondary + 1, 10)
+                .unwrap(),
+            [key(1)],
+            "mutation {n}"
+        );
+        drop(provider);
+
+        // A failed fsync may still have landed the write, along with its secondary key
+        let provider = open(&storage, &options);
+        let secondary = match provider.get(key(1)).unwrap() {
+            Some(data) if data == [2; 10] => 50,
+            _ => 5,
+        };
+        assert_eq!(
+            provider
+                .range_by_secondary(secondary, secondary + 1, 10)
+                .unwrap(),
+            [key(1)],
+            "mutation {n}"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::{path::Path, sync::Arc};
+
+use indexed_blobs::{
+    blob_provider::{BlobProvider, BlobProviderOptions, new_blob_provider_with_storage},
+    err_type::BlobProviderError,
+    sprite_sheet::SpriteSheet,
+    storage::mem_storage::MemStorage,
+};
+
+fn key(id: u8) -> String {
+    format!("00000000-0000-0000-0000-{:012X}", id)
+}
+
+fn open(storage: &MemStorage, options: &BlobProviderOptions) -> BlobProvider {
+    new_blob_provider_with_storage(
+        Arc::new(storage.clone()),
+        Path::new("/blobs"),
+        "thumbs".to_owned(),
+        options.clone(),
+    )
+    .unwrap()
+}
+
+fn row(num_tiles: u8) -> SpriteSheet {
+    SpriteSheet {
+        tile_width: 8,
+        tile_height: 8,
+        tiles: (0..num_tiles).map(|i| vec![i; 8 * 8 * 3 / 2]).collect(),
+    }
+}
+
+#[test]
+fn sheet_and_tiles_round_trip() {
+    for options in [
+        BlobProviderOptions::default(),
+        BlobProviderOptions {
+            cache_budget_bytes: 1 << 20,
+            ..Default::default()
+        },
+        BlobProviderOptions {
+            inline_max_bytes: 4096,
+            ..Default::default()
+        },
+    ] {
+        let storage = MemStorage::new();
+        let provider = open(&storage, &options);
+        let sheet = row(20);
+
+        provider.put_sprite_sheet(key(1), sheet.clone()).unwrap();
+
+        // Twice, so the second round is served from the cache if there is one
+        for _ in 0..2 {
+            assert_eq!(
+                provider.get_sprite_sheet(key(1)).unwrap(),
+                Some(sheet.clone())
+            );
+            for (i, tile) in sheet.tiles.iter().enumerate() {
+                assert_eq!(
+                    provider.get_sprite_tile(key(1), i as u32).unwrap().as_ref(),
+                    Some(tile)
+                );
+            }
+        }
+
+        assert!(matches!(
+            provider.get_sprite_tile(key(1), 20),
+            Err(BlobProviderError::TileOutOfBounds(20, 20))
+        ));
+        assert_eq!(provider.get_sprite_tile(key(2), 0).unwrap(), None);
+
+        drop(provider);
+        let provider = open(&storage, &options);
+        assert_eq!(
+            provider.get_sprite_tile(key(1), 7).unwrap(),
+            Some(vec![7; 96])
+        );
+    }
+}
+
+#[test]
+fn rejects_malformed_sheets() {
+    let storage = MemStorage::new();
+    let provider = open(&storage, &BlobProviderOptions::default());
+
+    let mut uneven = row(3);
+    uneven.tiles[1].pop();
+    // Tiles of a 7x7 sheet take 49 + 2 * 16 bytes
+    let wrong_size = SpriteSheet {
+        tile_width: 7,
+        tile_height: 7,
+        ..row(3)
+    };
+    let empty_tiles = SpriteSheet {
+        tile_width: 0,
+        ..row(3)
+    };
+    for sheet in [row(0), uneven, wrong_size, empty_tiles] {
+        assert!(matches!(
+            provider.put_sprite_sheet(key(1), sheet),
+            Err(BlobProviderError::InvalidSpriteSheet(_))
+        ));
+    }
+
+    provider.put(key(2), vec![0; 64]).unwrap();
+    assert!(matches!(
+        provider.get_sprite_tile(key(2), 0),
+        Err(BlobProviderError::InvalidSpriteSheet(_))
+    ));
+    assert!(matches!(
+        provider.get_sprite_sheet(key(2)),
+        Err(BlobProviderError::InvalidSpriteSheet(_))
+    ));
+
+    // A table whose second range is shorter than an 8x8 tile
+    let mut sheet = b"IBSS".to_vec();
+    for field in [1u16, 2] {
+        sheet.extend_from_slice(&field.to_le_bytes());
+    }
+    for field in [8u32, 8, 32, 96, 128, 95] {
+        sheet.extend_from_slice(&field.to_le_bytes());
+    }
+    sheet.resize(sheet.len() + 96 + 95, 0);
+    provider.put(key(3), sheet).unwrap();
+    assert!(provider.get_sprite_tile(key(3), 0).unwrap().is_some());
+    assert!(matches!(
+        provider.get_sprite_tile(key(3), 1),
+        Err(BlobProviderError::InvalidSpriteSheet(_))
+    ));
+    assert!(matches!(
+        provider.get_sprite_sheet(key(3)),
+        Err(BlobProviderError::InvalidSpriteSheet(_))
+    ));
+}
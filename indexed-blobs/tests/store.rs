@@ -0,0 +1,92 @@
+use std::{path::Path, sync::Arc};
+
+use indexed_blobs::{
+    blob_provider::BlobProviderOptions,
+    blob_store::{BlobStore, new_blob_store_with_storage},
+    err_type::BlobProviderError,
+    storage::mem_storage::MemStorage,
+};
+
+const KEY: &str = "00000000-0000-0000-0000-000000000001";
+
+fn open(storage: &MemStorage, options: BlobProviderOptions) -> BlobStore {
+    new_blob_store_with_storage(Arc::new(storage.clone()), Path::new("/blobs"), options)
+}
+
+#[test]
+fn namespaces_are_isolated() {
+    let storage = MemStorage::new();
+    let store = open(&storage, BlobProviderOptions::default());
+
+    let thumbs = store.namespace("thumbs".to_owned()).unwrap();
+    let previews = store.namespace("previews".to_owned()).unwrap();
+    thumbs.put(KEY.to_owned(), vec![1; 10]).unwrap();
+    previews.put(KEY.to_owned(), vec![2; 20]).unwrap();
+
+    assert!(Arc::ptr_eq(
+        &thumbs,
+        &store.namespace("thumbs".to_owned()).unwrap()
+    ));
+    assert!(previews.delete(KEY.to_owned()).unwrap());
+    assert_eq!(thumbs.get(KEY.to_owned()).unwrap(), Some(vec![1; 10]));
+
+    for name in ["", "thumbs_2", "a.b", "../x"] {
+        assert!(matches!(
+            store.namespace(name.to_owned()),
+            Err(BlobProviderError::InvalidPrefix)
+        ));
+    }
+}
+
+#[test]
+fn stats_and_compaction_cover_unopened_namespaces() {
+    let storage = MemStorage::new();
+    let store = open(&storage, BlobProviderOptions::default());
+    for (name, len) in [("thumbs", 10), ("previews", 300), ("embeddings", 4)] {
+        let provider = store.namespace(name.to_owned()).unwrap();
+        provider.put(KEY.to_owned(), vec![0; len]).unwrap();
+        provider.put(KEY.to_owned(), vec![1; len]).unwrap();
+    }
+    drop(store);
+
+    let store = open(&storage, BlobProviderOptions::default());
+    assert_eq!(
+        store.namespace_names().unwrap(),
+        ["embeddings", "previews", "thumbs"]
+    );
+
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.total.num_blobs, 3);
+    assert_eq!(stats.total.dat_bytes, 2 * 314);
+
+    let stats = store.compact().unwrap();
+    assert_eq!(stats.total.dat_bytes, 314);
+    assert_eq!(stats.namespaces[1].name, "previews");
+    assert_eq!(stats.namespaces[1].stats.logical_bytes, 300);
+}
+
+#[test]
+fn namespaces_share_one_cache() {
+    let storage = MemStorage::new();
+    let store = open(
+        &storage,
+        BlobProviderOptions {
+            cache_budget_bytes: 100,
+            ..Default::default()
+        },
+    );
+
+    let thumbs = store.namespace("thumbs".to_owned()).unwrap();
+    let previews = store.namespace("previews".to_owned()).unwrap();
+    thumbs.put(KEY.to_owned(), vec![1; 60]).unwrap();
+    previews.put(KEY.to_owned(), vec![2; 60]).unwrap();
+
+    assert_eq!(thumbs.get(KEY.to_owned()).unwrap(), Some(vec![1; 60]));
+    assert_eq!(previews.get(KEY.to_owned()).unwrap(), Some(vec![2; 60]));
+
+    // Both blobs don't fit into the shared budget, so the first one was evicted
+    let cache_stats = store.cache_stats().unwrap().unwrap();
+    assert_eq!(cache_stats.cached_bytes, 60);
+    assert_eq!(thumbs.get(KEY.to_owned()).unwrap(), Some(vec![1; 60]));
+    assert_eq!(store.cache_stats().unwrap().unwrap().hits, 0);
+}
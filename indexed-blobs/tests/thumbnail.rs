@@ -0,0 +1,192 @@
+use std::{io::Cursor, path::Path, sync::Arc};
+
+use image::{
+    DynamicImage, ImageEncoder, Rgb, RgbImage,
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+};
+use indexed_blobs::{
+    blob_provider::{BlobProvider, BlobProviderOptions, new_blob_provider_with_storage},
+    err_type::BlobProviderError,
+    storage::mem_storage::MemStorage,
+    thumbnail::{ThumbnailSize, compressed_pixel_size, generate_thumbnail},
+    yuv::{PixelLayout, YuvPixelRange, YuvThumbnail},
+};
+
+fn key(id: u8) -> String {
+    format!("00000000-0000-0000-0000-{:012X}", id)
+}
+
+fn open(storage: &MemStorage) -> BlobProvider {
+    new_blob_provider_with_storage(
+        Arc::new(storage.clone()),
+        Path::new("/blobs"),
+        "thumbs".to_owned(),
+        BlobProviderOptions::default(),
+    )
+    .unwrap()
+}
+
+/// Left half red, right half blue
+fn halves(width: u32, height: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, _| match x < width / 2 {
+        true => Rgb([220, 20, 20]),
+        false => Rgb([20, 20, 220]),
+    })
+}
+
+/// Minimal little-endian TIFF header with an orientation entry, as stored in EXIF
+fn exif_orientation(orientation: u16) -> Vec<u8> {
+    let mut exif = b"II*\0".to_vec();
+    exif.extend_from_slice(&8u32.to_le_bytes());
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    exif.extend_from_slice(&0x0112u16.to_le_bytes());
+    exif.extend_from_slice(&3u16.to_le_bytes());
+    exif.extend_from_slice(&1u32.to_le_bytes());
+    exif.extend_from_slice(&orientation.to_le_bytes());
+    exif.extend_from_slice(&[0, 0]);
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    exif
+}
+
+fn encode(image: &RgbImage, encoder: impl ImageEncoder) {
+    encoder
+        .write_image(
+            image.as_raw(),
+            image.width(),
+            image.height(),
+            image::ExtendedColorType::Rgb8,
+        )
+        .unwrap();
+}
+
+fn jpeg(image: &RgbImage, orientation: u16) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    let mut encoder = JpegEncoder::new_with_quality(&mut buffer, 95);
+    encoder
+        .set_exif_metadata(exif_orientation(orientation))
+        .unwrap();
+    encode(image, encoder);
+    buffer
+}
+
+fn png(image: &RgbImage) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    encode(image, PngEncoder::new(&mut buffer));
+    buffer
+}
+
+fn webp(image: &RgbImage) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    encode(image, WebPEncoder::new_lossless(&mut buffer));
+    buffer
+}
+
+/// RGB of the pixel at `x`, `y` of the thumbnail
+fn pixel(thumbnail: &YuvThumbnail, x: u32, y: u32) -> [u8; 3] {
+    let pixels = thumbnail.to_pixels(PixelLayout::Rgba).unwrap();
+    let offset = ((y * thumbnail.width + x) * 4) as usize;
+    [pixels[offset], pixels[offset + 1], pixels[offset + 2]]
+}
+
+fn is_red([r, _, b]: [u8; 3]) -> bool {
+    r > 150 && b < 80
+}
+
+fn is_blue([r, _, b]: [u8; 3]) -> bool {
+    b > 150 && r < 80
+}
+
+#[test]
+fn sizes_match_the_app() {
+    for (width, height, expected) in [
+        (4032, 3024, (350, 262)),
+        (3024, 4032, (262, 350)),
+        (1000, 1000, (350, 350)),
+        (1920, 1080, (350, 196)),
+        (100, 7, (350, 24)),
+        (10000, 1, (350, 2)),
+    ] {
+        assert_eq!(
+            compressed_pixel_size(width, height).unwrap(),
+            ThumbnailSize {
+                width: expected.0,
+                height: expected.1
+            }
+        );
+    }
+
+    assert!(matches!(
+        compressed_pixel_size(0, 10),
+        Err(BlobProviderError::InvalidImage(_))
+    ));
+}
+
+#[test]
+fn decodes_every_format() {
+    let image = halves(800, 600);
+
+    for encoded in [jpeg(&image, 1), png(&image), webp(&image)] {
+        let thumbnail = generate_thumbnail(&encoded).unwrap();
+
+        assert_eq!((thumbnail.width, thumbnail.height), (350, 262));
+        assert_eq!(thumbnail.pixel_range, YuvPixelRange::Video);
+        assert!(is_red(pixel(&thumbnail, 40, 130)));
+        assert!(is_blue(pixel(&thumbnail, 310, 130)));
+    }
+}
+
+#[test]
+fn applies_exif_orientation() {
+    // Stored sideways, orientation 6 rotates it 90 degrees clockwise for display, so
+    // the red half ends up on top
+    let thumbnail = generate_thumbnail(&jpeg(&halves(600, 400), 6)).unwrap();
+
+    assert_eq!((thumbnail.width, thumbnail.height), (234, 350));
+    assert!(is_red(pixel(&thumbnail, 117, 40)));
+    assert!(is_blue(pixel(&thumbnail, 117, 310)));
+}
+
+#[test]
+fn stores_thumbnails_in_the_provider() {
+    let storage = MemStorage::new();
+    let provider = open(&storage);
+
+    let size = provider
+        .put_thumbnail(key(1), jpeg(&halves(1200, 900), 1))
+        .unwrap();
+    assert_eq!(
+        size,
+        ThumbnailSize {
+            width: 350,
+            height: 262
+        }
+    );
+
+    let record = provider.get(key(1)).unwrap().unwrap();
+    let thumbnail = YuvThumbnail::decode(&record).unwrap();
+    assert_eq!((thumbnail.width, thumbnail.height), (350, 262));
+    assert_eq!(
+        provider
+            .get_placeholder(key(1))
+            .unwrap()
+            .map(|hash| hash.len()),
+        Some(28)
+    );
+
+    let bmp = {
+        let mut buffer = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(halves(4, 4))
+            .write_to(&mut buffer, image::ImageFormat::Bmp)
+            .map(|_| buffer.into_inner())
+    };
+    for garbage in [vec![0xFF, 0xD8, 0xFF, 0x00], b"not an image".to_vec()]
+        .into_iter()
+        .chain(bmp.ok())
+    {
+        assert!(matches!(
+            provider.put_thumbnail(key(2), garbage),
+            Err(BlobProviderError::InvalidImage(_))
+        ));
+    }
+    assert_eq!(provider.get(key(2)).unwrap(), None);
+}
@@ -0,0 +1,132 @@
+use indexed_blobs::{
+    err_type::BlobProviderError,
+    yuv::{PixelLayout, YuvPixelRange, YuvThumbnail},
+};
+
+/// Smooth gradient, so subsampling the chroma barely changes it
+fn gradient(width: u32, height: u32, row_bytes: u32) -> Vec<u8> {
+    let mut pixels = vec![0; (row_bytes * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = (y * row_bytes + x * 4) as usize;
+            pixels[pixel..pixel + 4].copy_from_slice(&[
+                (40 + x * 4) as u8,
+                (200 - y * 3) as u8,
+                (60 + x * 2 + y) as u8,
+                255,
+            ]);
+        }
+    }
+    pixels
+}
+
+fn assert_close(expected: &[u8], actual: &[u8], tolerance: u8) {
+    assert_eq!(expected.len(), actual.len());
+    for (i, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+        assert!(
+            expected.abs_diff(*actual) <= tolerance,
+            "byte {i}: expected {expected}, got {actual}"
+        );
+    }
+}
+
+#[test]
+fn pixels_round_trip_through_yuv() {
+    for pixel_range in [YuvPixelRange::Video, YuvPixelRange::Full] {
+        for (width, height) in [(16, 10), (7, 5), (1, 1)] {
+            let row_bytes = width * 4 + 12;
+            let pixels = gradient(width, height, row_bytes);
+
+            let thumbnail = YuvThumbnail::from_pixels(
+                &pixels,
+                width,
+                height,
+                row_bytes,
+                PixelLayout::Rgba,
+                pixel_range,
+            )
+            .unwrap();
+            assert_eq!(
+                thumbnail.planes.len() as u32,
+                width * height + 2 * width.div_ceil(2) * height.div_ceil(2)
+            );
+
+            let packed = pixels
+                .chunks(row_bytes as usize)
+                .flat_map(|row| &row[..width as usize * 4])
+                .copied()
+                .collect::<Vec<_>>();
+            assert_close(&packed, &thumbnail.to_pixels(PixelLayout::Rgba).unwrap(), 6);
+
+            let bgra = thumbnail.to_pixels(PixelLayout::Bgra).unwrap();
+            let swapped = packed
+                .chunks(4)
+                .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]])
+                .collect::<Vec<_>>();
+            assert_close(&swapped, &bgra, 6);
+        }
+    }
+}
+
+#[test]
+fn video_range_keeps_samples_in_range() {
+    let black_and_white = [0, 0, 0, 255, 255, 255, 255, 255].repeat(2);
+    let thumbnail = YuvThumbnail::from_pixels(
+        &black_and_white,
+        2,
+        2,
+        8,
+        PixelLayout::Bgra,
+        YuvPixelRange::Video,
+    )
+    .unwrap();
+
+    assert_eq!(&thumbnail.planes[..4], &[16, 235, 16, 235]);
+    assert_eq!(&thumbnail.planes[4..], &[128, 128]);
+    assert_eq!(
+        thumbnail.to_pixels(PixelLayout::Bgra).unwrap(),
+        black_and_white
+    );
+}
+
+#[test]
+fn record_round_trips_and_honors_strides() {
+    let thumbnail = YuvThumbnail {
+        width: 3,
+        height: 3,
+        y_stride: 4,
+        uv_stride: 3,
+        pixel_range: YuvPixelRange::Full,
+        planes: (0..4 * 3 + 2 * 3 * 2).map(|i| i as u8).collect(),
+    };
+
+    let record = thumbnail.encode().unwrap();
+    assert_eq!(&record[..4], b"IBYV");
+    assert_eq!(YuvThumbnail::decode(&record).unwrap(), thumbnail);
+
+    // Padding bytes at the end of each row are skipped
+    let mut padded = thumbnail.clone();
+    for padding in [3, 7, 11, 12 + 2, 12 + 5, 18 + 2, 18 + 5] {
+        padded.planes[padding] = 255;
+    }
+    assert_eq!(
+        padded.to_pixels(PixelLayout::Rgba).unwrap(),
+        thumbnail.to_pixels(PixelLayout::Rgba).unwrap()
+    );
+
+    for truncated in [&record[..10], &record[..record.len() - 1]] {
+        assert!(matches!(
+            YuvThumbnail::decode(truncated),
+            Err(BlobProviderError::InvalidThumbnail(_))
+        ));
+    }
+
+    let narrow = YuvThumbnail {
+        y_stride: 2,
+        ..thumbnail
+    };
+    assert!(matches!(
+        narrow.encode(),
+        Err(BlobProviderError::InvalidThumbnail(_))
+    ));
+}